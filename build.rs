@@ -0,0 +1,38 @@
+//! Embeds build information for `--version`.
+
+use std::process::Command;
+
+fn main() {
+    // Builds from a source archive have no git checkout to ask
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    // RON has no way to ask it for its version, so it's read from the lock file
+    let ron_version = std::fs::read_to_string("Cargo.lock")
+        .ok()
+        .and_then(|lock| locked_version(&lock, "ron"))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=RON_VERSION={}", ron_version);
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+/// Finds the version of a package in the contents of a Cargo.lock.
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name_line = format!("name = \"{}\"", package);
+    let mut lines = lock.lines().skip_while(|line| line.trim() != name_line);
+    lines.next()?;
+
+    let version = lines.next()?.trim().strip_prefix("version = ")?;
+    Some(version.trim_matches('"').to_string())
+}
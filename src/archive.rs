@@ -0,0 +1,213 @@
+//! Just enough of the zip format to bundle files up for download. Files are stored
+//! as they are, without compression, which every unzip tool can read.
+
+use chrono::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+
+/// The most files, and the most bytes in all, a zip file can hold without the
+/// ZIP64 extensions, which aren't supported here.
+const MAX_FILES: usize = 0xFFFF;
+const MAX_BYTES: usize = 0xFFFF_FFFF;
+
+/// Builds a zip file in memory.
+#[derive(Default)]
+pub struct ZipWriter {
+    data: Vec<u8>,
+    central_directory: Vec<u8>,
+    files: usize,
+}
+
+impl ZipWriter {
+    pub fn new() -> ZipWriter {
+        ZipWriter::default()
+    }
+
+    /// Adds a file at the given path inside the zip, like `entries/2019-01-01.md`,
+    /// modified on the given date.
+    pub fn add_file(&mut self, path: &str, contents: &[u8], date: NaiveDate) -> Result<()> {
+        if self.files == MAX_FILES {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("a zip file can hold at most {} files", MAX_FILES),
+            ));
+        }
+        if self.data.len() + contents.len() + path.len() + 30 > MAX_BYTES {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a zip file can hold at most 4 GB",
+            ));
+        }
+
+        let offset = self.data.len() as u32;
+        let crc = crc32(contents);
+        let size = contents.len() as u32;
+
+        // The earliest date a zip file can record is 1980
+        let dos_date = if date.year() < 1980 {
+            (1 << 5) | 1
+        } else {
+            (((date.year() - 1980) as u16) << 9) | ((date.month() as u16) << 5) | date.day() as u16
+        };
+
+        // Local file header, followed by the file itself
+        push_u32(&mut self.data, 0x0403_4b50);
+        push_u16(&mut self.data, 20); // Version needed to extract
+        push_u16(&mut self.data, 0x0800); // The path is UTF-8
+        push_u16(&mut self.data, 0); // Stored, not compressed
+        push_u16(&mut self.data, 0); // Modified at midnight
+        push_u16(&mut self.data, dos_date);
+        push_u32(&mut self.data, crc);
+        push_u32(&mut self.data, size);
+        push_u32(&mut self.data, size);
+        push_u16(&mut self.data, path.len() as u16);
+        push_u16(&mut self.data, 0); // No extra fields
+        self.data.extend_from_slice(path.as_bytes());
+        self.data.extend_from_slice(contents);
+
+        // And its entry in the central directory, which goes at the end of the zip
+        let cd = &mut self.central_directory;
+        push_u32(cd, 0x0201_4b50);
+        push_u16(cd, 20); // Version made by
+        push_u16(cd, 20); // Version needed to extract
+        push_u16(cd, 0x0800);
+        push_u16(cd, 0);
+        push_u16(cd, 0);
+        push_u16(cd, dos_date);
+        push_u32(cd, crc);
+        push_u32(cd, size);
+        push_u32(cd, size);
+        push_u16(cd, path.len() as u16);
+        push_u16(cd, 0); // No extra fields
+        push_u16(cd, 0); // No comment
+        push_u16(cd, 0); // Disk number
+        push_u16(cd, 0); // Internal attributes
+        push_u32(cd, 0); // External attributes
+        push_u32(cd, offset);
+        cd.extend_from_slice(path.as_bytes());
+
+        self.files += 1;
+        Ok(())
+    }
+
+    /// Finishes the zip file, returning its bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        let offset = self.data.len() as u32;
+        let size = self.central_directory.len() as u32;
+        self.data.append(&mut self.central_directory);
+
+        // End of central directory record
+        push_u32(&mut self.data, 0x0605_4b50);
+        push_u16(&mut self.data, 0); // This disk
+        push_u16(&mut self.data, 0); // The disk the central directory starts on
+        push_u16(&mut self.data, self.files as u16);
+        push_u16(&mut self.data, self.files as u16);
+        push_u32(&mut self.data, size);
+        push_u32(&mut self.data, offset);
+        push_u16(&mut self.data, 0); // No comment
+
+        self.data
+    }
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+/// The CRC-32 checksum zip files store for each file.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// Reads back the files in a zip from `ZipWriter`, as their paths, dates and
+/// contents, checking each against its CRC-32 and the central directory.
+#[cfg(test)]
+pub fn read_zip(zip: &[u8]) -> Vec<(String, u16, Vec<u8>)> {
+    let u16_at = |at: usize| u16::from_le_bytes([zip[at], zip[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes([zip[at], zip[at + 1], zip[at + 2], zip[at + 3]]);
+
+    let end = zip.len() - 22;
+    assert_eq!(u32_at(end), 0x0605_4b50);
+    let files = u16_at(end + 10) as usize;
+    let mut cd = u32_at(end + 16) as usize;
+
+    let mut read = Vec::new();
+    for _ in 0..files {
+        assert_eq!(u32_at(cd), 0x0201_4b50);
+        let path_len = u16_at(cd + 28) as usize;
+        let offset = u32_at(cd + 42) as usize;
+
+        assert_eq!(u32_at(offset), 0x0403_4b50);
+        assert_eq!(zip[offset + 14..offset + 26], zip[cd + 16..cd + 28]);
+        let size = u32_at(offset + 18) as usize;
+        let start = offset + 30 + path_len;
+        let contents = zip[start..start + size].to_vec();
+        assert_eq!(crc32(&contents), u32_at(offset + 14));
+
+        let path = String::from_utf8(zip[cd + 46..cd + 46 + path_len].to_vec()).unwrap();
+        read.push((path, u16_at(cd + 14), contents));
+        cd += 46 + path_len;
+    }
+
+    read
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_standard_check_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xE8B7_BE43);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn a_zip_lists_its_files_in_order() {
+        let mut zip = ZipWriter::new();
+        zip.add_file(
+            "entries/2024-06-01.md",
+            b"Dear diary",
+            NaiveDate::from_ymd(2024, 6, 1),
+        )
+        .unwrap();
+        zip.add_file("empty.txt", b"", NaiveDate::from_ymd(1970, 1, 1))
+            .unwrap();
+
+        assert_eq!(
+            read_zip(&zip.finish()),
+            vec![
+                (
+                    "entries/2024-06-01.md".to_string(),
+                    (44 << 9) | (6 << 5) | 1,
+                    b"Dear diary".to_vec()
+                ),
+                // Dates before 1980 are recorded as January 1, 1980
+                ("empty.txt".to_string(), (1 << 5) | 1, Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_empty_zip_has_no_files() {
+        assert_eq!(ZipWriter::new().finish().len(), 22);
+        assert!(read_zip(&ZipWriter::new().finish()).is_empty());
+    }
+}
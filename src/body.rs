@@ -0,0 +1,162 @@
+//! Cleaning up the body of a journal email before it's saved, with the
+//! `body_processors` picked in the config.
+
+/// One clean-up, given the body so far and returning the cleaned up body.
+type Step = fn(&str) -> String;
+
+/// Every processor there is, by the name the config uses for it.
+const PROCESSORS: &[(&str, Step)] = &[
+    ("strip_quotes", strip_quotes),
+    ("strip_signature", strip_signature),
+    ("normalize", normalize),
+];
+
+/// The processors from the config, run one after the other in the order they're
+/// listed. With none, the body is saved just as it arrived.
+pub struct BodyProcessor {
+    steps: Vec<Step>,
+}
+
+impl BodyProcessor {
+    pub fn new(names: &[String]) -> Result<BodyProcessor, String> {
+        let steps = names
+            .iter()
+            .map(|name| {
+                PROCESSORS
+                    .iter()
+                    .find(|(known, _)| known == name)
+                    .map(|(_, step)| *step)
+                    .ok_or_else(|| {
+                        let known: Vec<&str> = PROCESSORS.iter().map(|(known, _)| *known).collect();
+                        format!(
+                            "body_processors has \"{}\", which isn't one of {}.",
+                            name,
+                            known.join(", ")
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(BodyProcessor { steps })
+    }
+
+    pub fn process(&self, body: &str) -> String {
+        self.steps
+            .iter()
+            .fold(body.to_string(), |body, step| step(&body))
+    }
+}
+
+/// Drops the quoted lines of a reply, like those starting with `>`, along with the
+/// "On ... wrote:" line introducing them.
+fn strip_quotes(body: &str) -> String {
+    let lines: Vec<&str> = body.lines().collect();
+    let is_quoted = |line: &str| line.trim_start().starts_with('>');
+
+    let mut kept = Vec::new();
+    for (ix, line) in lines.iter().enumerate() {
+        if is_quoted(line) {
+            continue;
+        }
+
+        // The line a mail client puts above what it quotes, which can be followed
+        // by a blank line before the quote starts
+        let trimmed = line.trim();
+        if trimmed.starts_with("On ") && trimmed.ends_with("wrote:") {
+            let next = lines[ix + 1..].iter().find(|line| !line.trim().is_empty());
+            if next.is_none_or(|next| is_quoted(next)) {
+                continue;
+            }
+        }
+
+        kept.push(*line);
+    }
+
+    kept.join("\n")
+}
+
+/// Cuts the body off at the signature, which starts with a `-- ` line.
+fn strip_signature(body: &str) -> String {
+    body.lines()
+        .take_while(|line| line.trim_end() != "--")
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Tidies up the whitespace: drops the spaces at the end of every line, the blank
+/// lines at the start and end, and any run of more than one blank line.
+fn normalize(body: &str) -> String {
+    let mut normalized: Vec<&str> = Vec::new();
+    for line in body.lines().map(str::trim_end) {
+        let after_blank = normalized.last().is_none_or(|last| last.is_empty());
+        if !(line.is_empty() && after_blank) {
+            normalized.push(line);
+        }
+    }
+
+    while normalized.last() == Some(&"") {
+        normalized.pop();
+    }
+
+    normalized.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(names: &[&str], body: &str) -> String {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        BodyProcessor::new(&names).unwrap().process(body)
+    }
+
+    #[test]
+    fn no_processors_leave_the_body_as_it_is() {
+        assert_eq!(
+            process(&[], "  As it arrived  \n\n"),
+            "  As it arrived  \n\n"
+        );
+    }
+
+    #[test]
+    fn unknown_processors_are_rejected() {
+        let error = BodyProcessor::new(&["strip_quotes".to_string(), "shout".to_string()])
+            .err()
+            .unwrap();
+        assert!(error.contains("\"shout\""));
+    }
+
+    #[test]
+    fn strip_quotes_drops_the_quoted_reply() {
+        let body =
+            "My entry\n\nOn Tue, Jan 1, 2019, Journal wrote:\n\n> How was your day?\n> Reply here";
+        assert_eq!(process(&["strip_quotes"], body), "My entry\n\n");
+    }
+
+    #[test]
+    fn strip_quotes_keeps_a_line_that_only_looks_like_an_introduction() {
+        let body = "On Monday, my friend wrote:\nthat she's coming to visit";
+        assert_eq!(process(&["strip_quotes"], body), body);
+    }
+
+    #[test]
+    fn strip_signature_cuts_at_the_signature() {
+        let body = "My entry\n-- \nJohn Smith\nSent from my phone";
+        assert_eq!(process(&["strip_signature"], body), "My entry");
+    }
+
+    #[test]
+    fn normalize_tidies_the_whitespace() {
+        let body = "\n\nFirst line   \n\n\n\nSecond line\t\n\n";
+        assert_eq!(process(&["normalize"], body), "First line\n\nSecond line");
+    }
+
+    #[test]
+    fn processors_run_in_the_order_given() {
+        let body = "My entry  \n\n\n-- \nJohn\n\n> quoted";
+        assert_eq!(
+            process(&["strip_quotes", "strip_signature", "normalize"], body),
+            "My entry"
+        );
+    }
+}
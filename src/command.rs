@@ -0,0 +1,974 @@
+//! Commands sent in the subject line of an email.
+
+use crate::config::Config;
+use crate::error;
+use crate::export::{export_archive, export_top_words, export_word_trend};
+use crate::mail::{
+    extract_address, send_error_email, send_reply, send_reply_with_attachment, Email,
+};
+use crate::schedule::{build_reminder_body, send_reminder, truncate_chars};
+use crate::store::{
+    clear_state, get_state, open_current_db, open_storage, open_storage_with_history, set_state,
+};
+use chrono::naive::MIN_DATE;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// A command sent to Mail Journal in the subject line of an email.
+pub enum Command {
+    /// `DELETE YYYY-MM-DD`: Removes the entry for the given date
+    Delete(NaiveDate),
+    /// `TREND`: Replies with the number of words written each day, as CSV
+    Trend,
+    /// `PAUSE <days>`: Skips the reminders for the given number of days, starting today
+    Pause(u32),
+    /// `PAUSE WEEKENDS`: Skips the reminders every Saturday and Sunday
+    PauseWeekends,
+    /// `RESUME`: Undoes any `PAUSE`
+    Resume,
+    /// `STAR YYYY-MM-DD`: Marks the entry for the given date as a favorite
+    Star(NaiveDate),
+    /// `UNSTAR YYYY-MM-DD`: Undoes `STAR`
+    Unstar(NaiveDate),
+    /// `FAVORITES`: Replies with every starred entry
+    Favorites,
+    /// `WORDS [count]`: Replies with the most used words across all entries
+    Words(Option<usize>),
+    /// `EXPORT ALL`: Replies with the whole journal, as a zip file
+    ExportAll,
+    /// `TODAY`: Replies with the entry for today so far
+    Today,
+    /// `HELP`: Replies with the list of commands
+    Help,
+    /// `COUNT`: Replies with the number of entries, and the current streak
+    Count,
+    /// `LAST`: Replies with the start of the most recent entry
+    Last,
+    /// `REMIND`: Sends today's reminder right away, besides the scheduled one
+    Remind,
+    /// `FREEZES`: Replies with the streak freezes banked
+    Freezes,
+}
+
+impl Command {
+    /// One of each command, in the order the `HELP` reply lists them. The
+    /// arguments are placeholders, since only `usage` is used.
+    const ALL: &'static [Command] = &[
+        Command::Delete(MIN_DATE),
+        Command::Star(MIN_DATE),
+        Command::Unstar(MIN_DATE),
+        Command::Favorites,
+        Command::Today,
+        Command::Count,
+        Command::Freezes,
+        Command::Last,
+        Command::Remind,
+        Command::Pause(1),
+        Command::PauseWeekends,
+        Command::Resume,
+        Command::Trend,
+        Command::Words(None),
+        Command::ExportAll,
+        Command::Help,
+    ];
+
+    /// How the command is written, and what it does, for the `HELP` reply and the
+    /// welcome email.
+    fn usage(&self) -> (&'static str, &'static str) {
+        match self {
+            Command::Delete(_) => ("DELETE YYYY-MM-DD", "Deletes an entry"),
+            Command::Star(_) => ("STAR YYYY-MM-DD", "Stars an entry"),
+            Command::Unstar(_) => ("UNSTAR YYYY-MM-DD", "Unstars an entry"),
+            Command::Favorites => ("FAVORITES", "Lists your starred entries"),
+            Command::Today => ("TODAY", "Shows what you've written today so far"),
+            Command::Count => ("COUNT", "Your number of entries, and your current streak"),
+            Command::Freezes => ("FREEZES", "How many streak freezes you have"),
+            Command::Last => ("LAST", "The start of your most recent entry"),
+            Command::Remind => ("REMIND", "Sends today's reminder now"),
+            Command::Pause(_) => ("PAUSE <days>", "Skips reminders for that many days"),
+            Command::PauseWeekends => ("PAUSE WEEKENDS", "Skips reminders on weekends"),
+            Command::Resume => ("RESUME", "Undoes PAUSE"),
+            Command::Trend => ("TREND", "Your word count for each day, as CSV"),
+            Command::Words(_) => ("WORDS [count]", "Your most used words"),
+            Command::ExportAll => ("EXPORT ALL", "Your whole journal, as a zip file"),
+            Command::Help => ("HELP", "This list of commands"),
+        }
+    }
+}
+
+/// Lists every command, a line each.
+pub fn help_text() -> String {
+    Command::ALL
+        .iter()
+        .map(|command| {
+            let (usage, description) = command.usage();
+            format!("{}: {}", usage, description)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Parses a command from an email subject. Returns `None` if the subject isn't a
+/// command at all (so the email is a journal entry), or an error message if it
+/// is a command with bad arguments. A subject that starts with a command's name but
+/// doesn't have the number of arguments it takes, like "Delete this later: ..." or
+/// "Resume writing", isn't a command either.
+pub fn parse_command(subject: &str) -> Option<Result<Command, String>> {
+    let mut words = subject.split_whitespace();
+    let name = words.next()?.to_uppercase();
+    let args: Vec<&str> = words.collect();
+
+    let command = match (name.as_str(), args.as_slice()) {
+        ("DELETE", [date]) => entry_ref_arg(date).map(Command::Delete),
+        ("TREND", []) => Ok(Command::Trend),
+        ("PAUSE", [arg]) if arg.eq_ignore_ascii_case("WEEKENDS") => Ok(Command::PauseWeekends),
+        ("PAUSE", [arg]) => match arg.parse::<u32>() {
+            Ok(days) if days > 0 => Ok(Command::Pause(days)),
+            _ => Err("Usage: PAUSE <days> or PAUSE WEEKENDS".to_string()),
+        },
+        ("RESUME", []) => Ok(Command::Resume),
+        ("STAR", [date]) => entry_ref_arg(date).map(Command::Star),
+        ("UNSTAR", [date]) => entry_ref_arg(date).map(Command::Unstar),
+        ("FAVORITES", []) => Ok(Command::Favorites),
+        ("TODAY", []) => Ok(Command::Today),
+        ("HELP", []) => Ok(Command::Help),
+        ("COUNT", []) => Ok(Command::Count),
+        ("FREEZES", []) => Ok(Command::Freezes),
+        ("LAST", []) => Ok(Command::Last),
+        ("REMIND", []) => Ok(Command::Remind),
+        ("WORDS", []) => Ok(Command::Words(None)),
+        ("WORDS", [arg]) => match arg.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(Command::Words(Some(count))),
+            _ => Err("Usage: WORDS [count]".to_string()),
+        },
+        ("EXPORT", [arg]) if arg.eq_ignore_ascii_case("ALL") => Ok(Command::ExportAll),
+        ("EXPORT", [_]) => Err("Usage: EXPORT ALL".to_string()),
+        _ => return None,
+    };
+
+    Some(command)
+}
+
+/// Parses the command an email is sending, like `parse_command` does for its
+/// subject. A reply of just "help" (like to a reminder) asks for the commands too,
+/// rather than being an entry.
+pub fn parse_email_command(email: &Email) -> Option<Result<Command, String>> {
+    parse_command(&email.subject).or_else(|| {
+        if email.body.trim().eq_ignore_ascii_case("help") {
+            Some(Ok(Command::Help))
+        } else {
+            None
+        }
+    })
+}
+
+/// How much of the last entry the `LAST` reply shows.
+const LAST_SNIPPET_CHARS: usize = 200;
+
+/// The current streak, and the streak freezes banked to keep it going.
+#[derive(Debug, PartialEq)]
+struct Streak {
+    /// The days with an entry since the streak started
+    days: usize,
+    freezes: u32,
+}
+
+/// Works out the current streak: the days in a row, up to today, that have an
+/// entry. Today not having one yet doesn't break the streak, since there's still
+/// time to write it. With `freeze_days`, every that many days written in a row earn
+/// a freeze, and a missed day uses one up instead of breaking the streak. It's all
+/// worked out from the entries, going through the whole journal, so the same
+/// entries always give the same streak.
+fn current_streak(dates: &[NaiveDate], today: NaiveDate, freeze_days: Option<u32>) -> Streak {
+    let mut streak = Streak {
+        days: 0,
+        freezes: 0,
+    };
+    let mut day = match dates.iter().min() {
+        Some(&first) => first,
+        None => return streak,
+    };
+
+    let written: HashSet<NaiveDate> = dates.iter().cloned().collect();
+    while day <= today {
+        if written.contains(&day) {
+            streak.days += 1;
+            if freeze_days.is_some_and(|n| streak.days.is_multiple_of(n as usize)) {
+                streak.freezes += 1;
+            }
+        } else if day == today {
+            break;
+        } else if streak.freezes > 0 {
+            streak.freezes -= 1;
+        } else {
+            streak.days = 0;
+        }
+
+        day = day.succ();
+    }
+
+    streak
+}
+
+/// How an entry is referred to in replies, and can be in commands: by its date,
+/// like `#2024-06-01`.
+pub fn entry_ref(date: NaiveDate) -> String {
+    format!("#{}", date.format("%Y-%m-%d"))
+}
+
+/// Reads a reference to an entry, either as its `entry_ref` or just the date.
+pub fn parse_entry_ref(s: &str) -> Option<NaiveDate> {
+    let date = s.strip_prefix('#').unwrap_or(s);
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// Reads the entry a command is about, with an error message for the reply if it
+/// isn't one.
+fn entry_ref_arg(s: &str) -> Result<NaiveDate, String> {
+    parse_entry_ref(s).ok_or_else(|| {
+        format!(
+            "\"{}\" is not a valid date. Entries are referred to by their date, written as YYYY-MM-DD or #YYYY-MM-DD.",
+            s
+        )
+    })
+}
+
+pub fn parse_date(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| {
+        format!(
+            "\"{}\" is not a valid date. Dates must be written as YYYY-MM-DD.",
+            s
+        )
+    })
+}
+
+/// Records a command from `sender`, and checks that it's within
+/// `max_commands_per_hour`. This stops a client that auto-replies to our replies
+/// from turning into an endless back and forth.
+pub fn allow_command(config: &Config, sender: &str, now: DateTime<Utc>) -> rusqlite::Result<bool> {
+    let max_commands = match config.max_commands_per_hour {
+        Some(max_commands) => max_commands,
+        None => return Ok(true),
+    };
+
+    // The times of the sender's commands over the last hour, oldest first
+    let sql_conn = open_current_db(config, now.naive_utc().date())?;
+    let key = format!("command_times:{}", extract_address(sender).to_lowercase());
+    let mut times: Vec<DateTime<Utc>> = get_state(&sql_conn, &key)?
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Utc))
+        .filter(|&t| now - t < Duration::hours(1))
+        .collect();
+
+    let allowed = times.len() < max_commands;
+    if allowed {
+        times.push(now);
+    }
+
+    let times: Vec<String> = times.iter().map(|t| t.to_rfc3339()).collect();
+    set_state(&sql_conn, &key, &times.join(","))?;
+
+    Ok(allowed)
+}
+
+pub fn run_command(
+    config: &Config,
+    email: &Email,
+    command: Command,
+    now: DateTime<Utc>,
+) -> error::Result<()> {
+    let today = now.naive_utc().date();
+
+    match command {
+        Command::Delete(date) => {
+            match open_storage(config, date.year())?.delete_by_date(date)? {
+                Some(entry) => {
+                    log!("Deleted journal entry for {}", date);
+
+                    // Include the deleted entry, so it isn't lost for good if this was a mistake
+                    send_reply(
+                        config,
+                        &email.reply_to,
+                        &format!("Deleted entry {}", entry_ref(date)),
+                        &format!(
+                            "Your journal entry {} was deleted. This is what it said:\n\n\"{}\"",
+                            entry_ref(date),
+                            entry.body.trim()
+                        ),
+                    );
+                }
+                None => send_error_email(
+                    config,
+                    &email.reply_to,
+                    &format!("There is no journal entry {} to delete.", entry_ref(date)),
+                ),
+            }
+        }
+        Command::Trend => send_reply(
+            config,
+            &email.reply_to,
+            "Word count trend",
+            &export_word_trend(&*open_storage_with_history(config, today)?, today)?,
+        ),
+        Command::Pause(days) => {
+            let until = today + Duration::days(i64::from(days) - 1);
+            set_state(
+                &open_current_db(config, today)?,
+                "pause_until",
+                &until.to_string(),
+            )?;
+            log!("Reminders paused through {}", until);
+
+            send_reply(
+                config,
+                &email.reply_to,
+                "Reminders paused",
+                &format!(
+                    "You won't get any reminders through {}. Send RESUME to start them again sooner.",
+                    until
+                ),
+            );
+        }
+        Command::PauseWeekends => {
+            set_state(&open_current_db(config, today)?, "pause_weekends", "true")?;
+            log!("Reminders paused on weekends");
+
+            send_reply(
+                config,
+                &email.reply_to,
+                "Reminders paused on weekends",
+                "You won't get any reminders on Saturdays and Sundays. Send RESUME to get them every day again.",
+            );
+        }
+        Command::Resume => {
+            let sql_conn = open_current_db(config, today)?;
+            clear_state(&sql_conn, "pause_until")?;
+            clear_state(&sql_conn, "pause_weekends")?;
+            log!("Reminders resumed");
+
+            send_reply(
+                config,
+                &email.reply_to,
+                "Reminders resumed",
+                "Your daily reminders are back on.",
+            );
+        }
+        Command::Star(date) | Command::Unstar(date) => {
+            let is_favorite = matches!(command, Command::Star(_));
+
+            if open_storage(config, date.year())?.set_favorite(date, is_favorite)? {
+                let action = if is_favorite { "Starred" } else { "Unstarred" };
+                log!("{} the journal entry for {}", action, date);
+
+                send_reply(
+                    config,
+                    &email.reply_to,
+                    &format!("{} entry {}", action, entry_ref(date)),
+                    &format!(
+                        "Your journal entry {} was {}.",
+                        entry_ref(date),
+                        action.to_lowercase()
+                    ),
+                );
+            } else {
+                send_error_email(
+                    config,
+                    &email.reply_to,
+                    &format!(
+                        "There is no journal entry {} to star or unstar.",
+                        entry_ref(date)
+                    ),
+                );
+            }
+        }
+        Command::Favorites => {
+            let favorites = open_storage_with_history(config, today)?.fetch_favorites()?;
+            let message = if favorites.is_empty() {
+                "You haven't starred any journal entries yet. Send STAR YYYY-MM-DD to star one."
+                    .to_string()
+            } else {
+                favorites
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{} ({}):\n\"{}\"",
+                            entry.date.format(&config.date_format),
+                            entry_ref(entry.date),
+                            entry.body.trim()
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n\n")
+            };
+
+            send_reply(config, &email.reply_to, "Favorite entries", &message);
+        }
+        Command::Today => {
+            // Everything appended to today's entry so far is part of its body
+            let message = match open_storage(config, today.year())?.fetch_by_date(today)? {
+                Some(entry) => entry.body.trim().to_string(),
+                None => "No entry yet today.".to_string(),
+            };
+
+            send_reply(
+                config,
+                &email.reply_to,
+                &format!("Your entry for {}", today.format(&config.date_format)),
+                &message,
+            );
+        }
+        Command::Count => {
+            let entries = open_storage_with_history(config, today)?.fetch_all()?;
+            let dates: Vec<NaiveDate> = entries.iter().map(|entry| entry.date).collect();
+
+            send_reply(
+                config,
+                &email.reply_to,
+                "Entry count",
+                &format!(
+                    "You've written {} journal entries, and your current streak is {} day(s).",
+                    entries.len(),
+                    current_streak(&dates, today, config.streak_freeze_days).days
+                ),
+            );
+        }
+        Command::Freezes => {
+            let message = match config.streak_freeze_days {
+                Some(freeze_days) => {
+                    let entries = open_storage_with_history(config, today)?.fetch_all()?;
+                    let dates: Vec<NaiveDate> = entries.iter().map(|entry| entry.date).collect();
+                    let streak = current_streak(&dates, today, Some(freeze_days));
+                    format!(
+                        "You have {} streak freeze(s), and your current streak is {} day(s). A freeze keeps your streak going when you miss a day, and you earn one for every {} days in a row you write.",
+                        streak.freezes, streak.days, freeze_days
+                    )
+                }
+                None => "Streak freezes are off. Set streak_freeze_days to earn them.".to_string(),
+            };
+
+            send_reply(config, &email.reply_to, "Streak freezes", &message);
+        }
+        Command::Last => {
+            let message = match open_storage_with_history(config, today)?
+                .fetch_all()?
+                .last()
+            {
+                Some(entry) => format!(
+                    "Your last journal entry was on {} ({}):\n\n\"{}\"",
+                    entry.date.format(&config.date_format),
+                    entry_ref(entry.date),
+                    truncate_chars(entry.body.trim(), LAST_SNIPPET_CHARS)
+                ),
+                None => "You haven't written any journal entries yet.".to_string(),
+            };
+
+            send_reply(config, &email.reply_to, "Last entry", &message);
+        }
+        // Only sent, so the scheduled reminder still goes out as usual
+        Command::Remind => {
+            let body = build_reminder_body(config, today)?;
+            match send_reminder(config, email.reply_to.as_str(), &[], &body) {
+                Ok(()) => log!("Sent the journal reminder to {} on request", email.reply_to),
+                Err(e) => log_error!(
+                    "Failed to send the requested journal reminder to {}: {}",
+                    email.reply_to,
+                    e
+                ),
+            }
+        }
+        Command::Help => send_reply(
+            config,
+            &email.reply_to,
+            "Mail Journal commands",
+            &format!(
+                "Send a command by putting it in the subject line of an email:\n\n{}",
+                help_text()
+            ),
+        ),
+        Command::Words(count) => send_reply(
+            config,
+            &email.reply_to,
+            "Most used words",
+            &export_top_words(
+                config,
+                &*open_storage_with_history(config, today)?,
+                count.unwrap_or(config.top_words_count),
+            )?,
+        ),
+        Command::ExportAll => {
+            let archive =
+                export_archive(config, &*open_storage_with_history(config, today)?, today)?;
+
+            // Attachments are sent from a file, so the zip is saved either way
+            let path =
+                Path::new(&config.export_dir).join(format!("mail-journal-export-{}.zip", today));
+            std::fs::create_dir_all(&config.export_dir)?;
+            std::fs::write(&path, &archive)?;
+            log!(
+                "Exported the journal to {} ({} bytes)",
+                path.display(),
+                archive.len()
+            );
+
+            let emailed = archive.len() <= config.max_export_email_bytes
+                && send_reply_with_attachment(
+                    config,
+                    &email.reply_to,
+                    "Your journal export",
+                    "Your whole journal is attached, as a zip file.",
+                    Some(&path),
+                );
+
+            // Only kept on the server if it couldn't be emailed
+            if emailed {
+                std::fs::remove_file(&path)?;
+            } else {
+                send_reply(
+                    config,
+                    &email.reply_to,
+                    "Your journal export",
+                    &format!(
+                        "Your journal export is {:.1} MB, which couldn't be sent by email, so it was saved on the Mail Journal server at {}.",
+                        archive.len() as f64 / (1024.0 * 1024.0),
+                        path.display()
+                    ),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicatePolicy;
+    use crate::store::store_journal_email;
+    use crate::test_util::{sent_emails, test_config, TempDir};
+
+    fn june_first() -> NaiveDate {
+        NaiveDate::from_ymd(2024, 6, 1)
+    }
+
+    #[test]
+    fn parse_entry_ref_takes_a_bare_date() {
+        assert_eq!(parse_entry_ref("2024-06-01"), Some(june_first()));
+    }
+
+    #[test]
+    fn parse_entry_ref_takes_an_entry_ref() {
+        assert_eq!(parse_entry_ref("#2024-06-01"), Some(june_first()));
+        assert_eq!(
+            parse_entry_ref(&entry_ref(june_first())),
+            Some(june_first())
+        );
+    }
+
+    #[test]
+    fn parse_entry_ref_rejects_garbage() {
+        for garbage in &[
+            "",
+            "#",
+            "yesterday",
+            "2024-13-01",
+            "2024-02-30",
+            "06/01/2024",
+            "##2024-06-01",
+            "2024-06-01#",
+            "#2024-06-01x",
+        ] {
+            assert_eq!(parse_entry_ref(garbage), None, "{:?}", garbage);
+        }
+    }
+
+    #[test]
+    fn entry_ref_is_the_date_with_a_hash() {
+        assert_eq!(entry_ref(june_first()), "#2024-06-01");
+    }
+
+    #[test]
+    fn help_lists_every_command_as_it_parses() {
+        let help = help_text();
+        assert_eq!(help.lines().count(), Command::ALL.len());
+
+        for command in Command::ALL {
+            let (usage, _) = command.usage();
+            let example = usage
+                .replace("YYYY-MM-DD", "2024-06-01")
+                .replace("<days>", "3")
+                .replace("[count]", "10");
+            match parse_command(&example) {
+                Some(Ok(parsed)) => assert_eq!(
+                    std::mem::discriminant(&parsed),
+                    std::mem::discriminant(command),
+                    "{}",
+                    usage
+                ),
+                _ => panic!("\"{}\" doesn't parse", example),
+            }
+        }
+    }
+
+    fn email(subject: &str, body: &str) -> Email {
+        let raw = format!(
+            "From: me@example.com\r\nSubject: {}\r\nDate: Sat, 1 Jun 2024 10:00:00 +0000\r\n\r\n{}\r\n",
+            subject, body
+        );
+        Email::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn a_help_reply_asks_for_the_commands() {
+        for body in &["help", "  HELP\r\n", "Help"] {
+            assert!(matches!(
+                parse_email_command(&email("Re: Journal", body)),
+                Some(Ok(Command::Help))
+            ));
+        }
+    }
+
+    #[test]
+    fn an_entry_that_mentions_help_is_still_an_entry() {
+        assert!(parse_email_command(&email("Re: Journal", "I needed some help today")).is_none());
+        assert!(parse_email_command(&email("Re: Journal", "Today was good")).is_none());
+    }
+
+    fn noon() -> DateTime<Utc> {
+        Utc.ymd(2024, 6, 1).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn today_replies_with_everything_appended_today() {
+        let dir = TempDir::new("today-appended");
+        let config = Config {
+            duplicate_policy: DuplicatePolicy::Append,
+            ..test_config(&dir)
+        };
+        store_journal_email(
+            &config,
+            &email("Re: Journal", "A walk in the morning"),
+            noon(),
+        )
+        .unwrap();
+        store_journal_email(
+            &config,
+            &email("Re: Journal", "Dinner with friends"),
+            noon(),
+        )
+        .unwrap();
+        sent_emails();
+
+        run_command(&config, &email("TODAY", ""), Command::Today, noon()).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), "Your entry for June 1, 2024");
+        let body = sent[0].body();
+        assert!(body.contains("A walk in the morning"), "{}", body);
+        assert!(body.contains("Dinner with friends"), "{}", body);
+    }
+
+    #[test]
+    fn today_without_an_entry_says_so() {
+        let dir = TempDir::new("today-empty");
+        let config = test_config(&dir);
+
+        run_command(&config, &email("TODAY", ""), Command::Today, noon()).unwrap();
+
+        assert_eq!(sent_emails()[0].body().trim(), "No entry yet today.");
+    }
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2024, 6, d)
+    }
+
+    /// A journal with an entry on each of the days of June 2024.
+    fn seeded_config(dir: &TempDir, days: &[u32]) -> Config {
+        let config = test_config(dir);
+        let storage = open_storage(&config, 2024).unwrap();
+        for &d in days {
+            storage
+                .insert_entry(day(d), &format!("Entry for June {}", d))
+                .unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn current_streak_counts_the_days_in_a_row_up_to_today() {
+        assert_eq!(
+            current_streak(&[day(8), day(9), day(10)], day(10), None).days,
+            3
+        );
+        assert_eq!(
+            current_streak(&[day(3), day(8), day(9), day(10)], day(10), None).days,
+            3
+        );
+    }
+
+    #[test]
+    fn current_streak_isnt_broken_until_today_is_over() {
+        assert_eq!(current_streak(&[day(8), day(9)], day(10), None).days, 2);
+    }
+
+    #[test]
+    fn current_streak_is_broken_by_a_missed_day() {
+        assert_eq!(current_streak(&[day(7), day(8)], day(10), None).days, 0);
+        assert_eq!(current_streak(&[], day(10), None).days, 0);
+    }
+
+    #[test]
+    fn a_freeze_keeps_the_streak_going_over_a_missed_day() {
+        let week: Vec<NaiveDate> = (1..=7).map(day).collect();
+        assert_eq!(
+            current_streak(&week, day(7), Some(7)),
+            Streak {
+                days: 7,
+                freezes: 1
+            }
+        );
+
+        // June 8 is missed, and the freeze is used up on it
+        let mut dates = week.clone();
+        dates.push(day(9));
+        assert_eq!(
+            current_streak(&dates, day(9), Some(7)),
+            Streak {
+                days: 8,
+                freezes: 0
+            }
+        );
+
+        // With no freeze left, the next missed day breaks the streak
+        dates.push(day(11));
+        assert_eq!(
+            current_streak(&dates, day(11), Some(7)),
+            Streak {
+                days: 1,
+                freezes: 0
+            }
+        );
+    }
+
+    #[test]
+    fn freezes_are_only_earned_with_streak_freeze_days() {
+        let dates: Vec<NaiveDate> = (1..=7).chain(9..=10).map(day).collect();
+        assert_eq!(
+            current_streak(&dates, day(10), None),
+            Streak {
+                days: 2,
+                freezes: 0
+            }
+        );
+        assert_eq!(
+            current_streak(&dates, day(10), Some(3)),
+            Streak {
+                days: 9,
+                freezes: 2
+            }
+        );
+    }
+
+    #[test]
+    fn freezes_replies_with_the_freezes_banked() {
+        let dir = TempDir::new("freezes");
+        let config = Config {
+            streak_freeze_days: Some(2),
+            ..seeded_config(&dir, &[1, 2, 3, 4, 6])
+        };
+
+        run_command(
+            &config,
+            &email("FREEZES", ""),
+            Command::Freezes,
+            noon() + Duration::days(5),
+        )
+        .unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent[0].subject(), "Streak freezes");
+        assert!(sent[0]
+            .body()
+            .starts_with("You have 1 streak freeze(s), and your current streak is 5 day(s)."));
+    }
+
+    #[test]
+    fn count_replies_with_the_entries_and_the_streak() {
+        let dir = TempDir::new("count");
+        let config = seeded_config(&dir, &[1, 3, 4, 5]);
+        let now = Utc.ymd(2024, 6, 5).and_hms(12, 0, 0);
+
+        run_command(&config, &email("COUNT", ""), Command::Count, now).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent[0].subject(), "Entry count");
+        assert_eq!(
+            sent[0].body().trim(),
+            "You've written 4 journal entries, and your current streak is 3 day(s)."
+        );
+    }
+
+    #[test]
+    fn last_replies_with_the_most_recent_entry() {
+        let dir = TempDir::new("last");
+        let config = seeded_config(&dir, &[4, 1, 2]);
+
+        run_command(&config, &email("LAST", ""), Command::Last, noon()).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent[0].subject(), "Last entry");
+        assert_eq!(
+            sent[0].body().trim(),
+            "Your last journal entry was on June 4, 2024 (#2024-06-04):\n\n\"Entry for June 4\""
+        );
+    }
+
+    #[test]
+    fn last_without_any_entries_says_so() {
+        let dir = TempDir::new("last-empty");
+        let config = test_config(&dir);
+
+        run_command(&config, &email("LAST", ""), Command::Last, noon()).unwrap();
+
+        assert_eq!(
+            sent_emails()[0].body().trim(),
+            "You haven't written any journal entries yet."
+        );
+    }
+
+    /// Everything the journal keeps in its state table, like when the last reminder
+    /// was sent.
+    fn all_state(config: &Config) -> Vec<(String, String)> {
+        let sql_conn = open_current_db(config, noon().naive_utc().date()).unwrap();
+        let mut stmt = sql_conn
+            .prepare("SELECT key, value FROM state ORDER BY key")
+            .unwrap();
+        let rows = stmt
+            .query_map(rusqlite::NO_PARAMS, |row| (row.get(0), row.get(1)))
+            .unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn remind_sends_todays_reminder_and_leaves_the_schedule_alone() {
+        let dir = TempDir::new("remind");
+        let config = Config {
+            reminder_prompts: vec!["How was it?".to_string(), "What happened?".to_string()],
+            ..test_config(&dir)
+        };
+        open_storage(&config, 2023)
+            .unwrap()
+            .insert_entry(NaiveDate::from_ymd(2023, 6, 1), "A year ago today")
+            .unwrap();
+        crate::schedule::set_reminder_state(&config, day(1), "sent", noon()).unwrap();
+        let state = all_state(&config);
+
+        let now = noon() + Duration::hours(3);
+        run_command(&config, &email("REMIND", ""), Command::Remind, now).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, vec!["me@example.com".to_string()]);
+        assert_eq!(sent[0].subject(), "Daily Journal Entry");
+        let expected = build_reminder_body(&config, day(1)).unwrap().text;
+        assert_eq!(sent[0].body().trim(), expected.replace("\r\n", "\n").trim());
+        assert!(expected.contains("A year ago today"));
+        assert_eq!(all_state(&config), state);
+    }
+
+    #[test]
+    fn date_taking_commands_take_either_form() {
+        assert!(matches!(
+            parse_command("DELETE 2024-06-01"),
+            Some(Ok(Command::Delete(date))) if date == june_first()
+        ));
+        assert!(matches!(
+            parse_command("star #2024-06-01"),
+            Some(Ok(Command::Star(date))) if date == june_first()
+        ));
+        assert!(matches!(
+            parse_command("UNSTAR #2024-06-01"),
+            Some(Ok(Command::Unstar(date))) if date == june_first()
+        ));
+        assert!(matches!(parse_command("DELETE soon"), Some(Err(_))));
+    }
+
+    #[test]
+    fn a_subject_that_only_starts_like_a_command_is_an_entry() {
+        for subject in &[
+            "Delete this later: call the bank",
+            "Today was great",
+            "Count your blessings",
+            "Last day of school",
+            "Remind me to call mom",
+            "Help with the move",
+            "DELETE",
+            "Star of the show",
+            "Trend of the week",
+            "Resume writing",
+            "Favorites of the year",
+            "Pause for a moment",
+            "Words fail me today",
+            "Export business is booming",
+        ] {
+            assert!(parse_command(subject).is_none(), "{:?}", subject);
+        }
+    }
+
+    #[test]
+    fn a_command_with_bad_arguments_is_an_error() {
+        for subject in &[
+            "PAUSE 0",
+            "PAUSE soon",
+            "WORDS many",
+            "EXPORT SOME",
+            "STAR 2024-13-01",
+        ] {
+            assert!(
+                matches!(parse_command(subject), Some(Err(_))),
+                "{:?}",
+                subject
+            );
+        }
+    }
+
+    #[test]
+    fn a_command_over_max_commands_per_hour_is_dropped() {
+        let dir = TempDir::new("command-rate-limit");
+        let mut config = test_config(&dir);
+        config.max_commands_per_hour = Some(3);
+        let start = Utc.ymd(2024, 6, 1).and_hms(12, 0, 0);
+
+        for minutes in 0..3 {
+            let at = start + Duration::minutes(minutes);
+            assert!(allow_command(&config, "me@example.com", at).unwrap());
+        }
+        let fourth = start + Duration::minutes(10);
+        assert!(!allow_command(&config, "Me <ME@example.com>", fourth).unwrap());
+
+        // Someone else has their own count
+        assert!(allow_command(&config, "alice@example.com", fourth).unwrap());
+
+        // Once the first command is an hour old, there's room for one more
+        let later = start + Duration::minutes(60);
+        assert!(allow_command(&config, "me@example.com", later).unwrap());
+        assert!(!allow_command(&config, "me@example.com", later).unwrap());
+    }
+
+    #[test]
+    fn commands_are_unlimited_without_max_commands_per_hour() {
+        let dir = TempDir::new("command-no-rate-limit");
+        let mut config = test_config(&dir);
+        config.max_commands_per_hour = None;
+        let now = Utc.ymd(2024, 6, 1).and_hms(12, 0, 0);
+
+        for _ in 0..50 {
+            assert!(allow_command(&config, "me@example.com", now).unwrap());
+        }
+    }
+}
@@ -0,0 +1,734 @@
+//! The config file, and the settings it holds.
+
+use crate::body::BodyProcessor;
+use crate::mail::is_valid_address;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Weekday;
+use lettre::smtp::authentication::Mechanism;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+pub const CONFIG_PATH: &str = "config.ron";
+
+/// Common English words that would otherwise top every word count.
+const DEFAULT_STOPWORDS: &[&str] = &[
+    "a", "about", "after", "all", "am", "an", "and", "are", "as", "at", "be", "been", "but", "by",
+    "can", "did", "do", "for", "from", "got", "had", "has", "have", "he", "her", "him", "his", "i",
+    "i'm", "if", "in", "is", "it", "it's", "just", "me", "my", "not", "of", "on", "or", "out",
+    "she", "so", "that", "the", "their", "them", "then", "there", "they", "this", "to", "today",
+    "up", "was", "we", "were", "what", "when", "which", "with", "would", "you",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub target_email: String,
+    pub target_name: String,
+    pub db_filename: String,
+    pub db_rollover: DbRollover,
+    pub db_file_mode: Option<u32>,
+    pub storage_backend: StorageBackend,
+    pub journal_email_smtp: String,
+    pub journal_email_imap: String,
+    pub imap_tls_hostname: Option<String>,
+    pub journal_email: String,
+    pub journal_email_password: String,
+    pub smtp_auth_mechanism: SmtpAuthMechanism,
+    pub smtp_envelope_from: Option<String>,
+    pub imap_provider: ImapProvider,
+    pub gmail_search_all_mail: bool,
+    pub archive_folder: Option<String>,
+    pub utc_reminder_hour: i64,
+    pub fetch_batch_size: usize,
+    pub max_email_bytes: Option<u32>,
+    pub max_emails_per_poll: usize,
+    pub full_resync_minutes: i64,
+    pub include_yesterday_in_reminder: bool,
+    pub reminder_html: bool,
+    pub include_weekly_themes_in_reminder: bool,
+    pub weekly_themes_count: usize,
+    pub reminder_prompts: Vec<String>,
+    pub reminder_prompt_order: PromptOrder,
+    pub reminder_footer: Option<String>,
+    pub entry_template: Option<String>,
+    pub require_template_sections: bool,
+    pub reminder_footer_file: Option<String>,
+    pub flashback_label: String,
+    pub date_format: String,
+    pub flashback_order: FlashbackOrder,
+    pub flashback_max_chars: Option<usize>,
+    pub flashback_window_days: i64,
+    pub attachments_dir: Option<String>,
+    pub max_attachment_bytes: usize,
+    pub attachment_retention_days: Option<i64>,
+    pub allowed_attachment_mimes: Vec<String>,
+    pub max_entry_chars: Option<usize>,
+    pub min_words: Option<usize>,
+    pub allow_empty_body: bool,
+    pub empty_body_uses_subject: bool,
+    pub body_processors: Vec<String>,
+    pub quick_entry_words: Vec<String>,
+    pub quick_entry_tag: Option<String>,
+    pub confirm_entries: bool,
+    pub post_store_hook: Option<PostStoreHook>,
+    pub quiet_hours: Option<(u8, u8)>,
+    pub duplicate_policy: DuplicatePolicy,
+    pub throttle_duplicate_errors: bool,
+    pub dedup_similarity_window_minutes: i64,
+    pub append_delimiter: String,
+    pub append_timestamp: bool,
+    pub startup_selftest: bool,
+    pub send_welcome_email: bool,
+    pub reminder_mode: ReminderMode,
+    pub reminder_delivery: ReminderDelivery,
+    pub reminder_pending_grace_minutes: i64,
+    pub reminder_max_defer_hours: i64,
+    pub reminder_grace_minutes: i64,
+    pub journal_display_name: String,
+    pub reminder_skip_weekdays: Vec<Weekday>,
+    pub digest_weekday: Weekday,
+    pub reminder_cc: Vec<String>,
+    pub allowed_sender_domains: Vec<String>,
+    pub detect_bounces: bool,
+    pub backup_notify_email: Option<String>,
+    pub ignore_automatic_emails: bool,
+    pub top_words_count: usize,
+    pub streak_freeze_days: Option<u32>,
+    pub max_export_email_bytes: usize,
+    pub export_dir: String,
+    pub export_bom: bool,
+    pub export_line_ending: LineEnding,
+    pub stopwords: Vec<String>,
+    pub max_commands_per_hour: Option<usize>,
+    pub control_socket: Option<String>,
+    pub lock_file: Option<String>,
+    pub log_file: Option<String>,
+    pub log_max_size: u64,
+    pub log_keep_files: usize,
+    pub mailboxes: Vec<Mailbox>,
+}
+
+/// A journal mailbox of its own, for running several from one process. Everything
+/// not set here is shared, and taken from the rest of the config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mailbox {
+    /// Tags the log lines about this mailbox
+    pub name: String,
+    pub db_filename: String,
+    pub journal_email_smtp: String,
+    pub journal_email_imap: String,
+    pub journal_email: String,
+    pub journal_email_password: String,
+    #[serde(default)]
+    pub target_email: Option<String>,
+    #[serde(default)]
+    pub target_name: Option<String>,
+    #[serde(default)]
+    pub utc_reminder_hour: Option<i64>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            target_email: "john.smith@example.com".to_string(),
+            target_name: "John Smith".to_string(),
+            db_filename: "mail-journal.db".to_string(),
+            db_rollover: DbRollover::Single,
+            db_file_mode: None,
+            storage_backend: StorageBackend::Sqlite,
+            journal_email_smtp: "smtp.example.com".to_string(),
+            journal_email_imap: "imap.example.com".to_string(),
+            imap_tls_hostname: None,
+            journal_email: "mail-journal@example.com".to_string(),
+            journal_email_password: "password".to_string(),
+            smtp_auth_mechanism: SmtpAuthMechanism::Plain,
+            smtp_envelope_from: None,
+            imap_provider: ImapProvider::Auto,
+            gmail_search_all_mail: false,
+            archive_folder: None,
+            utc_reminder_hour: 0,
+            fetch_batch_size: 50,
+            max_email_bytes: None,
+            max_emails_per_poll: 100,
+            full_resync_minutes: 10,
+            include_yesterday_in_reminder: false,
+            reminder_html: false,
+            include_weekly_themes_in_reminder: false,
+            weekly_themes_count: 3,
+            reminder_prompts: Vec::new(),
+            reminder_prompt_order: PromptOrder::RoundRobin,
+            reminder_footer: None,
+            entry_template: None,
+            require_template_sections: false,
+            reminder_footer_file: None,
+            flashback_label: "On {date}, {years_ago} year(s) ago:".to_string(),
+            date_format: "%B %-d, %Y".to_string(),
+            flashback_order: FlashbackOrder::Desc,
+            flashback_max_chars: Some(500),
+            flashback_window_days: 0,
+            attachments_dir: None,
+            max_attachment_bytes: 10 * 1024 * 1024,
+            attachment_retention_days: None,
+            allowed_attachment_mimes: vec!["image/*".to_string(), "application/pdf".to_string()],
+            max_entry_chars: None,
+            min_words: None,
+            allow_empty_body: true,
+            empty_body_uses_subject: false,
+            body_processors: Vec::new(),
+            quick_entry_words: vec!["skip".to_string(), "busy".to_string()],
+            quick_entry_tag: Some("short".to_string()),
+            confirm_entries: false,
+            post_store_hook: None,
+            quiet_hours: None,
+            duplicate_policy: DuplicatePolicy::Reject,
+            throttle_duplicate_errors: true,
+            dedup_similarity_window_minutes: 5,
+            append_delimiter: "\n\n---\n\n".to_string(),
+            append_timestamp: false,
+            startup_selftest: false,
+            send_welcome_email: true,
+            reminder_mode: ReminderMode::Daily,
+            reminder_delivery: ReminderDelivery::AtMostOnce,
+            reminder_pending_grace_minutes: 10,
+            reminder_max_defer_hours: 6,
+            reminder_grace_minutes: 60,
+            journal_display_name: "Mail Journal".to_string(),
+            reminder_skip_weekdays: Vec::new(),
+            digest_weekday: Weekday::Sun,
+            reminder_cc: Vec::new(),
+            allowed_sender_domains: Vec::new(),
+            detect_bounces: false,
+            backup_notify_email: None,
+            ignore_automatic_emails: true,
+            top_words_count: 20,
+            streak_freeze_days: None,
+            max_export_email_bytes: 10 * 1024 * 1024,
+            export_dir: "exports".to_string(),
+            export_bom: false,
+            export_line_ending: LineEnding::Lf,
+            stopwords: DEFAULT_STOPWORDS.iter().map(|w| w.to_string()).collect(),
+            max_commands_per_hour: Some(30),
+            control_socket: None,
+            lock_file: Some("mail-journal.lock".to_string()),
+            log_file: None,
+            log_max_size: 10 * 1024 * 1024,
+            log_keep_files: 5,
+            mailboxes: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The section headers of the `entry_template`: its lines that end with a colon,
+    /// like "Grateful for:".
+    pub fn template_sections(&self) -> Vec<&str> {
+        self.entry_template
+            .iter()
+            .flat_map(|template| template.lines())
+            .map(str::trim)
+            .filter(|line| line.len() > 1 && line.ends_with(':'))
+            .collect()
+    }
+
+    /// Gets the config each mailbox runs with: this one if no `mailboxes` are listed,
+    /// otherwise one per mailbox, with its settings in place of the shared ones.
+    pub fn mailbox_configs(&self) -> Vec<Config> {
+        if self.mailboxes.is_empty() {
+            return vec![self.clone()];
+        }
+
+        self.mailboxes
+            .iter()
+            .map(|mailbox| {
+                let mut config = self.clone();
+                config.mailboxes = Vec::new();
+                config.db_filename = mailbox.db_filename.clone();
+                config.journal_email_smtp = mailbox.journal_email_smtp.clone();
+                config.journal_email_imap = mailbox.journal_email_imap.clone();
+                config.journal_email = mailbox.journal_email.clone();
+                config.journal_email_password = mailbox.journal_email_password.clone();
+                if let Some(target_email) = &mailbox.target_email {
+                    config.target_email = target_email.clone();
+                }
+                if let Some(target_name) = &mailbox.target_name {
+                    config.target_name = target_name.clone();
+                }
+                if let Some(hour) = mailbox.utc_reminder_hour {
+                    config.utc_reminder_hour = hour;
+                }
+                config
+            })
+            .collect()
+    }
+
+    /// Checks the settings that can't be enforced by their types alone.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.utc_reminder_hour < 0 || self.utc_reminder_hour > 23 {
+            return Err(
+                "reminder_hour must be an integer between 0 and 23 (inclusive).".to_string(),
+            );
+        }
+
+        // Any longer and yesterday's reminder could count for today
+        if self.reminder_grace_minutes < 0 || self.reminder_grace_minutes >= 24 * 60 {
+            return Err(
+                "reminder_grace_minutes must be between 0 and 1439 (inclusive).".to_string(),
+            );
+        }
+
+        if let Some(mode) = self.db_file_mode {
+            if mode > 0o777 {
+                return Err(format!(
+                    "db_file_mode must be a file mode between 0 and 511 (0777 in octal), not {}.",
+                    mode
+                ));
+            }
+        }
+
+        if self.db_rollover == DbRollover::Yearly && !self.db_filename.contains("{year}") {
+            return Err("db_filename must contain {year} when db_rollover is Yearly.".to_string());
+        }
+
+        if let StorageBackend::Files { dir } = &self.storage_backend {
+            if dir.trim().is_empty() {
+                return Err("storage_backend Files needs a dir to keep entries in.".to_string());
+            }
+
+            // Every mailbox would be writing its entries into the same files
+            if !self.mailboxes.is_empty() {
+                return Err("storage_backend Files can't be used with mailboxes.".to_string());
+            }
+        }
+
+        BodyProcessor::new(&self.body_processors)?;
+
+        // Any wider and the windows of neighbouring years would overlap
+        if self.flashback_window_days < 0 || self.flashback_window_days > 180 {
+            return Err("flashback_window_days must be between 0 and 180 (inclusive).".to_string());
+        }
+
+        if self.attachment_retention_days.is_some_and(|days| days < 0) {
+            return Err("attachment_retention_days must not be negative.".to_string());
+        }
+
+        if self.full_resync_minutes < 0 {
+            return Err("full_resync_minutes must not be negative.".to_string());
+        }
+
+        if StrftimeItems::new(&self.date_format).any(|item| item == Item::Error) {
+            return Err(format!(
+                "date_format \"{}\" is not a valid date format.",
+                self.date_format
+            ));
+        }
+
+        if self
+            .archive_folder
+            .as_deref()
+            .is_some_and(|folder| folder.trim().is_empty())
+        {
+            return Err("archive_folder must not be empty.".to_string());
+        }
+
+        if self
+            .log_file
+            .as_deref()
+            .is_some_and(|path| path.trim().is_empty())
+        {
+            return Err("log_file must not be empty.".to_string());
+        }
+
+        if self.log_max_size == 0 {
+            return Err("log_max_size must be greater than 0.".to_string());
+        }
+
+        if self.dedup_similarity_window_minutes < 0 {
+            return Err("dedup_similarity_window_minutes must not be negative.".to_string());
+        }
+
+        if self.fetch_batch_size == 0 {
+            return Err("fetch_batch_size must be greater than 0.".to_string());
+        }
+
+        if self.max_email_bytes == Some(0) {
+            return Err("max_email_bytes must be greater than 0.".to_string());
+        }
+
+        match &self.post_store_hook {
+            Some(PostStoreHook::Webhook { url }) => {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(format!(
+                        "post_store_hook url \"{}\" must start with http:// or https://.",
+                        url
+                    ));
+                }
+                if !cfg!(feature = "webhook") {
+                    return Err("post_store_hook Webhook needs Mail Journal to be built with the webhook feature (cargo build --features webhook).".to_string());
+                }
+            }
+            Some(PostStoreHook::Command { command }) if command.trim().is_empty() => {
+                return Err("post_store_hook command must not be empty.".to_string());
+            }
+            _ => {}
+        }
+
+        if let Some((start, end)) = self.quiet_hours {
+            if start > 23 || end > 23 || start == end {
+                return Err(
+                    "quiet_hours must be two different hours, each from 0 to 23.".to_string(),
+                );
+            }
+        }
+
+        if self.reminder_max_defer_hours < 0 {
+            return Err("reminder_max_defer_hours must not be negative.".to_string());
+        }
+
+        if self.streak_freeze_days == Some(0) {
+            return Err("streak_freeze_days must be greater than 0.".to_string());
+        }
+
+        if self.weekly_themes_count == 0 {
+            return Err("weekly_themes_count must be greater than 0.".to_string());
+        }
+
+        if self.max_emails_per_poll == 0 {
+            return Err("max_emails_per_poll must be greater than 0.".to_string());
+        }
+
+        // The display name ends up in the From header
+        if self.journal_display_name.trim().is_empty()
+            || self.journal_display_name.contains(['\r', '\n'])
+        {
+            return Err(
+                "journal_display_name must not be empty or contain line breaks.".to_string(),
+            );
+        }
+
+        if self.require_template_sections && self.template_sections().is_empty() {
+            return Err("require_template_sections needs an entry_template with section headers, lines ending in a colon.".to_string());
+        }
+
+        if self.reminder_footer.is_some() && self.reminder_footer_file.is_some() {
+            return Err(
+                "Only one of reminder_footer and reminder_footer_file can be set.".to_string(),
+            );
+        }
+
+        // Only checked here, since it's read afresh for every reminder
+        if let Some(path) = &self.reminder_footer_file {
+            if !std::path::Path::new(path).is_file() {
+                return Err(format!("reminder_footer_file \"{}\" doesn't exist.", path));
+            }
+        }
+
+        if let Some(address) = self.reminder_cc.iter().find(|a| !is_valid_address(a)) {
+            return Err(format!(
+                "reminder_cc contains an invalid address \"{}\".",
+                address
+            ));
+        }
+
+        // Ends up in the IMAP search, so it has to be a plain domain
+        if let Some(domain) = self.allowed_sender_domains.iter().find(|domain| {
+            domain.is_empty()
+                || domain.starts_with('@')
+                || !domain
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        }) {
+            return Err(format!(
+                "allowed_sender_domains contains \"{}\", which is not a domain like \"example.com\".",
+                domain
+            ));
+        }
+
+        if let Some(hostname) = &self.imap_tls_hostname {
+            let is_hostname = hostname.split('.').all(|label| {
+                !label.is_empty()
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+            if !is_hostname {
+                return Err(format!(
+                    "imap_tls_hostname \"{}\" is not a valid host name.",
+                    hostname
+                ));
+            }
+
+            // Each mailbox has a server of its own
+            if !self.mailboxes.is_empty() {
+                return Err("imap_tls_hostname can't be used with mailboxes.".to_string());
+            }
+        }
+
+        if let Some(address) = &self.smtp_envelope_from {
+            if !is_valid_address(address) {
+                return Err(format!(
+                    "smtp_envelope_from \"{}\" is not a valid address.",
+                    address
+                ));
+            }
+        }
+
+        if let Some(address) = &self.backup_notify_email {
+            if !is_valid_address(address) {
+                return Err(format!(
+                    "backup_notify_email \"{}\" is not a valid address.",
+                    address
+                ));
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut db_filenames = HashSet::new();
+        for (mailbox, config) in self.mailboxes.iter().zip(self.mailbox_configs()) {
+            // "main" would be mistaken for the main thread when tagging log lines
+            if mailbox.name.trim().is_empty()
+                || mailbox.name == "main"
+                || !names.insert(&mailbox.name)
+            {
+                return Err(
+                    "Every mailbox needs a name of its own, other than \"main\".".to_string(),
+                );
+            }
+
+            if !db_filenames.insert(config.db_filename.clone()) {
+                return Err(format!(
+                    "Mailbox \"{}\" needs a db_filename of its own.",
+                    mailbox.name
+                ));
+            }
+
+            config
+                .validate()
+                .map_err(|e| format!("In mailbox \"{}\": {}", mailbox.name, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How journal entries are split between database files.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DbRollover {
+    /// Everything is kept in `db_filename`
+    Single,
+    /// Each year's entries are kept in a file of their own
+    Yearly,
+}
+
+/// Where journal entries are kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StorageBackend {
+    /// In the database, along with everything else
+    Sqlite,
+    /// In a Markdown file per day, at `dir/YYYY/MM/DD.md`
+    Files { dir: String },
+}
+
+/// Another program to tell about each entry that's saved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PostStoreHook {
+    /// POST the entry as JSON to this URL. Needs the `webhook` feature.
+    Webhook { url: String },
+    /// Run this shell command, with `{date}` and `{path}` filled in and the entry
+    /// as JSON on its stdin
+    Command { command: String },
+}
+
+/// How to log in to the SMTP server. The connection is always encrypted with
+/// STARTTLS before logging in, so none of these send the password in the clear.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SmtpAuthMechanism {
+    Plain,
+    Login,
+    CramMd5,
+}
+
+impl SmtpAuthMechanism {
+    pub fn mechanism(self) -> Mechanism {
+        match self {
+            SmtpAuthMechanism::Plain => Mechanism::Plain,
+            SmtpAuthMechanism::Login => Mechanism::Login,
+            SmtpAuthMechanism::CramMd5 => Mechanism::CramMd5,
+        }
+    }
+}
+
+/// Which IMAP server quirks to expect.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ImapProvider {
+    /// Go by the `journal_email_imap` host name
+    Auto,
+    /// A standard IMAP server
+    Generic,
+    /// Gmail, which exposes labels as mailboxes
+    Gmail,
+}
+
+/// Whether the daily reminder is sent at all.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ReminderMode {
+    /// Every day at `utc_reminder_hour`, unless it's skipped or paused
+    Daily,
+    /// No daily reminder, only a digest of the week's entries every
+    /// `digest_weekday`, at `utc_reminder_hour`
+    DigestOnly,
+    /// Never. Entries and commands sent in are still handled as usual.
+    Off,
+}
+
+/// How to treat a reminder that was in the middle of being sent when Mail Journal stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ReminderDelivery {
+    /// Never resend it. The reminder is never duplicated, but may occasionally be lost.
+    AtMostOnce,
+    /// Resend it, unless the send was attempted within the grace window. The reminder
+    /// is never lost, but may occasionally be duplicated.
+    AtLeastOnce,
+}
+
+/// What to do when a journal entry arrives for a day that already has one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DuplicatePolicy {
+    /// Keep the first entry, and reply with an error
+    Reject,
+    /// Add the new entry to the end of the existing one
+    Append,
+}
+
+/// How the prompt that opens each reminder is picked from `reminder_prompts`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PromptOrder {
+    /// Each in turn, one per day
+    RoundRobin,
+    /// Any of them, picked afresh for each reminder
+    Random,
+}
+
+/// The line endings exported files are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+/// The order in which flashback entries from previous years are listed in the reminder.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FlashbackOrder {
+    Asc,
+    Desc,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_default_config_is_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn settings_left_out_of_the_file_take_their_defaults() {
+        let config: Config =
+            ron::de::from_str(r#"(target_email: "me@example.com", utc_reminder_hour: 7)"#).unwrap();
+
+        assert_eq!(config.target_email, "me@example.com");
+        assert_eq!(config.utc_reminder_hour, 7);
+        assert_eq!(config.fetch_batch_size, Config::default().fetch_batch_size);
+        assert_eq!(config.reminder_mode, ReminderMode::Daily);
+    }
+
+    #[test]
+    fn the_default_config_survives_being_written_out() {
+        let written = ron::ser::to_string(&Config::default()).unwrap();
+        let config: Config = ron::de::from_str(&written).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn each_smtp_auth_mechanism_maps_to_its_lettre_mechanism() {
+        assert_eq!(SmtpAuthMechanism::Plain.mechanism(), Mechanism::Plain);
+        assert_eq!(SmtpAuthMechanism::Login.mechanism(), Mechanism::Login);
+        assert_eq!(SmtpAuthMechanism::CramMd5.mechanism(), Mechanism::CramMd5);
+    }
+
+    #[test]
+    fn smtp_auth_mechanism_is_read_by_name() {
+        let config: Config = ron::de::from_str(r#"(smtp_auth_mechanism: Login)"#).unwrap();
+        assert_eq!(config.smtp_auth_mechanism, SmtpAuthMechanism::Login);
+        assert_eq!(
+            Config::default().smtp_auth_mechanism.mechanism(),
+            Mechanism::Plain
+        );
+    }
+
+    #[test]
+    fn validate_rejects_a_reminder_hour_out_of_range() {
+        for hour in &[-1, 24] {
+            let config = Config {
+                utc_reminder_hour: *hour,
+                ..Config::default()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn validate_wants_the_year_in_yearly_db_filenames() {
+        let config = Config {
+            db_rollover: DbRollover::Yearly,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            db_filename: "journal-{year}.db".to_string(),
+            ..config
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn template_sections_are_the_lines_ending_in_a_colon() {
+        let config = Config {
+            entry_template: Some("Grateful for:\n(a few things)\n  Tomorrow:  \n:\n".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.template_sections(),
+            vec!["Grateful for:", "Tomorrow:"]
+        );
+    }
+
+    #[test]
+    fn each_mailbox_gets_its_settings_over_the_shared_ones() {
+        let mailbox = |name: &str, hour: Option<i64>| Mailbox {
+            name: name.to_string(),
+            db_filename: format!("{}.db", name),
+            journal_email_smtp: "smtp.example.com".to_string(),
+            journal_email_imap: "imap.example.com".to_string(),
+            journal_email: format!("{}@example.com", name),
+            journal_email_password: "password".to_string(),
+            target_email: None,
+            target_name: None,
+            utc_reminder_hour: hour,
+        };
+        let config = Config {
+            utc_reminder_hour: 9,
+            mailboxes: vec![mailbox("work", Some(17)), mailbox("home", None)],
+            ..Config::default()
+        };
+
+        let configs = config.mailbox_configs();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].journal_email, "work@example.com");
+        assert_eq!(configs[0].utc_reminder_hour, 17);
+        assert_eq!(configs[1].db_filename, "home.db");
+        assert_eq!(configs[1].utc_reminder_hour, 9);
+        assert!(configs.iter().all(|config| config.mailboxes.is_empty()));
+    }
+}
@@ -0,0 +1,239 @@
+//! The control socket, which answers questions about the journal for local scripts.
+//!
+//! Clients connect to the Unix socket at `control_socket` and send one command per
+//! line. Each command is answered with a single line of JSON.
+
+use crate::config::Config;
+use crate::store::{db_path, open_storage_with_history};
+use chrono::prelude::*;
+use serde_json::{json, Value};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The longest command line a client can send. Commands are short, so anything
+/// longer is a client gone wrong, and it's disconnected.
+const MAX_LINE_BYTES: usize = 1024;
+
+/// The most clients answered at once. Any more are turned away until one leaves.
+const MAX_CLIENTS: usize = 8;
+
+/// Starts answering commands on the control socket, on a thread of its own.
+/// Returns an error if the socket can't be set up.
+pub fn spawn(config: &Config, path: &str) -> Result<(), String> {
+    // A socket left behind by an earlier run would stop us from binding
+    if std::fs::metadata(path).is_ok() {
+        std::fs::remove_file(path)
+            .map_err(|e| format!("Failed to remove old control socket {}: {}", path, e))?;
+    }
+
+    // Entries can be read through the socket, so only we get to connect. The umask
+    // keeps anyone else out from the moment it's created, before the chmod below.
+    let old_umask = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old_umask) };
+    let listener =
+        listener.map_err(|e| format!("Failed to create control socket {}: {}", path, e))?;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to restrict control socket {}: {}", path, e))?;
+
+    let config = config.clone();
+    let clients = Arc::new(AtomicUsize::new(0));
+    std::thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log_error!("Failed to accept control connection: {}", e);
+                        continue;
+                    }
+                };
+
+                if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    let _ = writeln!(stream, "{}", json!({ "error": "Too many connections" }));
+                    continue;
+                }
+
+                let config = config.clone();
+                let clients = Arc::clone(&clients);
+                std::thread::spawn(move || {
+                    handle_client(&config, stream);
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        })
+        .map_err(|e| format!("Failed to start control socket thread: {}", e))?;
+
+    log!("Listening for commands on {}", path);
+    Ok(())
+}
+
+/// Answers a client's commands until it disconnects.
+fn handle_client(config: &Config, stream: UnixStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            log_error!("Failed to set up control connection: {}", e);
+            return;
+        }
+    };
+
+    let mut reader = BufReader::new(stream);
+    loop {
+        // One byte over the limit is enough to tell that a line is too long
+        let mut line = String::new();
+        match reader
+            .by_ref()
+            .take(MAX_LINE_BYTES as u64 + 1)
+            .read_line(&mut line)
+        {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+
+        if line.len() > MAX_LINE_BYTES {
+            let error = format!("Commands can be at most {} bytes", MAX_LINE_BYTES);
+            let _ = writeln!(writer, "{}", json!({ "error": error }));
+            return;
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = run_command(config, &line, Utc::now());
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs a single control command received at `now`, returning its JSON response.
+pub fn run_command(config: &Config, line: &str, now: DateTime<Utc>) -> Value {
+    let today = now.naive_utc().date();
+    let command = line.trim().to_uppercase();
+    let storage = match open_storage_with_history(config, today) {
+        Ok(storage) => storage,
+        Err(e) => return json!({ "error": format!("Failed to open database: {}", e) }),
+    };
+
+    let result = match command.as_str() {
+        "COUNT" => storage
+            .fetch_all()
+            .map(|entries| json!({ "count": entries.len() })),
+        "LAST" => storage.fetch_all().map(|entries| match entries.last() {
+            Some(entry) => json!({
+                "date": entry.date.to_string(),
+                "words": entry.body.split_whitespace().count(),
+                "body": entry.body,
+            }),
+            None => json!({ "date": null }),
+        }),
+        "STATS" => storage.fetch_all().map(|entries| {
+            json!({
+                "db_path": db_path(config, today.year()),
+                "count": entries.len(),
+                "favorites": entries.iter().filter(|e| e.is_favorite).count(),
+                "words": entries
+                    .iter()
+                    .map(|e| e.body.split_whitespace().count())
+                    .sum::<usize>(),
+                "first_date": entries.first().map(|e| e.date.to_string()),
+                "last_date": entries.last().map(|e| e.date.to_string()),
+            })
+        }),
+        _ => {
+            return json!({
+                "error": format!("Unknown command \"{}\". Try STATS, COUNT or LAST.", line.trim())
+            })
+        }
+    };
+
+    result.unwrap_or_else(|e| json!({ "error": format!("Database error: {}", e) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::store::open_current_db;
+    use crate::test_util::{test_config, TempDir};
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2024, 6, 1).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn count_answers_with_the_number_of_entries() {
+        let dir = TempDir::new("control_count");
+        let config = test_config(&dir);
+        assert_eq!(run_command(&config, "COUNT", now()), json!({ "count": 0 }));
+
+        let today = now().naive_utc().date();
+        let sql_conn = open_current_db(&config, today).unwrap();
+        let storage: &dyn Storage = &sql_conn;
+        storage.insert_entry(today.pred(), "First").unwrap();
+        storage.insert_entry(today, "Second").unwrap();
+
+        assert_eq!(
+            run_command(&config, " count\n", now()),
+            json!({ "count": 2 })
+        );
+    }
+
+    #[test]
+    fn an_unknown_command_is_an_error() {
+        let dir = TempDir::new("control_unknown");
+        let response = run_command(&test_config(&dir), "DROP TABLE", now());
+        assert!(response["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Unknown command \"DROP TABLE\""));
+    }
+
+    fn answers(config: &Config, sent: &[u8]) -> Vec<Value> {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(sent).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        handle_client(config, server);
+
+        BufReader::new(client)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn a_client_gets_an_answer_per_line() {
+        let dir = TempDir::new("control_client");
+        let config = test_config(&dir);
+        assert_eq!(
+            answers(&config, b"COUNT\n\nCOUNT"),
+            vec![json!({ "count": 0 }), json!({ "count": 0 })]
+        );
+    }
+
+    #[test]
+    fn a_client_sending_too_long_a_line_is_disconnected() {
+        let dir = TempDir::new("control_long_line");
+        let config = test_config(&dir);
+        let mut sent = b"COUNT\n".to_vec();
+        sent.extend(vec![b'A'; MAX_LINE_BYTES * 4]);
+        sent.extend_from_slice(b"\nCOUNT\n");
+
+        let answers = answers(&config, &sent);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0], json!({ "count": 0 }));
+        assert_eq!(
+            answers[1]["error"],
+            format!("Commands can be at most {} bytes", MAX_LINE_BYTES)
+        );
+    }
+}
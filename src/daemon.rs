@@ -0,0 +1,439 @@
+//! The daemon loop for one mailbox, one pass at a time: checking for new journal
+//! emails, sending queued replies and the reminder, and the daily housekeeping.
+
+use crate::config::{Config, ReminderMode};
+use crate::error::{self, Error};
+use crate::inbox::handle_email;
+use crate::mail::{archive_emails, Email, ImapError, SearchProgress};
+use crate::schedule::{
+    defer_reminder, last_reminder_time, next_reminder_time, retry_deferred_reminder,
+    send_reminder_email, set_reminder_state, skip_reminder,
+};
+use crate::store::{prune_attachments, send_queued_replies};
+use chrono::prelude::*;
+use chrono::Duration;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+
+/// After the IMAP server fails us, the wait before trying it again starts here and
+/// doubles with each failure in a row, up to the maximum.
+const IMAP_RETRY_FIRST_SECONDS: i64 = 5;
+const IMAP_RETRY_MAX_SECONDS: i64 = 300;
+
+/// How long to wait before trying the IMAP server again, after it has failed us
+/// `failures` times in a row.
+fn imap_retry_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    Duration::seconds((IMAP_RETRY_FIRST_SECONDS << doublings).min(IMAP_RETRY_MAX_SECONDS))
+}
+
+/// What the daemon remembers from one pass to the next.
+pub struct Daemon {
+    search_progress: SearchProgress,
+    last_pruned: Option<NaiveDate>,
+    /// How many times in a row the IMAP server has failed us, and when to try again
+    imap_failures: u32,
+    next_imap_check: Option<DateTime<Utc>>,
+    /// When a reminder was last taken care of, either by sending or skipping it
+    last_reminded: Option<DateTime<Utc>>,
+}
+
+impl Daemon {
+    /// Picks up where the last run left off, as of `now`, and logs when the next
+    /// reminder is due.
+    pub fn start(config: &Config, now: DateTime<Utc>) -> error::Result<Daemon> {
+        let today = now.naive_utc().date();
+        let grace = Duration::minutes(config.reminder_grace_minutes);
+        let last_reminded = last_reminder_time(config, now)?;
+
+        let decision = next_reminder_time(now, config.utc_reminder_hour, grace, last_reminded);
+        if config.reminder_mode == ReminderMode::Off {
+            log!("Journal reminders are off, none will be sent.");
+        } else if config.reminder_mode == ReminderMode::DigestOnly {
+            log!(
+                "Only the weekly digest is sent, every {:?} at {}:00 UTC.",
+                config.digest_weekday,
+                config.utc_reminder_hour
+            );
+        } else if decision.send_for.is_some() {
+            log!("Journal reminder for today hasn't been sent yet, sending it now.");
+        } else if decision.next.naive_utc().date() == today {
+            log!(
+                "Journal reminder for today is scheduled at {}",
+                decision.next
+            );
+        } else {
+            log!(
+                "Journal reminder for today has been sent. Next reminder scheduled for {}",
+                decision.next
+            );
+        }
+
+        Ok(Daemon {
+            search_progress: SearchProgress::default(),
+            last_pruned: None,
+            imap_failures: 0,
+            next_imap_check: None,
+            last_reminded,
+        })
+    }
+
+    /// Does one pass of the daemon loop, as of `now`. Only errors that mean the
+    /// daemon has to stop are returned, and anything that may well fix itself, like
+    /// the IMAP server being down, is logged and tried again on a later pass.
+    pub fn process_once(&mut self, config: &Config, now: DateTime<Utc>) -> error::Result<()> {
+        // Check for new journal emails, unless we're waiting to try again
+        let check_imap = self.next_imap_check.is_none_or(|next| now >= next);
+        let received = if check_imap {
+            Some(receive_emails(config, &mut self.search_progress, now))
+        } else {
+            None
+        };
+
+        if let Some(Err(_)) = &received {
+            self.imap_failures += 1;
+            self.next_imap_check = Some(now + imap_retry_delay(self.imap_failures));
+        }
+
+        match received {
+            None => {}
+            Some(Ok(emails)) => {
+                self.imap_failures = 0;
+                self.next_imap_check = None;
+
+                // The rest of the batch is already marked as seen, so one email that
+                // can't be handled mustn't stop the others from being handled. Only
+                // emails saved as entries or run as commands are archived, and the rest
+                // are left in the inbox to be looked at.
+                let mut handled = Vec::new();
+                for email in emails {
+                    match handle_email(config, &email, now) {
+                        Ok(true) => handled.push(email),
+                        Ok(false) => {}
+                        Err(e @ Error::Config(_)) => return Err(e),
+                        Err(e) => log_error!("Failed to handle email from {}: {}", email.from, e),
+                    }
+                }
+
+                // Handled emails are still in the inbox if this fails, which is no
+                // reason to stop
+                if let Err(e) = archive_emails(config, &handled) {
+                    log_error!("Failed to move emails to the archive folder: {}", e);
+                }
+            }
+            // Trying a wrong password again won't make it right, and could get the
+            // account locked
+            Some(Err(e @ ImapError::Login(_))) => return Err(e.into()),
+            Some(Err(e)) => log_error!(
+                "Failed to check for new emails, will try again in {} seconds: {}",
+                imap_retry_delay(self.imap_failures).num_seconds(),
+                e
+            ),
+        }
+
+        send_queued_replies(config, now)?;
+
+        // Once a day, clear out attachments past their retention
+        let today = now.naive_utc().date();
+        if self.last_pruned != Some(today) {
+            prune_attachments(config, today)?;
+            self.last_pruned = Some(today);
+        }
+
+        self.handle_reminder(config, now)?;
+        retry_deferred_reminder(config, now)
+    }
+
+    /// Sends the reminder, if one is due at `now` and isn't skipped.
+    fn handle_reminder(&mut self, config: &Config, now: DateTime<Utc>) -> error::Result<()> {
+        let grace = Duration::minutes(config.reminder_grace_minutes);
+        let decision = next_reminder_time(now, config.utc_reminder_hour, grace, self.last_reminded);
+        let reminder_date = match decision.send_for {
+            Some(reminder_date) => reminder_date,
+            None => return Ok(()),
+        };
+        self.last_reminded = Some(now);
+
+        if skip_reminder(config, reminder_date)? {
+            log!(
+                "Skipped the journal reminder for {}. Next reminder scheduled for {}",
+                reminder_date,
+                decision.next
+            );
+            return Ok(());
+        }
+
+        // Record the attempt before sending, so that if we die mid-send, a restart
+        // knows not to blindly send the reminder again
+        set_reminder_state(config, reminder_date, "pending", now)?;
+        match send_reminder_email(config, now) {
+            Ok(()) => {
+                set_reminder_state(config, reminder_date, "sent", now)?;
+
+                log!(
+                    "Journal reminder for {} sent. Next reminder scheduled for {}",
+                    now,
+                    decision.next
+                );
+            }
+            // Tried again later, or without reminder_max_defer_hours, left pending,
+            // which reminder_delivery decides what to do about
+            Err(Error::Smtp(e)) => {
+                log_error!(
+                    "Failed to send the journal reminder for {}: {}",
+                    reminder_date,
+                    e
+                );
+                if config.reminder_max_defer_hours > 0 {
+                    defer_reminder(config, reminder_date, now)?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+}
+
+/// Takes the lock on `lock_file`, or fails if another Mail Journal holds it. It's
+/// an flock, so the lock goes when our process does, however it ends, and isn't
+/// left behind by a crash.
+pub fn lock_instance(path: &str) -> error::Result<std::fs::File> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    // Safe, since flock only looks at the descriptor, which we keep open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::WouldBlock {
+            return Err(error.into());
+        }
+
+        let mut pid = String::new();
+        let _ = file.read_to_string(&mut pid);
+        return Err(Error::Config(format!(
+            "Another Mail Journal is already running (process {}), since it holds the lock on {}.",
+            pid.trim(),
+            path
+        )));
+    }
+
+    // Only once it's ours, so the process ID in it is always the holder's
+    file.set_len(0)?;
+    file.write_all(std::process::id().to_string().as_bytes())?;
+
+    Ok(file)
+}
+
+/// Fetches any new journal emails from the IMAP server.
+#[cfg(not(test))]
+fn receive_emails(
+    config: &Config,
+    progress: &mut SearchProgress,
+    now: DateTime<Utc>,
+) -> Result<Vec<Email>, ImapError> {
+    use crate::mail::{fetch_emails, search_inbox_latest};
+
+    let new_mail = search_inbox_latest(config, progress, now)?;
+    if new_mail.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let count: usize = new_mail.iter().map(|(_, uids)| uids.len()).sum();
+    log!("{} new email(s)", count);
+
+    // The search has already moved past these emails, so if fetching them fails,
+    // search everything again next time rather than wait for the next full search
+    let emails = fetch_emails(config, new_mail);
+    if emails.is_err() {
+        progress.reset();
+    }
+
+    emails
+}
+
+/// In tests, the new emails are the ones the test put in its inbox.
+#[cfg(test)]
+fn receive_emails(
+    _config: &Config,
+    _progress: &mut SearchProgress,
+    _now: DateTime<Utc>,
+) -> Result<Vec<Email>, ImapError> {
+    Ok(crate::test_util::take_inbox())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::open_storage_with_history;
+    use crate::test_util::{receive_email, sent_emails, test_config, TempDir};
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2024, 6, day).and_hms(hour, minute, 0)
+    }
+
+    /// A journal with its reminder at 9:00, and the daemon started on it at `now`.
+    fn start(dir: &TempDir, now: DateTime<Utc>) -> (Config, Daemon) {
+        let config = Config {
+            utc_reminder_hour: 9,
+            ..test_config(dir)
+        };
+        let daemon = Daemon::start(&config, now).unwrap();
+        (config, daemon)
+    }
+
+    fn receive(subject: &str, body: &str, sent: DateTime<Utc>) {
+        receive_email(&format!(
+            "From: me@example.com\r\nTo: journal@example.com\r\nSubject: {}\r\nDate: {}\r\n\r\n{}\r\n",
+            subject,
+            sent.to_rfc2822(),
+            body
+        ));
+    }
+
+    fn bodies(config: &Config, now: DateTime<Utc>) -> Vec<(NaiveDate, String)> {
+        open_storage_with_history(config, now.naive_utc().date())
+            .unwrap()
+            .fetch_all()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.date, entry.body.trim().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn a_new_email_is_stored() {
+        let dir = TempDir::new("daemon_stored");
+        let (config, mut daemon) = start(&dir, at(1, 8, 0));
+
+        receive(
+            "Re: Daily Journal Entry",
+            "Went for a long walk.",
+            at(1, 8, 0),
+        );
+        daemon.process_once(&config, at(1, 8, 1)).unwrap();
+
+        assert_eq!(
+            bodies(&config, at(1, 8, 1)),
+            vec![(
+                at(1, 8, 0).naive_utc().date(),
+                "Went for a long walk.".to_string()
+            )]
+        );
+        assert!(sent_emails().is_empty());
+    }
+
+    #[test]
+    fn a_second_entry_for_the_day_is_rejected() {
+        let dir = TempDir::new("daemon_duplicate");
+        let (config, mut daemon) = start(&dir, at(1, 7, 0));
+
+        receive("", "Morning entry", at(1, 7, 0));
+        daemon.process_once(&config, at(1, 7, 1)).unwrap();
+        receive("", "Another go at it", at(1, 7, 30));
+        daemon.process_once(&config, at(1, 7, 31)).unwrap();
+
+        assert_eq!(bodies(&config, at(1, 7, 31)).len(), 1);
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), "Error");
+        assert!(sent[0].body().contains("already submitted"));
+    }
+
+    #[test]
+    fn the_reminder_is_sent_once_at_the_hour() {
+        let dir = TempDir::new("daemon_reminder");
+        let (config, mut daemon) = start(&dir, at(1, 8, 0));
+
+        daemon.process_once(&config, at(1, 8, 59)).unwrap();
+        assert!(sent_emails().is_empty());
+
+        daemon.process_once(&config, at(1, 9, 0)).unwrap();
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), "Daily Journal Entry");
+        assert_eq!(sent[0].to, vec!["me@example.com".to_string()]);
+
+        daemon.process_once(&config, at(1, 9, 1)).unwrap();
+        daemon.process_once(&config, at(1, 23, 59)).unwrap();
+        assert!(sent_emails().is_empty());
+
+        // And after a restart, it's still known to have been sent
+        let (config, mut daemon) = start(&dir, at(1, 10, 0));
+        daemon.process_once(&config, at(1, 10, 0)).unwrap();
+        assert!(sent_emails().is_empty());
+
+        daemon.process_once(&config, at(2, 9, 0)).unwrap();
+        assert_eq!(sent_emails().len(), 1);
+    }
+
+    #[test]
+    fn the_reminder_includes_a_flashback_to_a_year_ago() {
+        let dir = TempDir::new("daemon_flashback");
+        let (config, mut daemon) = start(&dir, at(1, 8, 0));
+
+        let a_year_ago = Utc.ymd(2023, 6, 1).and_hms(20, 0, 0);
+        receive("", "Moved into the new flat.", a_year_ago);
+        daemon.process_once(&config, at(1, 8, 0)).unwrap();
+        daemon.process_once(&config, at(1, 9, 0)).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]
+            .body()
+            .contains("On June 1, 2023, 1 year(s) ago:\n\"Moved into the new flat.\""));
+    }
+
+    #[test]
+    fn a_command_is_handled_and_not_stored() {
+        let dir = TempDir::new("daemon_command");
+        let (config, mut daemon) = start(&dir, at(1, 8, 0));
+
+        receive("", "Yesterday's entry", at(1, 8, 0) - Duration::days(1));
+        daemon.process_once(&config, at(1, 8, 0)).unwrap();
+        receive("COUNT", "", at(1, 8, 5));
+        daemon.process_once(&config, at(1, 8, 6)).unwrap();
+
+        assert_eq!(bodies(&config, at(1, 8, 6)).len(), 1);
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), "Entry count");
+    }
+
+    #[test]
+    fn only_one_mail_journal_can_hold_the_lock() {
+        let dir = TempDir::new("lock_instance");
+        let path = dir.file("mail-journal.lock");
+
+        let lock = lock_instance(&path).unwrap();
+        let pid = std::process::id().to_string();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pid);
+
+        match lock_instance(&path) {
+            Err(Error::Config(e)) => assert_eq!(
+                e,
+                format!(
+                    "Another Mail Journal is already running (process {}), since it holds the lock on {}.",
+                    pid, path
+                )
+            ),
+            _ => panic!("the lock was taken twice"),
+        }
+
+        // Once the holder is gone, the lock is free again
+        drop(lock);
+        assert!(lock_instance(&path).is_ok());
+    }
+
+    #[test]
+    fn the_imap_retry_delay_doubles_up_to_the_maximum() {
+        let delays: Vec<i64> = (1..=8).map(|n| imap_retry_delay(n).num_seconds()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 80, 160, 300, 300]);
+    }
+}
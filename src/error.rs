@@ -0,0 +1,133 @@
+//! The errors that can stop Mail Journal from doing what it was asked.
+
+use crate::mail::ImapError;
+
+/// Why something went wrong, grouped by where it went wrong.
+#[derive(Debug)]
+pub enum Error {
+    /// The config file or the command line needs fixing
+    Config(String),
+    /// The database couldn't be read or written
+    Db(rusqlite::Error),
+    /// The IMAP server couldn't be reached, or wouldn't do what we asked
+    Imap(ImapError),
+    /// An email couldn't be put together or sent
+    Smtp(String),
+    /// An email or an import couldn't be made sense of
+    Parse(String),
+    /// A file, like an entry or an attachment, couldn't be read or written
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Each reason Mail Journal stops exits with its own code (listed in the
+    /// README), so whatever runs Mail Journal can tell whether restarting it will
+    /// help.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) | Error::Parse(_) => 1,
+            Error::Imap(ImapError::Login(_)) => 2,
+            Error::Db(_) | Error::Io(_) => 3,
+            Error::Imap(ImapError::Other(_)) | Error::Smtp(_) => 4,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Db(e)
+    }
+}
+
+impl From<ImapError> for Error {
+    fn from(e: ImapError) -> Error {
+        Error::Imap(e)
+    }
+}
+
+impl From<lettre::Error> for Error {
+    fn from(e: lettre::Error) -> Error {
+        Error::Smtp(e.to_string())
+    }
+}
+
+impl From<lettre::smtp::error::Error> for Error {
+    fn from(e: lettre::smtp::error::Error) -> Error {
+        Error::Smtp(e.to_string())
+    }
+}
+
+impl From<lettre_email::error::Error> for Error {
+    fn from(e: lettre_email::error::Error) -> Error {
+        Error::Smtp(e.to_string())
+    }
+}
+
+impl From<mailparse::MailParseError> for Error {
+    fn from(e: mailparse::MailParseError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "{}", e),
+            Error::Db(e) => write!(f, "Database error! {}", e),
+            Error::Imap(e) => write!(f, "IMAP error! {}", e),
+            Error::Smtp(e) => write!(f, "Failed to send email: {}", e),
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "File error! {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_config_error_exits_with_1() {
+        let error = crate::config::Config {
+            weekly_themes_count: 0,
+            ..Default::default()
+        }
+        .validate()
+        .map_err(Error::Config)
+        .unwrap_err();
+        assert_eq!(error.exit_code(), 1);
+        assert_eq!(Error::Parse("bad import".to_string()).exit_code(), 1);
+    }
+
+    #[test]
+    fn only_a_rejected_login_exits_with_2() {
+        let rejected = imap::error::Error::No("bad password".to_string());
+        assert_eq!(Error::Imap(ImapError::Login(rejected)).exit_code(), 2);
+
+        let dropped = imap::error::Error::Io(std::io::ErrorKind::ConnectionReset.into());
+        assert_eq!(Error::Imap(ImapError::Other(dropped)).exit_code(), 4);
+        assert_eq!(Error::Smtp("connection refused".to_string()).exit_code(), 4);
+    }
+
+    #[test]
+    fn a_database_or_file_error_exits_with_3() {
+        assert_eq!(
+            Error::Db(rusqlite::Error::QueryReturnedNoRows).exit_code(),
+            3
+        );
+        assert_eq!(
+            Error::Io(std::io::ErrorKind::NotFound.into()).exit_code(),
+            3
+        );
+    }
+}
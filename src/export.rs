@@ -0,0 +1,655 @@
+//! Exporting the journal to other formats, and importing it from them.
+
+use crate::archive::ZipWriter;
+use crate::command::parse_date;
+use crate::config::{Config, DbRollover, DuplicatePolicy, LineEnding};
+use crate::error;
+use crate::journal::Entry;
+use crate::storage::Storage;
+use crate::store::open_db;
+use chrono::prelude::*;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Turns exported text into the bytes of the file, with the `export_line_ending`,
+/// and a byte order mark first with `export_bom`, for the editors that need them.
+pub fn encode_export(config: &Config, text: &str) -> Vec<u8> {
+    // Entries keep whatever line endings they were sent with, so they're evened
+    // out first
+    let text = text.replace("\r\n", "\n");
+    let text = match config.export_line_ending {
+        LineEnding::Lf => text,
+        LineEnding::Crlf => text.replace('\n', "\r\n"),
+    };
+
+    let mut bytes = Vec::with_capacity(text.len() + 3);
+    if config.export_bom {
+        bytes.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+    bytes.extend_from_slice(text.as_bytes());
+
+    bytes
+}
+
+/// Exports the number of words written on each day, from the first entry through
+/// `today`, as CSV. Days without an entry are included with a count of zero, so
+/// gaps in the journal show up in the trend.
+pub fn export_word_trend(storage: &dyn Storage, today: NaiveDate) -> error::Result<String> {
+    let mut csv = String::from("date,words\n");
+
+    if let Some(first) = storage.fetch_first_date()? {
+        let entries = storage.fetch_between(first, today)?;
+        for (date, words) in word_trend(&entries, first, today) {
+            csv.push_str(&format!("{},{}\n", date, words));
+        }
+    }
+
+    Ok(csv)
+}
+
+/// Counts the words written on each day between `start` and `end` (inclusive),
+/// filling in zero for days without an entry.
+fn word_trend(entries: &[Entry], start: NaiveDate, end: NaiveDate) -> Vec<(NaiveDate, usize)> {
+    let mut trend = Vec::new();
+
+    let mut date = start;
+    while date <= end {
+        let words = entries
+            .iter()
+            .filter(|e| e.date == date)
+            .map(|e| word_count(&e.body))
+            .sum();
+
+        trend.push((date, words));
+        date = date.succ();
+    }
+
+    trend
+}
+
+fn word_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// Exports the `count` most used words across all entries, leaving out the
+/// configured `stopwords`, as a ranked list.
+pub fn export_top_words(
+    config: &Config,
+    storage: &dyn Storage,
+    count: usize,
+) -> error::Result<String> {
+    let entries = storage.fetch_all()?;
+    let frequencies = word_frequencies(&entries, &config.stopwords);
+    if frequencies.is_empty() {
+        return Ok("There are no words to count yet.\n".to_string());
+    }
+
+    Ok(frequencies
+        .iter()
+        .take(count)
+        .enumerate()
+        .map(|(i, (word, uses))| format!("{}. {} ({})\n", i + 1, word, uses))
+        .collect())
+}
+
+/// Exports the whole journal as a zip file: each entry as `entries/YYYY-MM-DD.md`,
+/// the files saved from its attachments under `attachments/YYYY-MM-DD/`, and
+/// `metadata.json`, listing every entry along with its tags and whether it's starred.
+/// The text files are written with `encode_export`, and `metadata.json` is dated `today`.
+pub fn export_archive(
+    config: &Config,
+    storage: &dyn Storage,
+    today: NaiveDate,
+) -> error::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new();
+    let mut metadata = Vec::new();
+
+    for entry in storage.fetch_all()? {
+        let entry_path = format!("entries/{}.md", entry.date);
+        zip.add_file(&entry_path, &encode_export(config, &entry.body), entry.date)?;
+
+        // Attachments deleted since, like by `attachment_retention_days`, are left out
+        let mut attachment_paths = Vec::new();
+        for saved in saved_attachments(&entry.body) {
+            let contents = match std::fs::read(&saved) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    log!("Leaving {} out of the export: {}", saved.display(), e);
+                    continue;
+                }
+            };
+
+            let name = saved.file_name().unwrap_or_default().to_string_lossy();
+            let path = format!("attachments/{}/{}", entry.date, name);
+            zip.add_file(&path, &contents, entry.date)?;
+            attachment_paths.push(path);
+        }
+
+        metadata.push(json!({
+            "date": entry.date.to_string(),
+            "file": entry_path,
+            "favorite": entry.is_favorite,
+            "tags": storage.fetch_tags(entry.id)?,
+            "words": word_count(&entry.body),
+            "attachments": attachment_paths,
+        }));
+    }
+
+    let metadata = serde_json::to_string_pretty(&json!({ "entries": metadata }))
+        .map_err(std::io::Error::from)?;
+    zip.add_file("metadata.json", &encode_export(config, &metadata), today)?;
+
+    Ok(zip.finish())
+}
+
+/// Finds the files saved from an entry's attachments, going by the notes that
+/// storing the entry left in it, like `[attachment 'a.jpg' saved as dir/a.jpg]`.
+fn saved_attachments(body: &str) -> Vec<PathBuf> {
+    body.lines()
+        .filter_map(|line| line.trim().strip_prefix("[attachment '")?.strip_suffix(']'))
+        .filter_map(|note| note.rsplit_once("' saved as "))
+        .map(|(_, path)| Path::new(path).to_path_buf())
+        .collect()
+}
+
+/// Counts how often each word is used across the entries, most used first (and
+/// alphabetically among words used equally often). Words are compared in lowercase,
+/// without the punctuation around them, and `stopwords` and numbers aren't counted.
+pub fn word_frequencies(entries: &[Entry], stopwords: &[String]) -> Vec<(String, usize)> {
+    let stopwords: HashSet<String> = stopwords.iter().map(|w| w.trim().to_lowercase()).collect();
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for entry in entries {
+        // Apostrophes are kept inside words, so "don't" counts as one word, and
+        // the curly ones are made straight so they count as the same word
+        let words = entry
+            .body
+            .split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '’')
+            .map(|word| {
+                word.trim_matches(|c| c == '\'' || c == '’')
+                    .to_lowercase()
+                    .replace('’', "'")
+            });
+
+        for word in words {
+            if word.is_empty() || word.chars().all(|c| c.is_numeric()) || stopwords.contains(&word)
+            {
+                continue;
+            }
+
+            *counts.entry(word).or_insert(0) += 1;
+        }
+    }
+
+    let mut frequencies: Vec<(String, usize)> = counts.into_iter().collect();
+    frequencies.sort_by(|(word_a, uses_a), (word_b, uses_b)| {
+        uses_b.cmp(uses_a).then_with(|| word_a.cmp(word_b))
+    });
+
+    frequencies
+}
+
+/// The dated entries read from an import file, along with a description of each
+/// part of the file that had to be skipped.
+pub type ParsedImport = (Vec<(NaiveDate, String)>, Vec<String>);
+
+/// Parses a Markdown journal, where each entry is headed with `## YYYY-MM-DD`.
+pub fn parse_markdown_import(text: &str) -> ParsedImport {
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+
+    // The date and body of the section being read, if its heading was valid
+    let mut section: Option<(NaiveDate, String)> = None;
+    let mut finish_section = |section: Option<(NaiveDate, String)>, skipped: &mut Vec<String>| {
+        if let Some((date, body)) = section {
+            if body.trim().is_empty() {
+                skipped.push(format!("the entry for {}: it is empty", date));
+            } else {
+                entries.push((date, body.trim().to_string()));
+            }
+        }
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        if let Some(heading) = line.strip_prefix("## ") {
+            finish_section(section.take(), &mut skipped);
+
+            match parse_date(heading.trim()) {
+                Ok(date) => section = Some((date, String::new())),
+                Err(e) => skipped.push(format!("the section on line {}: {}", i + 1, e)),
+            }
+        } else if let Some((_, body)) = &mut section {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+    finish_section(section, &mut skipped);
+
+    (entries, skipped)
+}
+
+/// One entry of a JSON journal file, as `export_json` writes it and
+/// `parse_json_import` reads it.
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    date: String,
+    body: String,
+}
+
+/// Exports every entry as a JSON array of `{"date": "YYYY-MM-DD", "body": "..."}`
+/// objects, oldest first, which `parse_json_import` can read back in.
+pub fn export_json(storage: &dyn Storage) -> error::Result<String> {
+    let entries: Vec<JsonEntry> = storage
+        .fetch_all()?
+        .into_iter()
+        .map(|entry| JsonEntry {
+            date: entry.date.to_string(),
+            body: entry.body,
+        })
+        .collect();
+
+    let mut json = serde_json::to_string_pretty(&entries).unwrap();
+    json.push('\n');
+    Ok(json)
+}
+
+/// Parses a JSON journal, which is an array of `{"date": "YYYY-MM-DD", "body": "..."}`
+/// objects. Fails if the file isn't a JSON array at all.
+pub fn parse_json_import(text: &str) -> Result<ParsedImport, String> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_str(text).map_err(|e| format!("Failed to parse JSON: {}", e))?;
+
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        let entry: JsonEntry = match serde_json::from_value(value) {
+            Ok(entry) => entry,
+            Err(e) => {
+                skipped.push(format!("element {}: {}", i, e));
+                continue;
+            }
+        };
+
+        match parse_date(&entry.date) {
+            Ok(date) if entry.body.trim().is_empty() => {
+                skipped.push(format!("the entry for {}: it is empty", date))
+            }
+            Ok(date) => entries.push((date, entry.body.trim().to_string())),
+            Err(e) => skipped.push(format!("element {}: {}", i, e)),
+        }
+    }
+
+    Ok((entries, skipped))
+}
+
+/// Stores imported entries in the database, like `import_into`, each in its year's
+/// database when there's one a year. Nothing is committed until every entry is in,
+/// so if anything goes wrong the databases are left as they were.
+pub fn import_entries(
+    config: &Config,
+    entries: Vec<(NaiveDate, String)>,
+    skipped: &mut Vec<String>,
+) -> Result<usize, String> {
+    let single = config.db_rollover == DbRollover::Single;
+    let mut years: Vec<i32> = entries.iter().map(|(date, _)| date.year()).collect();
+    years.sort();
+    years.dedup();
+    if single {
+        years.truncate(1);
+    }
+
+    let mut sql_conns = Vec::new();
+    for &year in &years {
+        let sql_conn = open_db(config, year)
+            .map_err(|e| format!("Failed to start import, nothing was imported: {}", e))?;
+        sql_conns.push(sql_conn);
+    }
+    let transactions = sql_conns
+        .iter_mut()
+        .map(Connection::transaction)
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(|e| format!("Failed to start import, nothing was imported: {}", e))?;
+
+    let mut imported = 0;
+    for (&year, tx) in years.iter().zip(&transactions) {
+        let year_entries: Vec<(NaiveDate, String)> = entries
+            .iter()
+            .filter(|(date, _)| single || date.year() == year)
+            .cloned()
+            .collect();
+
+        // Returning drops the transactions, which rolls back every year's database
+        imported += import_into(config, &**tx, year_entries, skipped)
+            .map_err(|e| format!("{}\nNothing was imported.", e))?;
+    }
+
+    for (year, tx) in years.iter().zip(transactions) {
+        tx.commit().map_err(|e| {
+            format!(
+                "Failed to finish importing the entries for {}, those from the years before it were imported: {}",
+                year, e
+            )
+        })?;
+    }
+
+    Ok(imported)
+}
+
+/// Stores imported entries, following the `duplicate_policy` for days that already
+/// have an entry. Returns the number of entries imported.
+pub fn import_into(
+    config: &Config,
+    storage: &dyn Storage,
+    entries: Vec<(NaiveDate, String)>,
+    skipped: &mut Vec<String>,
+) -> Result<usize, String> {
+    let mut imported = 0;
+    for (date, body) in entries {
+        let result = match storage.fetch_by_date(date) {
+            Ok(Some(_)) if config.duplicate_policy == DuplicatePolicy::Reject => {
+                skipped.push(format!(
+                    "the entry for {}: there is already an entry for that day",
+                    date
+                ));
+                continue;
+            }
+            Ok(Some(existing)) => {
+                let body = format!("{}{}{}", existing.body, config.append_delimiter, body);
+                storage.update_body(existing.id, &body)
+            }
+            Ok(None) => storage.insert_entry(date, &body).map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        result.map_err(|e| format!("Failed to import the entry for {}: {}", date, e))?;
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{test_config, TempDir};
+
+    fn entries(bodies: &[&str]) -> Vec<Entry> {
+        bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| Entry {
+                id: i as i64 + 1,
+                date: NaiveDate::from_ymd(2024, 3, i as u32 + 1),
+                body: body.to_string(),
+                is_favorite: false,
+            })
+            .collect()
+    }
+
+    fn stopwords(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn word_frequencies_counts_across_entries() {
+        let entries = entries(&["Walked the dog. The dog was happy!", "Dog, coffee, dog."]);
+
+        assert_eq!(
+            word_frequencies(&entries, &[]),
+            vec![
+                ("dog".to_string(), 4),
+                ("the".to_string(), 2),
+                ("coffee".to_string(), 1),
+                ("happy".to_string(), 1),
+                ("walked".to_string(), 1),
+                ("was".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn word_frequencies_leaves_out_stopwords() {
+        let entries = entries(&["The dog and the cat", "A cat"]);
+
+        assert_eq!(
+            word_frequencies(&entries, &stopwords(&["the", " AND ", "a"])),
+            vec![("cat".to_string(), 2), ("dog".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn word_frequencies_keeps_apostrophes_in_words_and_leaves_out_numbers() {
+        let entries = entries(&["Don't stop. 'Don't' ran 5 km in 2024", "I don’t know"]);
+
+        assert_eq!(
+            word_frequencies(&entries, &stopwords(&["i"])),
+            vec![
+                ("don't".to_string(), 3),
+                ("in".to_string(), 1),
+                ("km".to_string(), 1),
+                ("know".to_string(), 1),
+                ("ran".to_string(), 1),
+                ("stop".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn word_frequencies_splits_unicode_words() {
+        let entries = entries(&["Café—crème, café; Straße"]);
+
+        assert_eq!(
+            word_frequencies(&entries, &[]),
+            vec![
+                ("café".to_string(), 2),
+                ("crème".to_string(), 1),
+                ("straße".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn word_frequencies_of_no_entries_is_empty() {
+        assert!(word_frequencies(&[], &stopwords(&["the"])).is_empty());
+    }
+
+    #[test]
+    fn word_trend_counts_days_without_an_entry_as_zero() {
+        let entries = vec![
+            Entry {
+                id: 1,
+                date: NaiveDate::from_ymd(2024, 3, 1),
+                body: "Three little words".to_string(),
+                is_favorite: false,
+            },
+            Entry {
+                id: 2,
+                date: NaiveDate::from_ymd(2024, 3, 4),
+                body: "Back again".to_string(),
+                is_favorite: false,
+            },
+        ];
+
+        assert_eq!(
+            word_trend(
+                &entries,
+                NaiveDate::from_ymd(2024, 3, 1),
+                NaiveDate::from_ymd(2024, 3, 5)
+            ),
+            vec![
+                (NaiveDate::from_ymd(2024, 3, 1), 3),
+                (NaiveDate::from_ymd(2024, 3, 2), 0),
+                (NaiveDate::from_ymd(2024, 3, 3), 0),
+                (NaiveDate::from_ymd(2024, 3, 4), 2),
+                (NaiveDate::from_ymd(2024, 3, 5), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_word_trend_csv_runs_from_the_first_entry_through_today() {
+        let dir = TempDir::new("export_word_trend");
+        let config = test_config(&dir);
+        let sql_conn = open_db(&config, 2024).unwrap();
+        assert_eq!(
+            export_word_trend(&sql_conn, NaiveDate::from_ymd(2024, 3, 3)).unwrap(),
+            "date,words\n"
+        );
+
+        import_entries(
+            &config,
+            dated(&[(2024, 3, 1, "One two"), (2024, 3, 3, "Three")]),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            export_word_trend(&sql_conn, NaiveDate::from_ymd(2024, 3, 4)).unwrap(),
+            "date,words\n2024-03-01,2\n2024-03-02,0\n2024-03-03,1\n2024-03-04,0\n"
+        );
+    }
+
+    #[test]
+    fn exports_have_their_line_endings_evened_out() {
+        let text = "One\r\nTwo\nThree";
+
+        assert_eq!(
+            encode_export(&Config::default(), text),
+            b"One\nTwo\nThree".to_vec()
+        );
+
+        let crlf = Config {
+            export_line_ending: LineEnding::Crlf,
+            ..Config::default()
+        };
+        assert_eq!(encode_export(&crlf, text), b"One\r\nTwo\r\nThree".to_vec());
+    }
+
+    #[test]
+    fn exports_can_start_with_a_byte_order_mark() {
+        let config = Config {
+            export_bom: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            encode_export(&config, "Café"),
+            "\u{feff}Café".as_bytes().to_vec()
+        );
+        assert_eq!(encode_export(&config, "")[..], [0xEF, 0xBB, 0xBF]);
+        assert!(!encode_export(&Config::default(), "Café").starts_with(&[0xEF]));
+    }
+
+    fn dated(entries: &[(i32, u32, u32, &str)]) -> Vec<(NaiveDate, String)> {
+        entries
+            .iter()
+            .map(|&(y, m, d, body)| (NaiveDate::from_ymd(y, m, d), body.to_string()))
+            .collect()
+    }
+
+    fn stored(config: &Config, year: i32) -> Vec<(NaiveDate, String)> {
+        let sql_conn = open_db(config, year).unwrap();
+        let storage: &dyn Storage = &sql_conn;
+        storage
+            .fetch_all()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.date, entry.body))
+            .collect()
+    }
+
+    #[test]
+    fn a_json_export_imports_back_into_an_empty_journal() {
+        let dir = TempDir::new("export_json_round_trip");
+        let original = test_config(&dir);
+        let entries = dated(&[
+            (2024, 3, 1, "First entry"),
+            (2024, 3, 2, "Café \"quotes\"\nand a second line"),
+        ]);
+        let mut skipped = Vec::new();
+        import_entries(&original, entries.clone(), &mut skipped).unwrap();
+
+        let json = export_json(&open_db(&original, 2024).unwrap()).unwrap();
+
+        let copy = Config {
+            db_filename: dir.file("copy.db"),
+            ..test_config(&dir)
+        };
+        let (imported, mut skipped) = parse_json_import(&json).unwrap();
+        assert_eq!(import_entries(&copy, imported, &mut skipped), Ok(2));
+        assert!(skipped.is_empty());
+        assert_eq!(stored(&copy, 2024), entries);
+    }
+
+    #[test]
+    fn an_archive_holds_every_entry_its_attachments_and_the_metadata() {
+        let dir = TempDir::new("export_archive");
+        let config = test_config(&dir);
+        let photo = dir.file("photo.png");
+        std::fs::write(&photo, b"not really a png").unwrap();
+        let with_photo = format!("A day out\n[attachment 'photo.png' saved as {}]", photo);
+        import_entries(
+            &config,
+            dated(&[(2024, 3, 1, "First entry"), (2024, 3, 2, &with_photo)]),
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        let today = NaiveDate::from_ymd(2024, 6, 1);
+        let zip = export_archive(&config, &open_db(&config, 2024).unwrap(), today).unwrap();
+        let files = crate::archive::read_zip(&zip);
+
+        let paths: Vec<&str> = files.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "entries/2024-03-01.md",
+                "entries/2024-03-02.md",
+                "attachments/2024-03-02/photo.png",
+                "metadata.json",
+            ]
+        );
+        assert_eq!(files[0].2, b"First entry");
+        assert_eq!(files[2].2, b"not really a png");
+
+        // Stamped with the day it was exported, not the day of any entry
+        let (_, metadata_date, metadata) = &files[3];
+        assert_eq!(*metadata_date, (44 << 9) | (6 << 5) | 1);
+        let metadata: serde_json::Value = serde_json::from_slice(metadata).unwrap();
+        let listed = metadata["entries"].as_array().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[1]["file"], "entries/2024-03-02.md");
+        assert_eq!(
+            listed[1]["attachments"],
+            json!(["attachments/2024-03-02/photo.png"])
+        );
+    }
+
+    #[test]
+    fn a_failed_import_leaves_every_years_database_as_it_was() {
+        let dir = TempDir::new("import_yearly_rollback");
+        let config = Config {
+            db_rollover: DbRollover::Yearly,
+            db_filename: dir.file("journal-{year}.db"),
+            ..test_config(&dir)
+        };
+        open_db(&config, 2024)
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER no_inserts BEFORE INSERT ON entries
+                 BEGIN SELECT RAISE(ABORT, 'read-only'); END;",
+            )
+            .unwrap();
+
+        let entries = dated(&[
+            (2023, 12, 31, "Last of 2023"),
+            (2024, 1, 1, "First of 2024"),
+        ]);
+        let result = import_entries(&config, entries, &mut Vec::new());
+
+        assert!(result.unwrap_err().contains("Nothing was imported"));
+        assert!(stored(&config, 2023).is_empty());
+        assert!(stored(&config, 2024).is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+//! Telling other programs about each entry that's saved, through the
+//! `post_store_hook`.
+
+use crate::config::{Config, PostStoreHook, StorageBackend};
+use crate::storage::FileStorage;
+use crate::store::db_path;
+use chrono::prelude::*;
+use serde_json::json;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long a hook command gets to finish before it's killed, so a hung hook
+/// can't hold up the next email.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the `post_store_hook`, if there is one, for an entry that was just saved.
+/// `body` is the whole entry, including anything it was appended to. The entry is
+/// already saved, so a hook that fails is only logged.
+pub fn run_post_store_hook(
+    config: &Config,
+    date: NaiveDate,
+    body: &str,
+    tags: &[String],
+    appended: bool,
+) {
+    let hook = match &config.post_store_hook {
+        Some(hook) => hook,
+        None => return,
+    };
+
+    let payload = json!({
+        "date": date.to_string(),
+        "body": body,
+        "tags": tags,
+        "appended": appended,
+    })
+    .to_string();
+
+    let result = match hook {
+        PostStoreHook::Webhook { url } => post_webhook(url, &payload),
+        PostStoreHook::Command { command } => run_command(
+            command,
+            &entry_path(config, date),
+            date,
+            &payload,
+            COMMAND_TIMEOUT,
+        ),
+    };
+
+    match result {
+        Ok(()) => log!("Ran the post-store hook for the entry for {}", date),
+        Err(e) => log_error!(
+            "The post-store hook for the entry for {} failed: {}",
+            date,
+            e
+        ),
+    }
+}
+
+/// Where the entry is kept: its own file, or the database that holds it.
+fn entry_path(config: &Config, date: NaiveDate) -> String {
+    match &config.storage_backend {
+        StorageBackend::Sqlite => db_path(config, date.year()),
+        StorageBackend::Files { dir } => FileStorage::new(dir).path(date).display().to_string(),
+    }
+}
+
+/// Runs the command through the shell, with `{date}` and `{path}` filled in, and
+/// the entry as JSON on its stdin. We wait for it to finish, so it should be quick,
+/// and it's killed if it takes longer than `timeout`.
+fn run_command(
+    command: &str,
+    path: &str,
+    date: NaiveDate,
+    payload: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let command = command
+        .replace("{date}", &date.to_string())
+        .replace("{path}", path);
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run \"{}\": {}", command, e))?;
+
+    // A command that doesn't read its stdin closes it early, which is fine. It's
+    // written from another thread, so one that never reads it still times out.
+    if let Some(mut stdin) = child.stdin.take() {
+        let payload = payload.to_string();
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(payload.as_bytes());
+        });
+    }
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!(
+                "\"{}\" was killed after running for {} seconds",
+                command,
+                timeout.as_secs()
+            ));
+        }
+
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    if !status.success() {
+        return Err(format!("\"{}\" exited with {}", command, status));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+fn post_webhook(url: &str, payload: &str) -> Result<(), String> {
+    webhook::post_json(url, payload)
+}
+
+#[cfg(not(feature = "webhook"))]
+fn post_webhook(_url: &str, _payload: &str) -> Result<(), String> {
+    Err("this build of Mail Journal doesn't have the webhook feature".to_string())
+}
+
+/// Just enough HTTP to POST a JSON body, over TLS for https URLs.
+#[cfg(feature = "webhook")]
+mod webhook {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn post_json(url: &str, payload: &str) -> Result<(), String> {
+        let (is_https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("\"{}\" isn't an http or https URL", url));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(ix) => (&rest[..ix], &rest[ix..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("\"{}\" has a bad port", url))?,
+            ),
+            None => (authority, if is_https { 443 } else { 80 }),
+        };
+
+        let address = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("failed to look up {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("{} has no address", host))?;
+        let stream = TcpStream::connect_timeout(&address, TIMEOUT)
+            .map_err(|e| format!("failed to connect to {}: {}", authority, e))?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+            .map_err(|e| e.to_string())?;
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: mail-journal/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            authority,
+            env!("CARGO_PKG_VERSION"),
+            payload.len(),
+            payload
+        );
+
+        let response = if is_https {
+            let tls = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+            let mut stream = tls.connect(host, stream).map_err(|e| e.to_string())?;
+            exchange(&mut stream, &request)?
+        } else {
+            let mut stream = stream;
+            exchange(&mut stream, &request)?
+        };
+
+        // Only the status matters, like the 204 of "HTTP/1.1 204 No Content"
+        let status = response.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            return Err(format!("{} answered with \"{}\"", url, response.trim()));
+        }
+
+        Ok(())
+    }
+
+    /// Sends the request, and reads back the status line of the response.
+    fn exchange<S: Read + Write>(stream: &mut S, request: &str) -> Result<String, String> {
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| format!("failed to send the request: {}", e))?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut status_line)
+            .map_err(|e| format!("failed to read the response: {}", e))?;
+
+        Ok(status_line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{test_config, TempDir};
+
+    #[test]
+    fn a_command_hook_gets_the_entry_as_json() {
+        let dir = TempDir::new("hook_command");
+        let config = Config {
+            post_store_hook: Some(PostStoreHook::Command {
+                command: "cat > {path}.{date}.json".to_string(),
+            }),
+            ..test_config(&dir)
+        };
+
+        let date = NaiveDate::from_ymd(2024, 6, 1);
+        run_post_store_hook(&config, date, "Dear diary", &["work".to_string()], true);
+
+        let written = std::fs::read_to_string(dir.file("journal.db.2024-06-01.json")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&written).unwrap(),
+            json!({
+                "date": "2024-06-01",
+                "body": "Dear diary",
+                "tags": ["work"],
+                "appended": true,
+            })
+        );
+    }
+
+    #[test]
+    fn a_failing_command_is_an_error() {
+        let date = NaiveDate::from_ymd(2024, 6, 1);
+        let result = run_command("exit 3", "", date, "{}", COMMAND_TIMEOUT);
+        assert!(result.unwrap_err().contains("exited with"));
+    }
+
+    #[test]
+    fn a_hung_command_is_killed() {
+        let date = NaiveDate::from_ymd(2024, 6, 1);
+        let started = Instant::now();
+        let result = run_command("sleep 10", "", date, "{}", Duration::from_millis(200));
+
+        assert!(result.unwrap_err().contains("was killed"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,299 @@
+//! Working out what each incoming email is, and handling it: a journal entry, a
+//! command, or something to leave alone.
+
+use crate::command::{allow_command, parse_email_command, run_command};
+use crate::config::Config;
+use crate::error;
+use crate::mail::{send_error_email, Email};
+use crate::schedule::handle_bounce;
+use crate::store::store_journal_email;
+use chrono::prelude::*;
+
+/// Handles a single incoming email, either running the command in its subject
+/// or storing it as a journal entry. Returns whether that's done with, so the
+/// email can be archived: it was saved as an entry or run as a command. Anything
+/// else, like an entry that was turned down or mail that was ignored, stays in the
+/// inbox to be looked at.
+pub fn handle_email(config: &Config, email: &Email, now: DateTime<Utc>) -> error::Result<bool> {
+    // Delivery reports are about mail we sent, so whoever they're from, they are
+    // never entries or commands
+    if let Some(bounce) = &email.bounce {
+        handle_bounce(config, bounce);
+        return Ok(false);
+    }
+
+    // An out of office reply to a reminder isn't an entry, and answering it could
+    // start an endless back and forth with the responder
+    if config.ignore_automatic_emails && email.is_automatic {
+        log!("Ignoring automatic email from {}", email.from);
+        return Ok(false);
+    }
+
+    // Mail from our own address (like a reminder or reply that loops back to us) is
+    // never an entry or a command, even if it's also the target address, or we
+    // could end up answering ourselves forever
+    if email.is_from(&config.journal_email) {
+        log!("Ignoring email from the journal address {}", email.from);
+        return Ok(false);
+    }
+
+    let is_target = email.is_from(&config.target_email);
+    if !is_target
+        && !config
+            .allowed_sender_domains
+            .iter()
+            .any(|domain| email.is_from_domain(domain))
+    {
+        log!("Ignoring email from {}", email.from);
+        return Ok(false);
+    }
+
+    let command = parse_email_command(email);
+
+    // Anyone at the allowed_sender_domains can write entries, but only the target
+    // address can run commands
+    if command.is_some() && !is_target {
+        log!(
+            "Ignoring command \"{}\" from {}: commands are only accepted from {}",
+            email.subject,
+            email.from,
+            config.target_email
+        );
+        return Ok(false);
+    }
+
+    if command.is_some() && !allow_command(config, &email.from, now)? {
+        log!(
+            "Ignoring command \"{}\" from {}: over max_commands_per_hour",
+            email.subject,
+            email.from
+        );
+        return Ok(false);
+    }
+
+    match command {
+        Some(Ok(command)) => {
+            run_command(config, email, command, now)?;
+            Ok(true)
+        }
+        Some(Err(e)) => {
+            log!("Rejecting malformed command \"{}\": {}", email.subject, e);
+            send_error_email(config, &email.reply_to, &e);
+            Ok(false)
+        }
+        None => store_journal_email(config, email, now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::open_storage;
+    use crate::test_util::{sent_emails, test_config, TempDir};
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2024, 6, 1).and_hms(12, 0, 0)
+    }
+
+    fn email_with_headers(headers: &str, subject: &str, body: &str) -> Email {
+        let raw = format!(
+            "{}\r\nSubject: {}\r\nDate: Sat, 1 Jun 2024 10:00:00 +0000\r\n\r\n{}\r\n",
+            headers.trim_end(),
+            subject,
+            body
+        );
+        Email::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    fn email_from(from: &str, subject: &str, body: &str) -> Email {
+        email_with_headers(&format!("From: {}", from), subject, body)
+    }
+
+    fn saved_entry(config: &Config) -> Option<String> {
+        open_storage(config, 2024)
+            .unwrap()
+            .fetch_by_date(now().naive_utc().date())
+            .unwrap()
+            .map(|entry| entry.body.trim().to_string())
+    }
+
+    #[test]
+    fn a_saved_entry_is_done_with() {
+        let dir = TempDir::new("inbox-saved");
+        let config = test_config(&dir);
+
+        let email = email_from("me@example.com", "Re: Journal", "A good day");
+        assert!(handle_email(&config, &email, now()).unwrap());
+        assert_eq!(saved_entry(&config).as_deref(), Some("A good day"));
+    }
+
+    #[test]
+    fn a_turned_down_entry_stays_in_the_inbox() {
+        let dir = TempDir::new("inbox-rejected");
+        let config = test_config(&dir);
+        let first = email_from("me@example.com", "Re: Journal", "A good day");
+        let second = email_from("me@example.com", "Re: Journal", "Something else entirely");
+
+        assert!(handle_email(&config, &first, now()).unwrap());
+        assert!(!handle_email(&config, &second, now()).unwrap());
+        assert_eq!(saved_entry(&config).as_deref(), Some("A good day"));
+    }
+
+    #[test]
+    fn a_command_that_ran_is_done_with() {
+        let dir = TempDir::new("inbox-command");
+        let config = test_config(&dir);
+
+        let email = email_from("me@example.com", "COUNT", "");
+        assert!(handle_email(&config, &email, now()).unwrap());
+        assert_eq!(sent_emails()[0].subject(), "Entry count");
+    }
+
+    #[test]
+    fn a_help_email_gets_the_commands_and_stores_nothing() {
+        for (subject, body) in &[("HELP", ""), ("Re: Journal", "Help")] {
+            let dir = TempDir::new("inbox-help");
+            let config = test_config(&dir);
+
+            let email = email_from("me@example.com", subject, body);
+            assert!(handle_email(&config, &email, now()).unwrap());
+
+            let sent = sent_emails();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].subject(), "Mail Journal commands");
+            assert!(sent[0].body().contains("HELP: This list of commands"));
+            assert_eq!(saved_entry(&config), None);
+        }
+    }
+
+    #[test]
+    fn help_with_more_to_say_is_an_entry() {
+        let dir = TempDir::new("inbox-help-entry");
+        let config = test_config(&dir);
+
+        let email = email_from(
+            "me@example.com",
+            "Help with the move",
+            "Carried boxes all day",
+        );
+        assert!(handle_email(&config, &email, now()).unwrap());
+        assert_eq!(
+            saved_entry(&config).as_deref(),
+            Some("Carried boxes all day")
+        );
+    }
+
+    #[test]
+    fn a_malformed_command_stays_in_the_inbox() {
+        let dir = TempDir::new("inbox-malformed");
+        let config = test_config(&dir);
+
+        let email = email_from("me@example.com", "PAUSE soon", "");
+        assert!(!handle_email(&config, &email, now()).unwrap());
+        assert_eq!(sent_emails()[0].subject(), "Error");
+    }
+
+    #[test]
+    fn mail_from_a_stranger_stays_in_the_inbox() {
+        let dir = TempDir::new("inbox-stranger");
+        let config = test_config(&dir);
+
+        let email = email_from("someone@elsewhere.com", "Re: Journal", "Hello");
+        assert!(!handle_email(&config, &email, now()).unwrap());
+        assert_eq!(saved_entry(&config), None);
+    }
+
+    fn reminder_bounce() -> Email {
+        email_with_headers(
+            "From: MAILER-DAEMON@mx.example.com\r\nMIME-Version: 1.0\r\nContent-Type: multipart/report; report-type=delivery-status; boundary=\"report\"",
+            "Undelivered Mail Returned to Sender",
+            "--report\r\nContent-Type: text/plain\r\n\r\nThe mailbox is full.\r\n--report\r\nContent-Type: text/rfc822-headers\r\n\r\nFrom: journal@example.com\r\nSubject: Daily Journal Entry\r\n--report--",
+        )
+    }
+
+    #[test]
+    fn a_bounced_reminder_is_reported_to_the_backup_address() {
+        let dir = TempDir::new("inbox-bounce");
+        let mut config = test_config(&dir);
+        config.detect_bounces = true;
+        config.backup_notify_email = Some("backup@example.com".to_string());
+
+        assert!(!handle_email(&config, &reminder_bounce(), now()).unwrap());
+
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, vec!["backup@example.com".to_string()]);
+        assert_eq!(sent[0].subject(), "Journal reminders are bouncing");
+        assert_eq!(saved_entry(&config), None);
+    }
+
+    #[test]
+    fn a_bounced_reminder_is_only_logged_without_detect_bounces() {
+        let dir = TempDir::new("inbox-bounce-off");
+        let mut config = test_config(&dir);
+        config.backup_notify_email = Some("backup@example.com".to_string());
+
+        assert!(!handle_email(&config, &reminder_bounce(), now()).unwrap());
+        assert!(sent_emails().is_empty());
+        assert_eq!(saved_entry(&config), None);
+    }
+
+    #[test]
+    fn automatic_mail_stays_in_the_inbox() {
+        let dir = TempDir::new("inbox-automatic");
+        let config = test_config(&dir);
+
+        let email = email_with_headers(
+            "From: me@example.com\r\nAuto-Submitted: auto-replied",
+            "Out of office",
+            "I'm away until Monday",
+        );
+        assert!(!handle_email(&config, &email, now()).unwrap());
+        assert_eq!(saved_entry(&config), None);
+        assert!(sent_emails().is_empty());
+    }
+
+    #[test]
+    fn mail_from_the_journal_address_is_ignored_even_if_its_the_target() {
+        let dir = TempDir::new("inbox-own-address");
+        let mut config = test_config(&dir);
+        config.target_email = config.journal_email.clone();
+        config.allowed_sender_domains = vec!["example.com".to_string()];
+
+        let from = format!("Mail Journal <{}>", config.journal_email);
+        for (subject, body) in &[
+            ("Daily Journal Entry", "How was your day today?"),
+            ("COUNT", ""),
+        ] {
+            let email = email_from(&from, subject, body);
+            assert!(!handle_email(&config, &email, now()).unwrap());
+        }
+        assert_eq!(saved_entry(&config), None);
+        assert!(sent_emails().is_empty());
+    }
+
+    #[test]
+    fn an_entry_from_an_allowed_domain_is_saved() {
+        let dir = TempDir::new("inbox-domain-entry");
+        let mut config = test_config(&dir);
+        config.allowed_sender_domains = vec!["MyFamily.com".to_string()];
+
+        let email = email_from("Alice <alice@myfamily.com>", "Re: Journal", "Baked bread");
+        assert!(handle_email(&config, &email, now()).unwrap());
+        assert_eq!(saved_entry(&config).as_deref(), Some("Baked bread"));
+
+        let email = email_from("bob@otherfamily.com", "Re: Journal", "Hello");
+        assert!(!handle_email(&config, &email, now()).unwrap());
+    }
+
+    #[test]
+    fn a_command_from_an_allowed_domain_is_refused() {
+        let dir = TempDir::new("inbox-domain-command");
+        let mut config = test_config(&dir);
+        config.allowed_sender_domains = vec!["myfamily.com".to_string()];
+
+        let email = email_from("alice@myfamily.com", "COUNT", "");
+        assert!(!handle_email(&config, &email, now()).unwrap());
+        assert!(sent_emails().is_empty());
+    }
+}
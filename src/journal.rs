@@ -0,0 +1,660 @@
+//! Storage and lookup of journal entries.
+//!
+//! Entries live in the `entries` table, one row per day, with the date split into
+//! `day`, `month` and `year` columns.
+
+use chrono::prelude::*;
+use chrono::Duration;
+use rusqlite::types::ToSql;
+use rusqlite::{Connection, Result, Row, NO_PARAMS};
+
+/// A journal entry, written on a single day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub id: i64,
+    pub date: NaiveDate,
+    pub body: String,
+    pub is_favorite: bool,
+}
+
+const ENTRY_COLUMNS: &str = "id, day, month, year, body, is_favorite";
+
+fn entry_from_row(row: &Row) -> Entry {
+    Entry {
+        id: row.get(0),
+        date: NaiveDate::from_ymd(
+            row.get(3),
+            row.get::<_, i32>(2) as u32,
+            row.get::<_, i32>(1) as u32,
+        ),
+        body: row.get(4),
+        is_favorite: row.get(5),
+    }
+}
+
+/// Packs a date into a single YYYYMMDD integer, which compares the same way the
+/// date does. `PACKED_DATE` is the same thing, computed from the date columns.
+fn pack_date(date: NaiveDate) -> i32 {
+    date.year() * 10000 + date.month() as i32 * 100 + date.day() as i32
+}
+
+const PACKED_DATE: &str = "(year * 10000 + month * 100 + day)";
+
+/// Creates the `entries` table, if it doesn't exist yet.
+pub fn create_table(sql_conn: &Connection) -> Result<()> {
+    // NOTE (Declan, 12/12/2018)
+    // I am using separate day, month, year columns in this database
+    // because SQLite does not have a sufficient DATETIME type, or functions
+    // to do complicated queries with them. Therefore it's just easier to manage
+    // each date component as an integer in our case.
+    sql_conn.execute(
+        "CREATE TABLE IF NOT EXISTS entries (
+              id    INTEGER PRIMARY KEY,
+              day   INTEGER NOT NULL,
+              month INTEGER NOT NULL,
+              year  INTEGER NOT NULL,
+              body  TEXT NOT NULL,
+              is_favorite INTEGER NOT NULL DEFAULT 0
+              )",
+        NO_PARAMS,
+    )?;
+
+    // Databases from before favorites existed need the column added
+    if !has_column(sql_conn, "main", "entries", "is_favorite")? {
+        sql_conn.execute(
+            "ALTER TABLE entries ADD COLUMN is_favorite INTEGER NOT NULL DEFAULT 0",
+            NO_PARAMS,
+        )?;
+    }
+
+    sql_conn.execute(
+        "CREATE TABLE IF NOT EXISTS entry_tags (
+              entry_id INTEGER NOT NULL,
+              tag      TEXT NOT NULL,
+              UNIQUE (entry_id, tag)
+              )",
+        NO_PARAMS,
+    )?;
+
+    // There's only ever one entry a day, since appending adds to the existing one.
+    // Databases from before that was enforced can have days with more, and only get
+    // the index once `dedup-days` has merged them, or it would fail to be created.
+    let has_index = sql_conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'entries_date'",
+        NO_PARAMS,
+        |row| row.get::<_, i64>(0),
+    )? > 0;
+    if !has_index && count_duplicate_days(sql_conn)? == 0 {
+        sql_conn.execute(
+            "CREATE UNIQUE INDEX entries_date ON entries (year, month, day)",
+            NO_PARAMS,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Counts the days that have more than one entry.
+pub fn count_duplicate_days(sql_conn: &Connection) -> Result<usize> {
+    sql_conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT 1 FROM entries GROUP BY year, month, day HAVING COUNT(*) > 1)",
+        NO_PARAMS,
+        |row| row.get::<_, i64>(0) as usize,
+    )
+}
+
+/// What to do with the bodies of several entries for the same day, when they're
+/// merged into one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DayMerge {
+    /// Keep them all, oldest first, separated by a delimiter
+    Concatenate,
+    /// Keep only the longest one
+    KeepLongest,
+}
+
+/// Merges the entries of any day that has more than one into the oldest of them,
+/// which also gets the tags of the others, and is a favorite if any of them was.
+/// Databases from before appending existed can have such days. Returns the number
+/// of entries merged away. Best run in a transaction, so a failure leaves nothing
+/// half merged.
+pub fn merge_duplicate_days(
+    sql_conn: &Connection,
+    merge: DayMerge,
+    delimiter: &str,
+) -> Result<usize> {
+    let mut stmt = sql_conn.prepare(
+        "SELECT day, month, year FROM entries GROUP BY year, month, day HAVING COUNT(*) > 1",
+    )?;
+    let days: Vec<(i32, i32, i32)> = stmt
+        .query_map(NO_PARAMS, |row| (row.get(0), row.get(1), row.get(2)))?
+        .collect::<Result<_>>()?;
+
+    let mut merged = 0;
+    for (day, month, year) in days {
+        let mut stmt = sql_conn.prepare(&format!(
+            "SELECT {} FROM entries WHERE day = ?1 AND month = ?2 AND year = ?3 ORDER BY id",
+            ENTRY_COLUMNS
+        ))?;
+        let entries: Vec<Entry> = stmt
+            .query_map([day, month, year], entry_from_row)?
+            .collect::<Result<_>>()?;
+
+        let (kept, others) = entries.split_first().unwrap();
+        let body = match merge {
+            DayMerge::Concatenate => entries
+                .iter()
+                .map(|entry| entry.body.as_str())
+                .collect::<Vec<&str>>()
+                .join(delimiter),
+            // The first of the longest, if several are as long
+            DayMerge::KeepLongest => entries
+                .iter()
+                .rev()
+                .max_by_key(|entry| entry.body.chars().count())
+                .unwrap()
+                .body
+                .clone(),
+        };
+        let is_favorite = entries.iter().any(|entry| entry.is_favorite);
+
+        sql_conn.execute(
+            "UPDATE entries SET body = ?1, is_favorite = ?2 WHERE id = ?3",
+            &[&body as &dyn ToSql, &is_favorite, &kept.id],
+        )?;
+
+        for other in others {
+            sql_conn.execute(
+                "INSERT OR IGNORE INTO entry_tags (entry_id, tag)
+                 SELECT ?1, tag FROM entry_tags WHERE entry_id = ?2",
+                [kept.id, other.id],
+            )?;
+            sql_conn.execute("DELETE FROM entry_tags WHERE entry_id = ?1", [other.id])?;
+            sql_conn.execute("DELETE FROM entries WHERE id = ?1", [other.id])?;
+        }
+
+        merged += others.len();
+    }
+
+    Ok(merged)
+}
+
+/// Checks whether a table in the given schema (`main`, or an attached database) has a column.
+pub fn has_column(sql_conn: &Connection, schema: &str, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = sql_conn.prepare(&format!("PRAGMA {}.table_info({})", schema, table))?;
+    let columns: Vec<String> = stmt
+        .query_map(NO_PARAMS, |row| row.get(1))?
+        .collect::<Result<_>>()?;
+
+    Ok(columns.iter().any(|c| c == column))
+}
+
+/// Tags an entry. Tagging an entry with a tag it already has does nothing.
+pub fn add_tag(sql_conn: &Connection, id: i64, tag: &str) -> Result<()> {
+    sql_conn.execute(
+        "INSERT OR IGNORE INTO entry_tags (entry_id, tag) VALUES (?1, ?2)",
+        &[&id as &dyn ToSql, &tag],
+    )?;
+
+    Ok(())
+}
+
+/// Fetches the tags of an entry, in alphabetical order.
+pub fn fetch_tags(sql_conn: &Connection, id: i64) -> Result<Vec<String>> {
+    let mut stmt =
+        sql_conn.prepare("SELECT tag FROM entry_tags WHERE entry_id = ?1 ORDER BY tag")?;
+    let tags = stmt.query_map([id], |row| row.get(0))?.collect();
+
+    tags
+}
+
+/// Stores a new entry for the given date, returning its id.
+pub fn insert_entry(sql_conn: &Connection, date: NaiveDate, body: &str) -> Result<i64> {
+    sql_conn.execute(
+        "INSERT INTO entries (day, month, year, body) values (?1, ?2, ?3, ?4)",
+        &[
+            &(date.day() as i32) as &dyn ToSql,
+            &(date.month() as i32),
+            &date.year(),
+            &body,
+        ],
+    )?;
+
+    Ok(sql_conn.last_insert_rowid())
+}
+
+/// Replaces the body of an existing entry.
+pub fn update_body(sql_conn: &Connection, id: i64, body: &str) -> Result<()> {
+    sql_conn.execute(
+        "UPDATE entries SET body = ?1 WHERE id = ?2",
+        &[&body as &dyn ToSql, &id],
+    )?;
+
+    Ok(())
+}
+
+/// Deletes the entry for the given date, returning what was removed.
+pub fn delete_by_date(sql_conn: &Connection, date: NaiveDate) -> Result<Option<Entry>> {
+    let entry = match fetch_by_date(sql_conn, date)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    sql_conn.execute("DELETE FROM entries WHERE id = ?1", [entry.id])?;
+    sql_conn.execute("DELETE FROM entry_tags WHERE entry_id = ?1", [entry.id])?;
+
+    Ok(Some(entry))
+}
+
+/// Stars or unstars the entry for the given date. Returns false if there is no
+/// entry for that date.
+pub fn set_favorite(sql_conn: &Connection, date: NaiveDate, is_favorite: bool) -> Result<bool> {
+    let changed = sql_conn.execute(
+        "UPDATE entries SET is_favorite = ?1 WHERE day = ?2 AND month = ?3 AND year = ?4",
+        &[
+            &is_favorite as &dyn ToSql,
+            &(date.day() as i32),
+            &(date.month() as i32),
+            &date.year(),
+        ],
+    )?;
+
+    Ok(changed > 0)
+}
+
+/// Fetches every starred entry, oldest first.
+pub fn fetch_favorites(sql_conn: &Connection) -> Result<Vec<Entry>> {
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries WHERE is_favorite = 1 ORDER BY year, month, day",
+        ENTRY_COLUMNS
+    ))?;
+
+    let entries = stmt.query_map(NO_PARAMS, entry_from_row)?.collect();
+
+    entries
+}
+
+/// Fetches the entry written on the given date, if there is one.
+pub fn fetch_by_date(sql_conn: &Connection, date: NaiveDate) -> Result<Option<Entry>> {
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries WHERE day = ?1 AND month = ?2 AND year = ?3",
+        ENTRY_COLUMNS
+    ))?;
+
+    let entry = stmt
+        .query_map(
+            [date.day() as i32, date.month() as i32, date.year()],
+            entry_from_row,
+        )?
+        .next()
+        .transpose()?;
+
+    Ok(entry)
+}
+
+/// Fetches the entries written on the same day as `date` in previous years, oldest first.
+pub fn fetch_on_this_day(sql_conn: &Connection, date: NaiveDate) -> Result<Vec<Entry>> {
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries WHERE day = ?1 AND month = ?2 AND year < ?3 ORDER BY year",
+        ENTRY_COLUMNS
+    ))?;
+
+    let entries = stmt
+        .query_map(
+            [date.day() as i32, date.month() as i32, date.year()],
+            entry_from_row,
+        )?
+        .collect();
+
+    entries
+}
+
+/// Fetches the entries written within `window_days` of the same day as `date` in
+/// previous years, oldest first. A window can reach into the year before or after,
+/// so an entry from December 30th is found for January 1st.
+pub fn fetch_near_this_day(
+    sql_conn: &Connection,
+    date: NaiveDate,
+    window_days: i64,
+) -> Result<Vec<Entry>> {
+    let first = match fetch_first_date(sql_conn)? {
+        Some(first) => first,
+        None => return Ok(Vec::new()),
+    };
+
+    // Starting a year early catches early January entries near a late December day
+    let window = Duration::days(window_days);
+    let mut entries = Vec::new();
+    for year in first.year() - 1..date.year() {
+        let anniversary = same_day_in(date, year);
+        entries.extend(fetch_between(
+            sql_conn,
+            anniversary - window,
+            anniversary + window,
+        )?);
+    }
+
+    Ok(entries)
+}
+
+/// Gets the same month and day as `date` in another year. February 29th becomes
+/// the 28th in years that don't have it.
+pub fn same_day_in(date: NaiveDate, year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, date.month(), date.day())
+        .unwrap_or_else(|| NaiveDate::from_ymd(year, 2, 28))
+}
+
+/// Fetches all entries written between `start` and `end` (inclusive), oldest first.
+pub fn fetch_between(
+    sql_conn: &Connection,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<Entry>> {
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries WHERE {} BETWEEN ?1 AND ?2 ORDER BY year, month, day",
+        ENTRY_COLUMNS, PACKED_DATE
+    ))?;
+
+    let entries = stmt
+        .query_map([pack_date(start), pack_date(end)], entry_from_row)?
+        .collect();
+
+    entries
+}
+
+/// Fetches every entry, oldest first.
+pub fn fetch_all(sql_conn: &Connection) -> Result<Vec<Entry>> {
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries ORDER BY year, month, day",
+        ENTRY_COLUMNS
+    ))?;
+
+    let entries = stmt.query_map(NO_PARAMS, entry_from_row)?.collect();
+
+    entries
+}
+
+/// Fetches the date of the oldest entry, if there are any entries at all.
+pub fn fetch_first_date(sql_conn: &Connection) -> Result<Option<NaiveDate>> {
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries ORDER BY year, month, day LIMIT 1",
+        ENTRY_COLUMNS
+    ))?;
+
+    let date = stmt
+        .query_map(NO_PARAMS, entry_from_row)?
+        .next()
+        .transpose()?
+        .map(|entry| entry.date);
+
+    Ok(date)
+}
+
+/// Fetches every entry whose body contains `term` (ignoring case), oldest first.
+pub fn search(sql_conn: &Connection, term: &str) -> Result<Vec<Entry>> {
+    // Escape the LIKE wildcards, so the term is matched literally
+    let pattern = format!(
+        "%{}%",
+        term.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+
+    let mut stmt = sql_conn.prepare(&format!(
+        "SELECT {} FROM entries WHERE body LIKE ?1 ESCAPE '\\' ORDER BY year, month, day",
+        ENTRY_COLUMNS
+    ))?;
+
+    let entries = stmt.query_map([pattern], entry_from_row)?.collect();
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> Connection {
+        let sql_conn = Connection::open_in_memory().unwrap();
+        create_table(&sql_conn).unwrap();
+        sql_conn
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    fn bodies(entries: Vec<Entry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.body).collect()
+    }
+
+    #[test]
+    fn an_inserted_entry_is_fetched_by_its_date() {
+        let sql_conn = open();
+        let id = insert_entry(&sql_conn, date(2020, 5, 17), "A good day").unwrap();
+
+        let entry = fetch_by_date(&sql_conn, date(2020, 5, 17))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.id, id);
+        assert_eq!(entry.date, date(2020, 5, 17));
+        assert_eq!(entry.body, "A good day");
+        assert!(!entry.is_favorite);
+        assert_eq!(fetch_by_date(&sql_conn, date(2020, 5, 18)).unwrap(), None);
+    }
+
+    #[test]
+    fn update_body_replaces_the_body() {
+        let sql_conn = open();
+        let id = insert_entry(&sql_conn, date(2020, 5, 17), "Before").unwrap();
+        update_body(&sql_conn, id, "After").unwrap();
+
+        let entry = fetch_by_date(&sql_conn, date(2020, 5, 17))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.body, "After");
+    }
+
+    #[test]
+    fn delete_by_date_removes_the_entry_and_its_tags() {
+        let sql_conn = open();
+        let id = insert_entry(&sql_conn, date(2020, 5, 17), "Gone soon").unwrap();
+        add_tag(&sql_conn, id, "work").unwrap();
+
+        let deleted = delete_by_date(&sql_conn, date(2020, 5, 17))
+            .unwrap()
+            .unwrap();
+        assert_eq!(deleted.body, "Gone soon");
+        assert_eq!(fetch_by_date(&sql_conn, date(2020, 5, 17)).unwrap(), None);
+        assert!(fetch_tags(&sql_conn, id).unwrap().is_empty());
+        assert_eq!(delete_by_date(&sql_conn, date(2020, 5, 17)).unwrap(), None);
+    }
+
+    #[test]
+    fn tags_are_kept_once_in_alphabetical_order() {
+        let sql_conn = open();
+        let id = insert_entry(&sql_conn, date(2020, 5, 17), "Tagged").unwrap();
+        for tag in &["work", "family", "work"] {
+            add_tag(&sql_conn, id, tag).unwrap();
+        }
+
+        assert_eq!(fetch_tags(&sql_conn, id).unwrap(), vec!["family", "work"]);
+    }
+
+    #[test]
+    fn favorites_are_the_starred_entries_oldest_first() {
+        let sql_conn = open();
+        insert_entry(&sql_conn, date(2021, 1, 2), "Newer").unwrap();
+        insert_entry(&sql_conn, date(2020, 1, 2), "Older").unwrap();
+        insert_entry(&sql_conn, date(2020, 6, 1), "Not starred").unwrap();
+
+        assert!(set_favorite(&sql_conn, date(2021, 1, 2), true).unwrap());
+        assert!(set_favorite(&sql_conn, date(2020, 1, 2), true).unwrap());
+        assert!(!set_favorite(&sql_conn, date(2019, 1, 1), true).unwrap());
+        assert_eq!(
+            bodies(fetch_favorites(&sql_conn).unwrap()),
+            vec!["Older", "Newer"]
+        );
+
+        set_favorite(&sql_conn, date(2021, 1, 2), false).unwrap();
+        assert_eq!(bodies(fetch_favorites(&sql_conn).unwrap()), vec!["Older"]);
+    }
+
+    #[test]
+    fn fetch_on_this_day_finds_previous_years_only() {
+        let sql_conn = open();
+        insert_entry(&sql_conn, date(2019, 3, 4), "2019").unwrap();
+        insert_entry(&sql_conn, date(2018, 3, 4), "2018").unwrap();
+        insert_entry(&sql_conn, date(2019, 3, 5), "Another day").unwrap();
+        insert_entry(&sql_conn, date(2020, 3, 4), "Today").unwrap();
+
+        assert_eq!(
+            bodies(fetch_on_this_day(&sql_conn, date(2020, 3, 4)).unwrap()),
+            vec!["2018", "2019"]
+        );
+    }
+
+    #[test]
+    fn fetch_near_this_day_reaches_across_the_new_year() {
+        let sql_conn = open();
+        insert_entry(&sql_conn, date(2018, 12, 30), "Just before").unwrap();
+        insert_entry(&sql_conn, date(2019, 1, 3), "Just after").unwrap();
+        insert_entry(&sql_conn, date(2019, 1, 10), "Too far").unwrap();
+
+        assert_eq!(
+            bodies(fetch_near_this_day(&sql_conn, date(2020, 1, 1), 3).unwrap()),
+            vec!["Just before", "Just after"]
+        );
+    }
+
+    #[test]
+    fn fetch_near_this_day_of_an_empty_journal_is_empty() {
+        let sql_conn = open();
+        assert!(fetch_near_this_day(&sql_conn, date(2020, 1, 1), 3)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn same_day_in_turns_february_29th_into_the_28th() {
+        assert_eq!(same_day_in(date(2020, 2, 29), 2019), date(2019, 2, 28));
+        assert_eq!(same_day_in(date(2020, 2, 29), 2016), date(2016, 2, 29));
+        assert_eq!(same_day_in(date(2020, 7, 1), 2010), date(2010, 7, 1));
+    }
+
+    #[test]
+    fn fetch_between_includes_both_ends() {
+        let sql_conn = open();
+        insert_entry(&sql_conn, date(2020, 1, 31), "Before").unwrap();
+        insert_entry(&sql_conn, date(2020, 3, 1), "End").unwrap();
+        insert_entry(&sql_conn, date(2020, 2, 1), "Start").unwrap();
+        insert_entry(&sql_conn, date(2020, 3, 2), "After").unwrap();
+
+        assert_eq!(
+            bodies(fetch_between(&sql_conn, date(2020, 2, 1), date(2020, 3, 1)).unwrap()),
+            vec!["Start", "End"]
+        );
+    }
+
+    #[test]
+    fn fetch_all_and_fetch_first_date_go_by_date() {
+        let sql_conn = open();
+        assert_eq!(fetch_first_date(&sql_conn).unwrap(), None);
+
+        insert_entry(&sql_conn, date(2020, 10, 2), "Third").unwrap();
+        insert_entry(&sql_conn, date(2019, 12, 31), "First").unwrap();
+        insert_entry(&sql_conn, date(2020, 2, 10), "Second").unwrap();
+
+        assert_eq!(
+            bodies(fetch_all(&sql_conn).unwrap()),
+            vec!["First", "Second", "Third"]
+        );
+        assert_eq!(
+            fetch_first_date(&sql_conn).unwrap(),
+            Some(date(2019, 12, 31))
+        );
+    }
+
+    #[test]
+    fn search_ignores_case_and_matches_wildcards_literally() {
+        let sql_conn = open();
+        insert_entry(&sql_conn, date(2020, 1, 1), "Went to the Beach").unwrap();
+        insert_entry(&sql_conn, date(2020, 1, 2), "Got 100% on the test").unwrap();
+        insert_entry(&sql_conn, date(2020, 1, 3), "Got 100 points").unwrap();
+
+        assert_eq!(
+            bodies(search(&sql_conn, "beach").unwrap()),
+            vec!["Went to the Beach"]
+        );
+        assert_eq!(
+            bodies(search(&sql_conn, "100%").unwrap()),
+            vec!["Got 100% on the test"]
+        );
+        assert!(search(&sql_conn, "mountain").unwrap().is_empty());
+    }
+
+    /// A database from before there was one entry a day, with two entries on one day.
+    fn open_with_duplicate_day() -> (Connection, i64) {
+        let sql_conn = open();
+        sql_conn
+            .execute("DROP INDEX entries_date", NO_PARAMS)
+            .unwrap();
+        let first = insert_entry(&sql_conn, date(2020, 1, 1), "Short").unwrap();
+        let second = insert_entry(&sql_conn, date(2020, 1, 1), "The longer one").unwrap();
+        insert_entry(&sql_conn, date(2020, 1, 2), "Alone").unwrap();
+        add_tag(&sql_conn, second, "work").unwrap();
+        set_favorite(&sql_conn, date(2020, 1, 1), true).unwrap();
+        (sql_conn, first)
+    }
+
+    #[test]
+    fn merge_duplicate_days_concatenates_into_the_oldest() {
+        let (sql_conn, first) = open_with_duplicate_day();
+        assert_eq!(count_duplicate_days(&sql_conn).unwrap(), 1);
+
+        let merged = merge_duplicate_days(&sql_conn, DayMerge::Concatenate, "\n---\n").unwrap();
+        assert_eq!(merged, 1);
+        assert_eq!(count_duplicate_days(&sql_conn).unwrap(), 0);
+
+        let entry = fetch_by_date(&sql_conn, date(2020, 1, 1)).unwrap().unwrap();
+        assert_eq!(entry.id, first);
+        assert_eq!(entry.body, "Short\n---\nThe longer one");
+        assert!(entry.is_favorite);
+        assert_eq!(fetch_tags(&sql_conn, first).unwrap(), vec!["work"]);
+    }
+
+    fn has_date_index(sql_conn: &Connection) -> bool {
+        sql_conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'entries_date'",
+                NO_PARAMS,
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+            > 0
+    }
+
+    #[test]
+    fn the_one_entry_a_day_index_waits_until_the_duplicates_are_merged() {
+        let (sql_conn, _) = open_with_duplicate_day();
+
+        create_table(&sql_conn).unwrap();
+        assert!(!has_date_index(&sql_conn));
+        assert!(insert_entry(&sql_conn, date(2020, 1, 2), "Still allowed").is_ok());
+
+        merge_duplicate_days(&sql_conn, DayMerge::Concatenate, "\n").unwrap();
+        create_table(&sql_conn).unwrap();
+        assert!(has_date_index(&sql_conn));
+        assert!(insert_entry(&sql_conn, date(2020, 1, 2), "One too many").is_err());
+    }
+
+    #[test]
+    fn merge_duplicate_days_can_keep_the_longest() {
+        let (sql_conn, _) = open_with_duplicate_day();
+        merge_duplicate_days(&sql_conn, DayMerge::KeepLongest, "").unwrap();
+
+        let entry = fetch_by_date(&sql_conn, date(2020, 1, 1)).unwrap().unwrap();
+        assert_eq!(entry.body, "The longer one");
+        assert_eq!(bodies(fetch_all(&sql_conn).unwrap()).len(), 2);
+    }
+}
@@ -0,0 +1,51 @@
+//! The building blocks of Mail Journal. The binary wires these together into the
+//! daemon and its command line tools.
+
+/// Like `println!`, but when several mailboxes are configured, starts the line with
+/// the name of the mailbox it's about.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        let line = format!("{}{}", $crate::log_tag(), format_args!($($arg)*));
+        println!("{}", line);
+        $crate::logging::write_line(&line);
+    }};
+}
+
+/// Like `log!`, but for errors, which go to stderr.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {{
+        let line = format!("{}{}", $crate::log_tag(), format_args!($($arg)*));
+        eprintln!("{}", line);
+        $crate::logging::write_line(&line);
+    }};
+}
+
+pub mod archive;
+pub mod body;
+pub mod command;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod error;
+pub mod export;
+pub mod hook;
+pub mod inbox;
+pub mod journal;
+pub mod logging;
+pub mod mail;
+pub mod schedule;
+pub mod storage;
+pub mod store;
+#[cfg(test)]
+mod test_util;
+
+/// Each mailbox is run on a thread named after it, so the thread's name is the tag.
+#[doc(hidden)]
+pub fn log_tag() -> String {
+    match std::thread::current().name() {
+        Some(name) if name != "main" => format!("[{}] ", name),
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,163 @@
+//! Copying log lines into a log file, for servers where nobody is watching the
+//! console. Once the file grows past `log_max_size` it's rotated, so
+//! `mail-journal.log` becomes `mail-journal.log.1`, `.1` becomes `.2`, and so on,
+//! keeping `log_keep_files` of the old files.
+
+use chrono::prelude::*;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    keep_files: usize,
+}
+
+/// Held while a line is written, so lines from different mailbox threads are
+/// never mixed up, and none are written while the file is being rotated.
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Starts copying every log line into the file at `path`.
+pub fn init(path: &str, max_size: u64, keep_files: usize) {
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(LogFile {
+        path: PathBuf::from(path),
+        max_size,
+        keep_files,
+    });
+}
+
+/// Writes a line to the log file, if there is one. A line that can't be written
+/// still made it to the console, so that's only mentioned there.
+#[doc(hidden)]
+pub fn write_line(line: &str) {
+    let log_file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(log_file) = log_file.as_ref() {
+        if let Err(e) = log_file.write_line(line) {
+            eprintln!(
+                "Failed to write to the log file {}: {}",
+                log_file.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl LogFile {
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let line = format!("{} {}\n", Utc::now().format("%Y-%m-%d %H:%M:%S"), line);
+
+        // The file is opened afresh for every line, rather than kept open, so if
+        // it's deleted or moved out from under us a new one is simply started
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// Moves each old log file one number up, dropping the oldest, and the current
+    /// one to `.1`.
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep_files == 0 {
+            return ignore_not_found(fs::remove_file(&self.path));
+        }
+
+        ignore_not_found(fs::remove_file(self.rotated_path(self.keep_files)))?;
+        for number in (1..self.keep_files).rev() {
+            ignore_not_found(fs::rename(
+                self.rotated_path(number),
+                self.rotated_path(number + 1),
+            ))?;
+        }
+
+        ignore_not_found(fs::rename(&self.path, self.rotated_path(1)))
+    }
+
+    fn rotated_path(&self, number: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// The messages in a log file, without their timestamps.
+    fn messages(path: PathBuf) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| line[20..].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn a_full_log_file_is_rotated_keeping_log_keep_files() {
+        let dir = TempDir::new("log_rotation");
+        // Room for two lines, of 27 bytes with their timestamps
+        let log_file = LogFile {
+            path: dir.path().join("mail-journal.log"),
+            max_size: 60,
+            keep_files: 2,
+        };
+
+        for number in 1..=7 {
+            log_file.write_line(&format!("line {}", number)).unwrap();
+        }
+
+        assert_eq!(messages(log_file.path.clone()), vec!["line 7"]);
+        assert_eq!(messages(log_file.rotated_path(1)), vec!["line 5", "line 6"]);
+        assert_eq!(messages(log_file.rotated_path(2)), vec!["line 3", "line 4"]);
+        assert!(!log_file.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn a_deleted_log_file_is_started_again() {
+        let dir = TempDir::new("log_deleted");
+        let log_file = LogFile {
+            path: dir.path().join("mail-journal.log"),
+            max_size: 1024,
+            keep_files: 2,
+        };
+
+        log_file.write_line("before").unwrap();
+        fs::remove_file(&log_file.path).unwrap();
+        log_file.write_line("after").unwrap();
+
+        assert_eq!(messages(log_file.path.clone()), vec!["after"]);
+        assert!(!log_file.rotated_path(1).exists());
+    }
+
+    #[test]
+    fn with_no_files_to_keep_a_full_log_file_is_started_over() {
+        let dir = TempDir::new("log_keep_none");
+        let log_file = LogFile {
+            path: dir.path().join("mail-journal.log"),
+            max_size: 30,
+            keep_files: 0,
+        };
+
+        log_file.write_line("first").unwrap();
+        log_file.write_line("second").unwrap();
+
+        assert_eq!(messages(log_file.path.clone()), vec!["second"]);
+        assert!(!log_file.rotated_path(1).exists());
+    }
+}
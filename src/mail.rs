@@ -0,0 +1,2188 @@
+//! Receiving journal entries over IMAP, and sending mail over SMTP.
+
+use crate::config::{Config, ImapProvider};
+use crate::error::{self, Error};
+use chrono::prelude::*;
+use imap::types::{Fetch, Uid};
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::client::net::NetworkStream;
+use lettre::smtp::client::Client as SmtpClient;
+use lettre::smtp::commands::{EhloCommand, StarttlsCommand};
+use lettre::smtp::extension::{ClientId, Extension, ServerInfo};
+#[cfg(not(test))]
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::smtp::SUBMISSION_PORT;
+use lettre::{ClientTlsParameters, EmailAddress, Envelope};
+#[cfg(not(test))]
+use lettre::{EmailTransport, SmtpTransport};
+use lettre_email::{EmailBuilder, IntoMailbox};
+use mailparse::*;
+use mime::Mime;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+pub struct Email {
+    pub message_id: Option<String>,
+    pub from: String,
+    pub reply_to: String,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub timestamp: DateTime<Utc>,
+    pub body: String,
+    pub attachments: Vec<Attachment>,
+    /// Set if the email is a delivery report, saying that mail we sent bounced
+    pub bounce: Option<Bounce>,
+    /// Set if the email says it was sent automatically, like by a vacation responder
+    pub is_automatic: bool,
+    /// The mailbox the email was fetched from, and its UID there
+    pub source: Option<(String, Uid)>,
+    /// Set to its size in bytes if the email was over `max_email_bytes`. Only its
+    /// headers were fetched then, so it has no body or attachments.
+    pub too_large: Option<u32>,
+}
+
+/// A delivery report for mail that couldn't be delivered.
+pub struct Bounce {
+    /// The subject of the email that bounced, if the report includes its headers
+    pub subject: Option<String>,
+}
+
+pub struct Attachment {
+    pub filename: String,
+    pub mimetype: String,
+    pub data: Vec<u8>,
+}
+
+impl Email {
+    pub fn from_bytes(bytes: &[u8]) -> error::Result<Email> {
+        // mailparse keeps the CR of a CRLF line ending on header values, so the
+        // Content-Transfer-Encoding of a part reads as "base64\r" and its body is
+        // never decoded. Parsing with bare LF line endings sidesteps that.
+        let bytes = lf_line_endings(bytes);
+        let parsed = parse_mail(&bytes)?;
+
+        // Header values come decoded from any RFC 2047 encoded words, like the
+        // =?UTF-8?B?...?= that non-ASCII subjects and names are sent as, but can
+        // have stray whitespace
+        let header = |name: &str| -> error::Result<Option<String>> {
+            Ok(parsed
+                .headers
+                .get_first_value(name)?
+                .map(|v| v.trim().to_string()))
+        };
+        let required_header = |name: &str| {
+            header(name)?.ok_or_else(|| Error::Parse(format!("the email has no {} header", name)))
+        };
+
+        let message_id = header("Message-ID")?;
+        let from = required_header("From")?;
+
+        // Replies go to the Reply-To address if the sender set one, otherwise back to From
+        let reply_to = header("Reply-To")?
+            .map(|v| extract_address(&v))
+            .unwrap_or_else(|| extract_address(&from));
+        let subject = header("Subject")?.unwrap_or_default();
+
+        // Every address the email was delivered to, so we can tell which of our
+        // addresses (e.g. a plus address) it was sent to
+        let mut values = Vec::new();
+        for name in &["To", "Cc", "Delivered-To", "X-Original-To"] {
+            values.extend(parsed.headers.get_all_values(name)?);
+        }
+        let recipients: Vec<String> = values
+            .iter()
+            .flat_map(|value| {
+                split_addresses(value)
+                    .into_iter()
+                    .map(extract_address)
+                    .filter(|address| !address.is_empty())
+                    .collect::<Vec<String>>()
+            })
+            .collect();
+
+        let timestamp_rfc2882 = required_header("Date")?;
+        let timestamp: DateTime<Utc> = DateTime::parse_from_rfc2822(&timestamp_rfc2882)
+            .map_err(|e| {
+                Error::Parse(format!("the email's Date \"{}\": {}", timestamp_rfc2882, e))
+            })?
+            .with_timezone(&Utc);
+
+        let body = extract_body(&parsed)?;
+
+        let mut attachments = Vec::new();
+        collect_attachments(std::slice::from_ref(&parsed), &mut attachments);
+
+        // RFC 3834 says anything but "no" is automatic, and Precedence is the older,
+        // unofficial way of saying so that plenty of responders still use
+        let auto_submitted = header("Auto-Submitted")?.unwrap_or_default();
+        let precedence = header("Precedence")?.unwrap_or_default();
+        let is_automatic = (!auto_submitted.is_empty()
+            && !auto_submitted.eq_ignore_ascii_case("no"))
+            || ["bulk", "junk", "auto_reply"]
+                .iter()
+                .any(|value| precedence.eq_ignore_ascii_case(value));
+
+        let bounce = if is_delivery_report(&parsed, &from) {
+            Some(Bounce {
+                subject: bounced_subject(&parsed.subparts),
+            })
+        } else {
+            None
+        };
+
+        Ok(Email {
+            message_id,
+            from,
+            reply_to,
+            recipients,
+            subject,
+            timestamp,
+            body,
+            attachments,
+            bounce,
+            is_automatic,
+            source: None,
+            too_large: None,
+        })
+    }
+
+    /// Checks whether the email was sent from the given address, however the sender's
+    /// client formatted the From header.
+    pub fn is_from(&self, address: &str) -> bool {
+        same_address(&extract_address(&self.from), address)
+    }
+
+    /// Checks whether the email was sent from any address at the given domain, like
+    /// `example.com`.
+    pub fn is_from_domain(&self, domain: &str) -> bool {
+        extract_address(&self.from)
+            .rsplit_once('@')
+            .is_some_and(|(_, from_domain)| same_address(from_domain, domain))
+    }
+}
+
+/// Turns every CRLF into a bare LF.
+fn lf_line_endings(bytes: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(bytes.len());
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte != b'\r' || bytes.get(i + 1) != Some(&b'\n') {
+            converted.push(byte);
+        }
+    }
+    converted
+}
+
+/// Gets the text of an email.
+fn extract_body(parsed: &ParsedMail) -> error::Result<String> {
+    // Prefer the plaintext alternative, and only fall back to converting
+    // the HTML part when the client didn't send one. The search starts at the
+    // top level, so a single-part email is handled the same way.
+    let parts = std::slice::from_ref(parsed);
+    let body = if let Some(part) = find_body_part(parts, "text/plain") {
+        Some(part.get_body()?)
+    } else if let Some(part) = find_body_part(parts, "text/html") {
+        Some(html_to_text(&part.get_body()?))
+    } else {
+        None
+    };
+
+    if let Some(body) = &body {
+        if !body.trim().is_empty() {
+            return Ok(body.clone());
+        }
+    }
+
+    // Clients that "forward as attachment" can send nothing but the forwarded
+    // message, so that's where the text is
+    let embedded = find_embedded_message(parts)
+        .and_then(|part| part.get_body_raw().ok())
+        .map(|raw| {
+            match parse_mail(&raw)
+                .map_err(Error::from)
+                .and_then(|m| extract_body(&m))
+            {
+                Ok(body) => body,
+                Err(e) => {
+                    log_error!("Failed to parse forwarded message: {}", e);
+                    String::new()
+                }
+            }
+        });
+    if let Some(embedded) = embedded {
+        if !embedded.trim().is_empty() {
+            return Ok(embedded);
+        }
+    }
+
+    // Without a text part, like an email with only a photo, there's no text at all,
+    // rather than an attachment read as if it were
+    Ok(body.unwrap_or_default())
+}
+
+/// Checks whether an email is a delivery report (RFC 3464), or at least comes from
+/// the kind of address that sends them, for servers that don't follow the RFC.
+fn is_delivery_report(parsed: &ParsedMail, from: &str) -> bool {
+    let is_report = parsed.ctype.mimetype == "multipart/report"
+        && parsed
+            .ctype
+            .params
+            .get("report-type")
+            .is_some_and(|report_type| report_type.eq_ignore_ascii_case("delivery-status"));
+
+    let sender = extract_address(from).to_lowercase();
+    let local_part = sender.split('@').next().unwrap_or_default();
+
+    is_report || local_part == "mailer-daemon" || local_part == "postmaster"
+}
+
+/// Gets the subject of the bounced email from a delivery report, which includes
+/// either the whole email or just its headers.
+fn bounced_subject(parts: &[ParsedMail]) -> Option<String> {
+    for part in parts {
+        if part.ctype.mimetype.starts_with("multipart/") {
+            if let Some(subject) = bounced_subject(&part.subparts) {
+                return Some(subject);
+            }
+        } else if part.ctype.mimetype == "message/rfc822"
+            || part.ctype.mimetype == "text/rfc822-headers"
+        {
+            let raw = part.get_body_raw().ok()?;
+            let (headers, _) = parse_headers(&raw).ok()?;
+            return headers
+                .get_first_value("Subject")
+                .ok()
+                .flatten()
+                .map(|subject| subject.trim().to_string());
+        }
+    }
+
+    None
+}
+
+/// Searches the (possibly nested) subparts of an email for a whole email embedded
+/// in it, attached or not.
+fn find_embedded_message<'a, 'b>(parts: &'b [ParsedMail<'a>]) -> Option<&'b ParsedMail<'a>> {
+    for part in parts {
+        if part.ctype.mimetype.starts_with("multipart/") {
+            if let Some(found) = find_embedded_message(&part.subparts) {
+                return Some(found);
+            }
+        } else if part.ctype.mimetype == "message/rfc822" {
+            return Some(part);
+        }
+    }
+
+    None
+}
+
+/// Gathers every attached file from the (possibly nested) subparts of an email.
+/// This includes the resources of a `multipart/related` part, like the images
+/// shown inline in an HTML body, whether or not they have a filename.
+fn collect_attachments(parts: &[ParsedMail], attachments: &mut Vec<Attachment>) {
+    for part in parts {
+        if part.ctype.mimetype == "multipart/related" {
+            let root = related_root(part);
+            for subpart in &part.subparts {
+                if root.is_some_and(|root| std::ptr::eq(root, subpart))
+                    || subpart.ctype.mimetype.starts_with("multipart/")
+                {
+                    collect_attachments(std::slice::from_ref(subpart), attachments);
+                } else {
+                    let filename = part_filename(subpart).unwrap_or_else(|| {
+                        let extension = subpart.ctype.mimetype.split('/').nth(1).unwrap_or("bin");
+                        format!("inline-{}.{}", attachments.len() + 1, extension)
+                    });
+                    push_attachment(subpart, filename, attachments);
+                }
+            }
+            continue;
+        }
+
+        if part.ctype.mimetype.starts_with("multipart/") {
+            collect_attachments(&part.subparts, attachments);
+            continue;
+        }
+
+        let disposition = part.get_content_disposition().unwrap_or_default();
+        let filename = part_filename(part);
+
+        // Inline parts are only attachments if they are named files, like a pasted
+        // image, rather than one of the body alternatives
+        let is_attachment = match disposition.disposition {
+            DispositionType::Attachment => true,
+            _ => filename.is_some() && !part.ctype.mimetype.starts_with("text/"),
+        };
+
+        if is_attachment {
+            let filename =
+                filename.unwrap_or_else(|| format!("attachment-{}", attachments.len() + 1));
+            push_attachment(part, filename, attachments);
+        }
+    }
+}
+
+fn part_filename(part: &ParsedMail) -> Option<String> {
+    let disposition = part.get_content_disposition().unwrap_or_default();
+    disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+}
+
+fn push_attachment(part: &ParsedMail, filename: String, attachments: &mut Vec<Attachment>) {
+    match part.get_body_raw() {
+        Ok(data) => attachments.push(Attachment {
+            filename,
+            mimetype: part.ctype.mimetype.clone(),
+            data,
+        }),
+        Err(e) => log_error!("Failed to decode attachment: {}", e),
+    }
+}
+
+/// Gets the root of a `multipart/related` part, which the other parts are the
+/// resources of. That's the part its `start` parameter names by Content-ID, or
+/// the first part if there's no `start`.
+fn related_root<'a, 'b>(related: &'b ParsedMail<'a>) -> Option<&'b ParsedMail<'a>> {
+    let content_id = |part: &ParsedMail| {
+        part.headers
+            .get_first_value("Content-ID")
+            .ok()
+            .flatten()
+            .map(|id| {
+                id.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    };
+
+    let start = related
+        .ctype
+        .params
+        .get("start")
+        .map(|start| start.trim().trim_start_matches('<').trim_end_matches('>'));
+
+    start
+        .and_then(|start| {
+            related
+                .subparts
+                .iter()
+                .find(|part| content_id(part).as_deref() == Some(start))
+        })
+        .or_else(|| related.subparts.first())
+}
+
+/// Searches the (possibly nested) subparts of an email for the first inline part
+/// with the given mimetype.
+fn find_body_part<'a, 'b>(
+    parts: &'b [ParsedMail<'a>],
+    mimetype: &str,
+) -> Option<&'b ParsedMail<'a>> {
+    for part in parts {
+        // The body of a multipart/related is its root, the rest are its resources
+        if part.ctype.mimetype == "multipart/related" {
+            let root = related_root(part)
+                .map(std::slice::from_ref)
+                .unwrap_or_default();
+            if let Some(found) = find_body_part(root, mimetype) {
+                return Some(found);
+            }
+        } else if part.ctype.mimetype.starts_with("multipart/") {
+            if let Some(found) = find_body_part(&part.subparts, mimetype) {
+                return Some(found);
+            }
+        } else if part.ctype.mimetype == mimetype {
+            let disposition = part.get_content_disposition().unwrap_or_default();
+            if disposition.disposition != DispositionType::Attachment {
+                return Some(part);
+            }
+        }
+    }
+
+    None
+}
+
+/// Converts an HTML email body into readable plaintext. This isn't a real HTML
+/// parser, but it copes with the kind of markup mail clients produce: block
+/// elements become line breaks, links keep their target, and entities are decoded.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut link: Option<String> = None;
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        push_html_text(&mut text, &rest[..start]);
+
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/' && !tag.starts_with('/'))
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+
+        match name.as_str() {
+            "br" => text.push('\n'),
+            "p" | "/p" | "div" | "/div" | "blockquote" | "/blockquote" | "ul" | "/ul" | "ol"
+            | "/ol" | "table" | "/table" | "h1" | "/h1" | "h2" | "/h2" | "h3" | "/h3" | "h4"
+            | "/h4" | "h5" | "/h5" | "h6" | "/h6" => text.push_str("\n\n"),
+            "li" => text.push_str("\n- "),
+            "tr" | "/tr" => text.push('\n'),
+            "a" => link = html_attribute(tag, "href"),
+            "/a" => {
+                if let Some(href) = link.take() {
+                    let href = href.trim_start_matches("mailto:");
+                    if !text.trim_end().ends_with(href) {
+                        text.push_str(&format!(" ({})", href));
+                    }
+                }
+            }
+            "script" | "style" | "head" => {
+                // Skip everything up to the matching closing tag
+                let closing = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&closing) {
+                    Some(ix) => &rest[ix..],
+                    None => "",
+                };
+            }
+            _ => {}
+        }
+    }
+
+    push_html_text(&mut text, rest);
+
+    // Tidy up the whitespace left behind by the markup, keeping at most one
+    // blank line between paragraphs
+    let mut result = String::new();
+    let mut blank_lines = 0;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+
+        if !result.is_empty() {
+            result.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+        }
+
+        result.push_str(line);
+        blank_lines = 0;
+    }
+
+    result
+}
+
+/// Appends a run of HTML text content, collapsing whitespace the way a browser
+/// would and decoding any entities.
+fn push_html_text(text: &mut String, html: &str) {
+    let collapsed = html.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if collapsed.is_empty() {
+        if !html.is_empty() && !text.ends_with(char::is_whitespace) {
+            text.push(' ');
+        }
+        return;
+    }
+
+    if html.starts_with(char::is_whitespace) && !text.ends_with(char::is_whitespace) {
+        text.push(' ');
+    }
+
+    text.push_str(&decode_html_entities(&collapsed));
+
+    if html.ends_with(char::is_whitespace) {
+        text.push(' ');
+    }
+}
+
+/// Gets the value of an attribute from the inside of an HTML tag, e.g. the
+/// `href` of `a href="https://example.com"`.
+fn html_attribute(tag: &str, attribute: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let ix = lower.find(&format!("{}=", attribute))? + attribute.len() + 1;
+    let value = &tag[ix..];
+
+    let value = match value.chars().next()? {
+        quote @ '"' | quote @ '\'' => value[1..].split(quote).next()?,
+        _ => value.split(char::is_whitespace).next()?,
+    };
+
+    Some(decode_html_entities(value))
+}
+
+/// Decodes the named entities that commonly show up in emails, plus numeric
+/// character references. Anything unrecognized is left as-is.
+fn decode_html_entities(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                result.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..]
+                .parse::<u32>()
+                .ok()
+                .and_then(std::char::from_u32),
+            _ => None,
+        };
+
+        match decoded {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+pub fn send_error_email(config: &Config, to: &str, msg: &str) {
+    send_reply(config, to, "Error", msg);
+}
+
+/// Sends a reply to an incoming email, such as a command response. Unlike the
+/// daily reminder, replies go to whoever sent the email, so the address is
+/// checked before anything is sent.
+pub fn send_reply(config: &Config, to: &str, subject: &str, body: &str) {
+    send_reply_with_attachment(config, to, subject, body, None);
+}
+
+/// Like `send_reply`, but can attach a file. Returns whether the reply was sent.
+pub fn send_reply_with_attachment(
+    config: &Config,
+    to: &str,
+    subject: &str,
+    body: &str,
+    attachment: Option<&Path>,
+) -> bool {
+    if !is_valid_address(to) {
+        log_error!(
+            "Not sending \"{}\" email to invalid address \"{}\"",
+            subject,
+            to
+        );
+        return false;
+    }
+
+    // A reply that can't be sent isn't worth stopping for, the entry or command it
+    // answers has already been taken care of
+    match send_email(config, to, subject, body, None, &[], attachment) {
+        Ok(()) => true,
+        Err(e) => {
+            log_error!("Failed to send \"{}\" email to {}: {}", subject, to, e);
+            false
+        }
+    }
+}
+
+pub fn send_email<A: IntoMailbox>(
+    config: &Config,
+    to: A,
+    subject: &str,
+    body: &str,
+    html: Option<&str>,
+    cc: &[String],
+    attachment: Option<&Path>,
+) -> error::Result<()> {
+    // Without SMTPUTF8, headers have to be plain ASCII, so anything else is sent
+    // as RFC 2047 encoded words instead
+    let smtp_utf8 = smtp_supports_utf8(config);
+    let encode = |value: &str| {
+        if smtp_utf8 {
+            value.to_string()
+        } else {
+            encode_header_words(value)
+        }
+    };
+
+    // Subjects can include text from incoming emails, so make sure nothing in them
+    // can end the header early. The body is escaped by the builder.
+    let to = to.into_mailbox();
+    let mut builder = EmailBuilder::new()
+        .to(to.clone())
+        .from((
+            config.journal_email.clone(),
+            encode(&config.journal_display_name),
+        ))
+        .subject(encode(&sanitize_header(subject)))
+        // So vacation responders and the like know not to answer us
+        .header(("Auto-Submitted", "auto-generated"));
+
+    // The plain text always goes along too, for clients that don't show HTML
+    builder = match html {
+        Some(html) => builder.alternative(html, body),
+        None => builder.text(body),
+    };
+
+    for address in cc {
+        builder = builder.cc(address.as_str());
+    }
+
+    // Otherwise the envelope is worked out from the headers, sent from journal_email
+    if let Some(envelope_from) = &config.smtp_envelope_from {
+        let recipients = std::iter::once(to.address)
+            .chain(cc.iter().cloned())
+            .map(EmailAddress::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        builder = builder.envelope(Envelope::new(
+            Some(EmailAddress::new(envelope_from.clone())?),
+            recipients,
+        )?);
+    }
+
+    if let Some(path) = attachment {
+        let content_type = mime_guess(path)
+            .parse::<Mime>()
+            .map_err(|e| Error::Smtp(e.to_string()))?;
+        builder = builder.attachment(path, None, &content_type)?;
+    }
+
+    let email = builder.build()?;
+    deliver(config, &email, smtp_utf8)
+}
+
+/// Hands a built email over to the SMTP server.
+#[cfg(not(test))]
+fn deliver(config: &Config, email: &lettre_email::Email, smtp_utf8: bool) -> error::Result<()> {
+    let mut mailer = SmtpTransport::simple_builder(&config.journal_email_smtp)?
+        .hello_name(ClientId::Domain(config.journal_email_smtp.clone()))
+        .credentials(Credentials::new(
+            config.journal_email.clone(),
+            config.journal_email_password.clone(),
+        ))
+        // Only ask for SMTPUTF8 if the server said it supports it
+        .smtp_utf8(smtp_utf8)
+        // Configure expected authentication mechanism
+        .authentication_mechanism(config.smtp_auth_mechanism.mechanism())
+        // Enable connection reuse
+        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
+        .build();
+
+    let result = mailer.send(email);
+
+    // Explicitly close the SMTP transaction as we enabled connection reuse
+    mailer.close();
+
+    result?;
+    Ok(())
+}
+
+/// The tests never reach an SMTP server. What would have been sent is kept in the
+/// test's outbox instead, for it to look at.
+#[cfg(test)]
+fn deliver(_config: &Config, email: &lettre_email::Email, _smtp_utf8: bool) -> error::Result<()> {
+    crate::test_util::record_sent_email(email);
+    Ok(())
+}
+
+/// Works out the type of a file we're about to attach, from its extension.
+fn mime_guess(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether each SMTP server we've asked supports SMTPUTF8, so it's only asked once
+/// rather than on a connection of its own for every email. Mailboxes can send
+/// through different servers, so they're kept apart by host.
+static SMTP_UTF8_SUPPORT: Mutex<BTreeMap<String, bool>> = Mutex::new(BTreeMap::new());
+
+/// Asks the SMTP server whether it supports SMTPUTF8. If we can't tell, we assume it
+/// doesn't, since encoding the headers works either way.
+fn smtp_supports_utf8(config: &Config) -> bool {
+    cached_utf8_support(&config.journal_email_smtp, || probe_smtp_utf8(config))
+}
+
+/// Connects to the SMTP server to see whether it supports SMTPUTF8, or `None` if it
+/// can't be reached.
+#[cfg(not(test))]
+fn probe_smtp_utf8(config: &Config) -> Option<bool> {
+    let mut client: SmtpClient<NetworkStream> = SmtpClient::new();
+    let result = smtp_handshake(config, &mut client);
+    client.close();
+
+    match result {
+        Ok(server_info) => Some(server_info.supports_feature(Extension::SmtpUtfEight)),
+        Err(e) => {
+            log!("Couldn't check whether the SMTP server supports SMTPUTF8 ({}), assuming it doesn't", e);
+            None
+        }
+    }
+}
+
+/// The tests' outbox takes anything.
+#[cfg(test)]
+fn probe_smtp_utf8(_config: &Config) -> Option<bool> {
+    Some(true)
+}
+
+/// Looks up whether `server` supports SMTPUTF8, asking it with `probe` the first
+/// time. A `probe` that can't tell isn't remembered, so the server is asked again
+/// next time.
+fn cached_utf8_support(server: &str, probe: impl FnOnce() -> Option<bool>) -> bool {
+    if let Some(&supported) = SMTP_UTF8_SUPPORT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(server)
+    {
+        return supported;
+    }
+
+    // Not held while probing, since that's a round trip to the server
+    match probe() {
+        Some(supported) => {
+            SMTP_UTF8_SUPPORT
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(server.to_string(), supported);
+            supported
+        }
+        None => false,
+    }
+}
+
+/// Encodes a header value as RFC 2047 encoded words, if it isn't plain ASCII. Words
+/// are kept within the 75 character limit, and never split a character.
+fn encode_header_words(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+
+    const PREFIX: &str = "=?UTF-8?Q?";
+    const SUFFIX: &str = "?=";
+    const MAX_ENCODED_LEN: usize = 75 - PREFIX.len() - SUFFIX.len();
+
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let encoded = match c {
+            ' ' => "_".to_string(),
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '!' | '*' | '+' | '-' | '/' => c.to_string(),
+            _ => {
+                let mut buf = [0; 4];
+                c.encode_utf8(&mut buf)
+                    .bytes()
+                    .map(|b| format!("={:02X}", b))
+                    .collect()
+            }
+        };
+
+        if word.len() + encoded.len() > MAX_ENCODED_LEN {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+
+    words
+        .iter()
+        .map(|word| format!("{}{}{}", PREFIX, word, SUFFIX))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Makes a string safe to use as a header value by replacing any line breaks,
+/// which would otherwise let it add headers of its own, with spaces.
+fn sanitize_header(value: &str) -> String {
+    value
+        .split(['\r', '\n'])
+        .filter(|line| !line.trim().is_empty())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Pulls the bare address out of a header value like `Name <addr@example.com>`,
+/// `"Smith, John" <addr@example.com>`, `addr@example.com (Name)` or just
+/// `addr@example.com`.
+pub fn extract_address(header_value: &str) -> String {
+    // Quoted names and comments can contain anything, angle brackets included, so
+    // they're dropped before looking for the address
+    let mut unquoted = String::new();
+    let mut in_quotes = false;
+    let mut comment_depth = 0;
+    let mut escaped = false;
+    for c in header_value.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' if in_quotes || comment_depth > 0 => escaped = true,
+            '"' if comment_depth == 0 => in_quotes = !in_quotes,
+            '(' if !in_quotes => comment_depth += 1,
+            ')' if !in_quotes && comment_depth > 0 => comment_depth -= 1,
+            _ if in_quotes || comment_depth > 0 => {}
+            c => unquoted.push(c),
+        }
+    }
+
+    // The address is in the last pair of angle brackets, since a name that was sent
+    // encoded isn't quoted once it's decoded, and could have brackets of its own
+    let value = unquoted.trim();
+    match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => value[start + 1..end].trim().to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Splits a header listing several addresses at its commas, except for commas inside
+/// a quoted name like `"Smith, John" <addr@example.com>`.
+fn split_addresses(header_value: &str) -> Vec<&str> {
+    let mut addresses = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in header_value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                addresses.push(&header_value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    addresses.push(&header_value[start..]);
+
+    addresses
+}
+
+/// Compares two bare addresses. Mail servers treat addresses as case-insensitive
+/// in practice, so this does too.
+pub fn same_address(a: &str, b: &str) -> bool {
+    a.trim().to_lowercase() == b.trim().to_lowercase()
+}
+
+/// Gets the tag from a plus address, like `work` from `journal+work@example.com`,
+/// if `recipient` is a plus address of `address`.
+pub fn plus_tag(address: &str, recipient: &str) -> Option<String> {
+    let (local, domain) = address.split_at(address.rfind('@')?);
+    let (recipient_local, recipient_domain) = recipient.split_at(recipient.rfind('@')?);
+    if !domain.eq_ignore_ascii_case(recipient_domain) {
+        return None;
+    }
+
+    let (base, tag) = recipient_local.split_at(recipient_local.find('+')?);
+    let tag = tag[1..].trim().to_lowercase();
+    if base.eq_ignore_ascii_case(local) && !tag.is_empty() {
+        Some(tag)
+    } else {
+        None
+    }
+}
+
+/// A deliberately loose sanity check, just enough to catch garbage or header
+/// injection attempts before we hand an address to the mailer.
+pub fn is_valid_address(address: &str) -> bool {
+    let mut parts = address.split('@');
+    let (local, domain) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => (local, domain),
+        _ => return false,
+    };
+
+    !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !address
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+}
+
+/// The mailboxes searched for new journal entries. On Gmail, mail that skipped the
+/// inbox (because of a filter, say) can optionally be picked up from All Mail too.
+fn mailboxes(config: &Config) -> Vec<&'static str> {
+    let is_gmail = match config.imap_provider {
+        ImapProvider::Auto => {
+            let host = config.journal_email_imap.to_lowercase();
+            host.ends_with("gmail.com") || host.ends_with("googlemail.com")
+        }
+        ImapProvider::Generic => false,
+        ImapProvider::Gmail => true,
+    };
+
+    if is_gmail && config.gmail_search_all_mail {
+        vec!["INBOX", GMAIL_ALL_MAIL]
+    } else {
+        vec!["INBOX"]
+    }
+}
+
+const GMAIL_ALL_MAIL: &str = "[Gmail]/All Mail";
+
+type ImapSession = imap::Session<native_tls::TlsStream<std::net::TcpStream>>;
+
+/// Why talking to the IMAP server failed.
+#[derive(Debug)]
+pub enum ImapError {
+    /// The server turned down our username and password
+    Login(imap::error::Error),
+    /// Anything else, like the connection dropping, which may well fix itself
+    Other(imap::error::Error),
+}
+
+impl From<imap::error::Error> for ImapError {
+    fn from(e: imap::error::Error) -> ImapError {
+        ImapError::Other(e)
+    }
+}
+
+impl From<native_tls::Error> for ImapError {
+    fn from(e: native_tls::Error) -> ImapError {
+        ImapError::Other(e.into())
+    }
+}
+
+impl std::fmt::Display for ImapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ImapError::Login(e) => write!(f, "failed to log in: {}", e),
+            ImapError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Connects to the IMAP server and logs in.
+fn imap_login(config: &Config) -> Result<ImapSession, ImapError> {
+    let domain = config.journal_email_imap.as_str();
+    let tls = native_tls::TlsConnector::builder().build()?;
+
+    let client = imap::connect((domain, 993), imap_tls_hostname(config), &tls)?;
+    log_in(config, client)
+}
+
+/// Logs in over a connection to the IMAP server. Only the server answering `NO` or
+/// `BAD` to the login counts as it rejecting us.
+fn log_in<T: Read + Write>(
+    config: &Config,
+    client: imap::Client<T>,
+) -> Result<imap::Session<T>, ImapError> {
+    client
+        .login(&config.journal_email, &config.journal_email_password)
+        .map_err(|(e, _)| match e {
+            imap::error::Error::No(_) | imap::error::Error::Bad(_) => ImapError::Login(e),
+            e => ImapError::Other(e),
+        })
+}
+
+/// The name the IMAP server's certificate is checked against (and sent as SNI),
+/// which is the host we connect to unless `imap_tls_hostname` says otherwise.
+fn imap_tls_hostname(config: &Config) -> &str {
+    config
+        .imap_tls_hostname
+        .as_deref()
+        .unwrap_or(&config.journal_email_imap)
+}
+
+/// Picks the bodies (or with `Fetch::header`, the headers) of the messages we asked
+/// for out of the server's answer to a UID FETCH, along with the UIDs it didn't send
+/// one for. A server can split its answer about one message over several FETCH
+/// responses, like the message in one and its flags in another, and can mix in
+/// FETCH responses we didn't ask for, like flag changes to other messages, which
+/// don't always have a UID. So each response is matched up by its UID, and only the
+/// first body for each UID is taken.
+fn fetched_bodies<'a>(
+    fetched: &'a [Fetch],
+    requested: &[Uid],
+    part: fn(&Fetch) -> Option<&[u8]>,
+) -> (Vec<(Uid, &'a [u8])>, BTreeSet<Uid>) {
+    let mut missing: BTreeSet<Uid> = requested.iter().cloned().collect();
+    let mut bodies = Vec::new();
+
+    for m in fetched {
+        let uid = match m.uid {
+            Some(uid) => uid,
+            None => continue,
+        };
+
+        if let Some(body) = part(m) {
+            if missing.remove(&uid) {
+                bodies.push((uid, body));
+            }
+        }
+    }
+
+    (bodies, missing)
+}
+
+/// Splits the UIDs to fetch into batches of at most `batch_size`, fetched one at a
+/// time, so that a large backlog of unseen mail doesn't produce a command line
+/// longer than the server will accept.
+fn fetch_batches(uids: &[Uid], batch_size: usize) -> Vec<&[Uid]> {
+    uids.chunks(batch_size).collect()
+}
+
+/// The messages the server says are bigger than `max_bytes`, out of its answer to a
+/// UID FETCH of RFC822.SIZE. Those it doesn't give a size for are let through.
+fn oversized(fetched: &[Fetch], max_bytes: u32) -> BTreeMap<Uid, u32> {
+    fetched
+        .iter()
+        .filter_map(|m| Some((m.uid?, m.size?)))
+        .filter(|&(_, size)| size > max_bytes)
+        .collect()
+}
+
+/// Fetches the given messages from each mailbox, as found by `search_inbox_latest`.
+/// On Gmail the same message can show up in more than one mailbox (one per label),
+/// so messages are only returned once, going by their Message-ID. Messages over
+/// `max_email_bytes` are checked for before anything big is fetched, and only have
+/// their headers fetched, so they are never held in memory whole.
+pub fn fetch_emails(
+    config: &Config,
+    new_mail: Vec<(String, BTreeSet<Uid>)>,
+) -> Result<Vec<Email>, ImapError> {
+    let mut imap_session = imap_login(config)?;
+    let emails = fetch_in_session(config, &mut imap_session, new_mail)?;
+    imap_session.logout()?;
+
+    Ok(emails)
+}
+
+/// Does the fetching for `fetch_emails`, in a session that's already logged in.
+fn fetch_in_session<T: Read + Write>(
+    config: &Config,
+    imap_session: &mut imap::Session<T>,
+    new_mail: Vec<(String, BTreeSet<Uid>)>,
+) -> Result<Vec<Email>, ImapError> {
+    let mut emails: Vec<Email> = Vec::new();
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    for (mailbox, uids) in new_mail {
+        imap_session.select(&mailbox)?;
+
+        let uids: Vec<Uid> = uids.into_iter().collect();
+        for batch in fetch_batches(&uids, config.fetch_batch_size) {
+            let mut batch_emails: Vec<(Uid, Email)> = Vec::new();
+
+            let too_large = match config.max_email_bytes {
+                Some(max_bytes) => {
+                    let uid_str = collapse_uid_set(&batch.iter().cloned().collect());
+                    oversized(&imap_session.uid_fetch(uid_str, "RFC822.SIZE")?, max_bytes)
+                }
+                None => BTreeMap::new(),
+            };
+            if !too_large.is_empty() {
+                let too_large_uids: Vec<Uid> = too_large.keys().cloned().collect();
+                let too_large_str = collapse_uid_set(&too_large.keys().cloned().collect());
+                log!(
+                    "Emails {} are over max_email_bytes, only fetching their headers",
+                    too_large_str
+                );
+
+                // BODY[HEADER] rather than BODY.PEEK[HEADER], so they're marked as
+                // seen like the others and aren't fetched again
+                let fetched = imap_session.uid_fetch(too_large_str, "BODY[HEADER]")?;
+                let (headers, missing) = fetched_bodies(&fetched, &too_large_uids, Fetch::header);
+                for (uid, header) in headers {
+                    match Email::from_bytes(header) {
+                        Ok(mut email) => {
+                            email.too_large = too_large.get(&uid).cloned();
+                            batch_emails.push((uid, email));
+                        }
+                        Err(e) => log_error!("Skipping email {}: {}", uid, e),
+                    }
+                }
+                for uid in missing {
+                    log!(
+                        "Skipping email {}: the server didn't return its headers",
+                        uid
+                    );
+                }
+            }
+
+            let batch: Vec<Uid> = batch
+                .iter()
+                .filter(|uid| !too_large.contains_key(uid))
+                .cloned()
+                .collect();
+            if !batch.is_empty() {
+                let uid_str = collapse_uid_set(&batch.iter().cloned().collect());
+
+                log!("Fetching emails with UIDs: {}", uid_str);
+
+                let fetched = imap_session.uid_fetch(uid_str, "RFC822")?;
+                let (bodies, missing) = fetched_bodies(&fetched, &batch, Fetch::body);
+                for (uid, body) in bodies {
+                    match Email::from_bytes(body) {
+                        Ok(email) => batch_emails.push((uid, email)),
+                        Err(e) => log_error!("Skipping email {}: {}", uid, e),
+                    }
+                }
+
+                // Some servers only hand over the message when asked for BODY[], so give
+                // those another try before giving up on them
+                if !missing.is_empty() {
+                    let missing_str = collapse_uid_set(&missing);
+                    log!(
+                        "No RFC822 body returned for {}, retrying with BODY[]",
+                        missing_str
+                    );
+
+                    let missing: Vec<Uid> = missing.into_iter().collect();
+                    let fetched = imap_session.uid_fetch(missing_str, "BODY[]")?;
+                    let (bodies, still_missing) = fetched_bodies(&fetched, &missing, Fetch::body);
+                    for (uid, body) in bodies {
+                        match Email::from_bytes(body) {
+                            Ok(email) => batch_emails.push((uid, email)),
+                            Err(e) => log_error!("Skipping email {}: {}", uid, e),
+                        }
+                    }
+                    for uid in still_missing {
+                        log!("Skipping email {}: the server didn't return its body", uid);
+                    }
+                }
+            }
+
+            // Servers aren't required to answer in UID order, so sort the batch
+            // to make sure emails are processed in the order they arrived
+            batch_emails.sort_by_key(|(uid, _)| *uid);
+            for (uid, mut email) in batch_emails {
+                email.source = Some((mailbox.clone(), uid));
+
+                if let Some(id) = &email.message_id {
+                    if !seen_ids.insert(id.clone()) {
+                        log!(
+                            "Skipping email {} in {}: already fetched from another mailbox",
+                            id,
+                            mailbox
+                        );
+                        continue;
+                    }
+                }
+
+                emails.push(email);
+            }
+        }
+    }
+
+    Ok(emails)
+}
+
+/// Moves handled emails into `archive_folder`. Servers with the MOVE extension do
+/// this in one go, others get each email copied across and deleted where it was.
+pub fn archive_emails(config: &Config, emails: &[Email]) -> Result<(), ImapError> {
+    let folder = match &config.archive_folder {
+        Some(folder) => folder,
+        None => return Ok(()),
+    };
+
+    let mut by_mailbox: HashMap<&str, BTreeSet<Uid>> = HashMap::new();
+    for (mailbox, uid) in emails.iter().filter_map(|email| email.source.as_ref()) {
+        by_mailbox.entry(mailbox).or_default().insert(*uid);
+    }
+    if by_mailbox.is_empty() {
+        return Ok(());
+    }
+
+    let mut imap_session = imap_login(config)?;
+    archive_in_session(&mut imap_session, folder, by_mailbox)?;
+    imap_session.logout()?;
+
+    Ok(())
+}
+
+/// Does the moving for `archive_emails`, in a session that's already logged in.
+fn archive_in_session<T: Read + Write>(
+    imap_session: &mut imap::Session<T>,
+    folder: &str,
+    by_mailbox: HashMap<&str, BTreeSet<Uid>>,
+) -> Result<(), ImapError> {
+    let (can_move, can_expunge_uids) = {
+        let capabilities = imap_session.capabilities()?;
+        (capabilities.has("MOVE"), capabilities.has("UIDPLUS"))
+    };
+
+    for (mailbox, uids) in by_mailbox {
+        let uid_str = collapse_uid_set(&uids);
+        imap_session.select(mailbox)?;
+
+        if can_move {
+            imap_session.uid_mv(&uid_str, folder)?;
+        } else {
+            // Unlike MOVE, the imap crate sends COPY's mailbox as it is, which
+            // breaks on a name with a space in it
+            imap_session.uid_copy(&uid_str, quote_mailbox(folder))?;
+            imap_session.uid_store(&uid_str, "+FLAGS.SILENT (\\Deleted)")?;
+
+            // Without UIDPLUS, anything else marked as deleted goes too, which is
+            // what the user's own client would do the next time it expunged
+            if can_expunge_uids {
+                imap_session.uid_expunge(&uid_str)?;
+            } else {
+                imap_session.expunge()?;
+            }
+        }
+
+        log!("Moved emails {} in {} to {}", uid_str, mailbox, folder);
+    }
+
+    Ok(())
+}
+
+/// Writes a mailbox name as an IMAP quoted string.
+fn quote_mailbox(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Builds an IMAP sequence set string from the given UIDs, collapsing contiguous
+/// runs into ranges. For example, `{1, 2, 3, 4, 5, 8, 10, 11, 12}` becomes
+/// `1:5,8,10:12`.
+fn collapse_uid_set(uids: &BTreeSet<Uid>) -> String {
+    let mut ranges: Vec<(Uid, Uid)> = Vec::new();
+    for &uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == uid => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}:{}", start, end)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Remembers how far each mailbox has been searched, so that most searches only
+/// need to look at mail that arrived since the last one.
+#[derive(Debug, Default)]
+pub struct SearchProgress {
+    /// The UIDVALIDITY of each mailbox, and the highest UID already searched in it
+    searched: HashMap<String, (u32, Uid)>,
+    last_full_search: Option<DateTime<Utc>>,
+}
+
+impl SearchProgress {
+    /// Makes the next search a full one, for when mail it found may not have been
+    /// processed.
+    pub fn reset(&mut self) {
+        self.searched.clear();
+        self.last_full_search = None;
+    }
+}
+
+/// Builds the UID SEARCH query for new mail from the target address and the
+/// `allowed_sender_domains` (and, with `detect_bounces`, from the addresses
+/// delivery reports are sent from), only looking past `searched_up_to` if the
+/// mailbox has been searched before.
+fn search_query(config: &Config, searched_up_to: Option<Uid>) -> String {
+    // FROM matches any part of the address, so these find everyone at the domain
+    let mut senders = vec![config.target_email.clone()];
+    senders.extend(
+        config
+            .allowed_sender_domains
+            .iter()
+            .map(|domain| format!("@{}", domain)),
+    );
+    if config.detect_bounces {
+        senders.push("mailer-daemon".to_string());
+        senders.push("postmaster".to_string());
+    }
+
+    // OR only takes two keys, so more are nested, like OR FROM a OR FROM b FROM c
+    let mut from = format!("FROM {}", senders.pop().unwrap());
+    while let Some(sender) = senders.pop() {
+        from = format!("OR FROM {} {}", sender, from);
+    }
+
+    match searched_up_to {
+        Some(uid) => format!("UID {}:* UNSEEN {}", uid + 1, from),
+        None => format!("UNSEEN {}", from),
+    }
+}
+
+/// Searches each mailbox for unseen mail from the target address, returning the
+/// mailboxes that have any along with the UIDs of the new messages. Usually only
+/// mail that arrived since the last search is looked at, but every
+/// `full_resync_minutes` the whole mailbox is searched again, to catch anything
+/// that was missed. At most `max_emails_per_poll` messages are returned, the
+/// oldest first, and the rest are left for the following searches.
+pub fn search_inbox_latest(
+    config: &Config,
+    progress: &mut SearchProgress,
+    now: DateTime<Utc>,
+) -> Result<Vec<(String, BTreeSet<Uid>)>, ImapError> {
+    let full_search = progress
+        .last_full_search
+        .is_none_or(|last| now - last >= chrono::Duration::minutes(config.full_resync_minutes));
+    if full_search {
+        progress.searched.clear();
+        progress.last_full_search = Some(now);
+    }
+
+    let mut imap_session = imap_login(config)?;
+    let new_mail = search_in_session(&mut imap_session, config, progress)?;
+    imap_session.logout()?;
+
+    Ok(new_mail)
+}
+
+/// Does the searching for `search_inbox_latest`, in a session that's already
+/// logged in.
+fn search_in_session<T: Read + Write>(
+    imap_session: &mut imap::Session<T>,
+    config: &Config,
+    progress: &mut SearchProgress,
+) -> Result<Vec<(String, BTreeSet<Uid>)>, ImapError> {
+    let mut new_mail = Vec::new();
+    let mut remaining = config.max_emails_per_poll;
+    for mailbox in mailboxes(config) {
+        // Mailboxes that weren't searched keep their progress, so nothing is missed
+        if remaining == 0 {
+            break;
+        }
+
+        let selected = imap_session.select(mailbox)?;
+        let uid_validity = selected.uid_validity.unwrap_or_default();
+
+        // UIDs only carry over between sessions while UIDVALIDITY stays the same
+        let searched_up_to = match progress.searched.get(mailbox) {
+            Some(&(validity, uid)) if validity == uid_validity => Some(uid),
+            _ => None,
+        };
+
+        // A range like 10:* always matches the newest message, even when its UID is
+        // below 10, so anything that was already searched is dropped again
+        let mut uids: BTreeSet<Uid> = imap_session
+            .uid_search(search_query(config, searched_up_to))?
+            .into_iter()
+            .filter(|&uid| searched_up_to.is_none_or(|searched| uid > searched))
+            .collect();
+
+        if uids.len() > remaining {
+            let left_over = *uids.iter().nth(remaining).unwrap();
+            let left_over = uids.split_off(&left_over);
+            log!(
+                "Leaving {} email(s) in {} for the next poll",
+                left_over.len(),
+                mailbox
+            );
+
+            // Only searched as far as the last email taken, so the next search
+            // starts with the ones left over
+            let last_taken = *uids.iter().next_back().unwrap();
+            progress
+                .searched
+                .insert(mailbox.to_string(), (uid_validity, last_taken));
+        } else if let Some(uid_next) = selected.uid_next {
+            // Everything below UIDNEXT existed when the mailbox was selected, so it
+            // has been searched now. Servers that don't say get a full search every
+            // time.
+            progress
+                .searched
+                .insert(mailbox.to_string(), (uid_validity, uid_next - 1));
+        }
+        remaining -= uids.len();
+
+        if !uids.is_empty() {
+            new_mail.push((mailbox.to_string(), uids));
+        }
+    }
+
+    Ok(new_mail)
+}
+
+/// Checks that we can log in to both the IMAP and SMTP servers, without touching
+/// any mail, and reports the result of each. Returns true if both checks passed.
+pub fn run_selftest(config: &Config) -> bool {
+    let mut passed = true;
+
+    let results = [("IMAP", check_imap(config)), ("SMTP", check_smtp(config))];
+    for (name, result) in results.iter() {
+        match result {
+            Ok(_) => log!("{} check: pass", name),
+            Err(e) => {
+                log!("{} check: FAIL ({})", name, e);
+                passed = false;
+            }
+        }
+    }
+
+    passed
+}
+
+fn check_imap(config: &Config) -> Result<(), String> {
+    let domain = config.journal_email_imap.as_str();
+    let tls = native_tls::TlsConnector::builder()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let client = imap::connect((domain, 993), imap_tls_hostname(config), &tls)
+        .map_err(|e| format!("failed to connect to {}: {}", domain, e))?;
+    let mut imap_session = client
+        .login(&config.journal_email, &config.journal_email_password)
+        .map_err(|e| format!("failed to log in: {}", e.0))?;
+
+    imap_session
+        .select("INBOX")
+        .map_err(|e| format!("failed to select INBOX: {}", e))?;
+    imap_session.logout().map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Goes through the same EHLO, STARTTLS and AUTH steps as sending an email, but
+/// hangs up before a message would be sent.
+fn check_smtp(config: &Config) -> Result<(), String> {
+    let mut client: SmtpClient<NetworkStream> = SmtpClient::new();
+
+    let result = smtp_handshake(config, &mut client).and_then(|_| {
+        let credentials = Credentials::new(
+            config.journal_email.clone(),
+            config.journal_email_password.clone(),
+        );
+        client
+            .auth(config.smtp_auth_mechanism.mechanism(), &credentials)
+            .map_err(|e| format!("failed to authenticate: {:?}", e))
+    });
+
+    client.close();
+    result.map(|_| ())
+}
+
+/// Connects to the SMTP server and goes through EHLO and STARTTLS, returning what
+/// the server said it supports once the connection is encrypted.
+fn smtp_handshake(
+    config: &Config,
+    client: &mut SmtpClient<NetworkStream>,
+) -> Result<ServerInfo, String> {
+    let domain = config.journal_email_smtp.as_str();
+    let tls = native_tls_legacy::TlsConnector::builder()
+        .and_then(|builder| builder.build())
+        .map_err(|e| e.to_string())?;
+    let tls_parameters = ClientTlsParameters::new(domain.to_string(), tls);
+    let hello_name = ClientId::Domain(domain.to_string());
+
+    client
+        .connect(&(domain, SUBMISSION_PORT), None)
+        .map_err(|e| format!("failed to connect to {}: {:?}", domain, e))?;
+
+    client
+        .command(EhloCommand::new(hello_name.clone()))
+        .and_then(|_| client.command(StarttlsCommand))
+        .map_err(|e| format!("failed to start TLS: {:?}", e))?;
+    client
+        .upgrade_tls_stream(&tls_parameters)
+        .map_err(|e| format!("failed to start TLS: {}", e))?;
+
+    let response = client
+        .command(EhloCommand::new(hello_name))
+        .map_err(|e| format!("{:?}", e))?;
+    ServerInfo::from_response(&response).map_err(|e| format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        imap_client, imap_session, recording_imap_session, sent_emails, test_config, TempDir,
+    };
+
+    /// A journal entry, as the server would hand it over.
+    fn message(name: &str) -> Vec<u8> {
+        format!(
+            "From: me@example.com\r\nTo: journal@example.com\r\nSubject: Re: Journal\r\nDate: Tue, 1 Jan 2019 10:00:00 +0000\r\nMessage-ID: <{}@example.com>\r\n\r\nEntry {}\r\n",
+            name, name
+        )
+        .into_bytes()
+    }
+
+    /// One untagged FETCH response, with `item` holding `data` as a literal.
+    fn fetch_response(seq: u32, uid: Uid, item: &str, data: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "* {} FETCH (UID {} {} {{{}}}\r\n",
+            seq,
+            uid,
+            item,
+            data.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(data);
+        response.extend_from_slice(b")\r\n");
+        response
+    }
+
+    fn message_ids(emails: &[Email]) -> Vec<&str> {
+        emails
+            .iter()
+            .map(|email| email.message_id.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    /// An email with the given headers (besides a Date) and body.
+    fn parse(headers: &str, body: &str) -> Email {
+        let raw = format!(
+            "{}\r\nDate: Tue, 1 Jan 2019 10:00:00 +0000\r\n\r\n{}",
+            headers.replace('\n', "\r\n"),
+            body.replace('\n', "\r\n")
+        );
+        Email::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn an_encoded_subject_is_decoded() {
+        let base64 = parse(
+            "From: me@example.com\nSubject: =?UTF-8?B?Q2Fmw6kgZGF5?=",
+            "Entry",
+        );
+        assert_eq!(base64.subject, "Café day");
+
+        let quoted_printable = parse(
+            "From: me@example.com\nSubject: =?utf-8?Q?Caf=C3=A9_day?=",
+            "Entry",
+        );
+        assert_eq!(quoted_printable.subject, "Café day");
+    }
+
+    #[test]
+    fn an_encoded_sender_name_is_decoded_and_the_address_kept() {
+        let email = parse(
+            "From: =?ISO-8859-1?Q?Ren=E9e?= <renee@example.com>\nSubject: Entry",
+            "Entry",
+        );
+        assert_eq!(email.from, "Renée <renee@example.com>");
+        assert_eq!(email.reply_to, "renee@example.com");
+        assert!(email.is_from("renee@example.com"));
+
+        let email = parse(
+            "From: =?UTF-8?B?5bCP5p6X?= <kobayashi@example.com>\nSubject: Entry",
+            "Entry",
+        );
+        assert_eq!(email.from, "小林 <kobayashi@example.com>");
+        assert_eq!(email.reply_to, "kobayashi@example.com");
+    }
+
+    #[test]
+    fn a_single_part_html_email_is_converted_to_text() {
+        let email = parse(
+            "From: me@example.com\nSubject: Entry\nContent-Type: text/html; charset=utf-8",
+            "<html><body><p>Went hiking.</p><p>Saw a deer &amp; a fox.</p></body></html>",
+        );
+        assert!(!email.body.contains('<'));
+        assert!(email.body.contains("Went hiking."));
+        assert!(email.body.contains("Saw a deer & a fox."));
+    }
+
+    #[test]
+    fn a_single_part_plaintext_email_is_kept_as_it_is() {
+        let email = parse(
+            "From: me@example.com\nSubject: Entry\nContent-Type: text/plain; charset=utf-8",
+            "Went <hiking>.",
+        );
+        assert_eq!(email.body.trim(), "Went <hiking>.");
+    }
+
+    #[test]
+    fn the_body_of_a_forwarded_message_is_used_when_the_email_has_none() {
+        let email = parse(
+            "From: me@example.com\nSubject: Fwd: Entry\nMIME-Version: 1.0\nContent-Type: multipart/mixed; boundary=\"outer\"",
+            "--outer\nContent-Type: text/plain\n\n\n--outer\nContent-Type: message/rfc822\nContent-Disposition: attachment\n\nFrom: me@example.com\nSubject: Entry\nDate: Mon, 31 Dec 2018 21:00:00 +0000\nContent-Type: text/plain\n\nWrote this on my phone.\n--outer--\n",
+        );
+        assert_eq!(email.body.trim(), "Wrote this on my phone.");
+    }
+
+    #[test]
+    fn the_email_body_wins_over_a_forwarded_message() {
+        let email = parse(
+            "From: me@example.com\nSubject: Fwd: Entry\nMIME-Version: 1.0\nContent-Type: multipart/mixed; boundary=\"outer\"",
+            "--outer\nContent-Type: text/plain\n\nMy own words.\n--outer\nContent-Type: message/rfc822\n\nFrom: me@example.com\nSubject: Entry\nDate: Mon, 31 Dec 2018 21:00:00 +0000\n\nForwarded words.\n--outer--\n",
+        );
+        assert_eq!(email.body.trim(), "My own words.");
+    }
+
+    /// A multipart/related email with an image and an HTML part, whose root is
+    /// named by `start`, if given.
+    fn related(start: Option<&str>) -> Email {
+        let content_type = match start {
+            Some(start) => format!(
+                "multipart/related; boundary=\"rel\"; type=\"text/html\"; start=\"{}\"",
+                start
+            ),
+            None => "multipart/related; boundary=\"rel\"; type=\"text/html\"".to_string(),
+        };
+        parse(
+            &format!(
+                "From: me@example.com\nSubject: Entry\nMIME-Version: 1.0\nContent-Type: {}",
+                content_type
+            ),
+            "--rel\nContent-Type: image/png\nContent-ID: <photo@example.com>\nContent-Transfer-Encoding: base64\n\naW1hZ2U=\n--rel\nContent-Type: text/html\nContent-ID: <root@example.com>\n\n<p>A day at the beach.</p><img src=\"cid:photo@example.com\">\n--rel--\n",
+        )
+    }
+
+    #[test]
+    fn the_root_of_a_related_email_is_the_part_start_names() {
+        let email = related(Some("<root@example.com>"));
+        assert!(email.body.contains("A day at the beach."));
+        assert_eq!(email.attachments.len(), 1);
+        assert_eq!(email.attachments[0].mimetype, "image/png");
+        assert_eq!(email.attachments[0].filename, "inline-1.png");
+        assert_eq!(email.attachments[0].data, b"image");
+    }
+
+    #[test]
+    fn the_root_of_a_related_email_without_start_is_its_first_part() {
+        // The first part is the image, so there's no text to be found, and the HTML
+        // part is one of the resources
+        let email = related(None);
+        assert_eq!(email.body, "");
+        assert_eq!(email.attachments.len(), 1);
+        assert_eq!(email.attachments[0].mimetype, "text/html");
+    }
+
+    /// A delivery report for an email with the given subject.
+    fn delivery_report(subject: &str) -> Email {
+        parse(
+            "From: Mail Delivery System <MAILER-DAEMON@mx.example.com>\nSubject: Undelivered Mail Returned to Sender\nMIME-Version: 1.0\nContent-Type: multipart/report; report-type=delivery-status; boundary=\"report\"",
+            &format!(
+                "--report\nContent-Type: text/plain\n\nThe mailbox is full.\n--report\nContent-Type: message/delivery-status\n\nReporting-MTA: dns; mx.example.com\n\nFinal-Recipient: rfc822; me@example.com\nAction: failed\nStatus: 5.2.2\n--report\nContent-Type: text/rfc822-headers\n\nFrom: journal@example.com\nTo: me@example.com\nSubject: {}\n--report--\n",
+                subject
+            ),
+        )
+    }
+
+    #[test]
+    fn a_delivery_report_is_a_bounce_of_the_email_it_reports() {
+        let email = delivery_report("Daily Journal Entry");
+        let bounce = email.bounce.expect("a bounce");
+        assert_eq!(bounce.subject.as_deref(), Some("Daily Journal Entry"));
+    }
+
+    #[test]
+    fn mail_from_the_mailer_daemon_is_a_bounce_without_a_report() {
+        let email = parse(
+            "From: postmaster@mx.example.com\nSubject: Delivery failure",
+            "Your message couldn't be delivered.",
+        );
+        assert_eq!(email.bounce.expect("a bounce").subject, None);
+    }
+
+    #[test]
+    fn an_entry_is_not_a_bounce() {
+        let email = parse("From: me@example.com\nSubject: Re: Journal", "A good day");
+        assert!(email.bounce.is_none());
+    }
+
+    #[test]
+    fn auto_submitted_mail_is_automatic() {
+        let from = "From: me@example.com\nSubject: Out of office\n";
+        for header in &[
+            "Auto-Submitted: auto-replied",
+            "Auto-Submitted: auto-generated",
+            "Precedence: bulk",
+            "Precedence: Auto_Reply",
+        ] {
+            let email = parse(&format!("{}{}", from, header), "I'm away");
+            assert!(email.is_automatic, "{}", header);
+        }
+    }
+
+    #[test]
+    fn mail_that_isnt_auto_submitted_is_not_automatic() {
+        let from = "From: me@example.com\nSubject: Re: Journal";
+        for header in &["", "\nAuto-Submitted: no", "\nPrecedence: first-class"] {
+            let email = parse(&format!("{}{}", from, header), "A good day");
+            assert!(!email.is_automatic, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn sent_mail_says_it_was_auto_generated() {
+        let dir = TempDir::new("mail-auto-submitted");
+        let config = test_config(&dir);
+
+        send_reply(
+            &config,
+            "me@example.com",
+            "Entry count",
+            "You have 3 entries",
+        );
+
+        let sent = sent_emails();
+        assert_eq!(
+            sent[0].header("Auto-Submitted").as_deref(),
+            Some("auto-generated")
+        );
+    }
+
+    #[test]
+    fn fetched_emails_are_in_uid_order() {
+        let mut answers = b"* 2 EXISTS\r\na2 OK [READ-WRITE] selected\r\n".to_vec();
+        answers.extend(fetch_response(2, 6, "RFC822", &message("six")));
+        answers.extend(fetch_response(1, 5, "RFC822", &message("five")));
+        answers.extend_from_slice(b"a3 OK fetched\r\n");
+
+        let mut session = imap_session(&answers);
+        let uids: BTreeSet<Uid> = [6, 5].iter().cloned().collect();
+        let emails = fetch_in_session(
+            &Config::default(),
+            &mut session,
+            vec![("INBOX".to_string(), uids)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            message_ids(&emails),
+            vec!["<five@example.com>", "<six@example.com>"]
+        );
+        assert_eq!(emails[0].source, Some(("INBOX".to_string(), 5)));
+    }
+
+    fn collapse(uids: &[Uid]) -> String {
+        collapse_uid_set(&uids.iter().cloned().collect())
+    }
+
+    #[test]
+    fn html_to_text_keeps_paragraphs_apart() {
+        assert_eq!(
+            html_to_text("<p>First paragraph.</p><p>Second<br>line.</p>"),
+            "First paragraph.\n\nSecond\nline."
+        );
+    }
+
+    #[test]
+    fn html_to_text_keeps_the_target_of_a_link() {
+        assert_eq!(
+            html_to_text(r#"<p>Read <a href="https://example.com/post">this post</a> today.</p>"#),
+            "Read this post (https://example.com/post) today."
+        );
+        assert_eq!(
+            html_to_text(r#"<a href="https://example.com">https://example.com</a>"#),
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn html_to_text_decodes_entities_and_drops_styles() {
+        assert_eq!(
+            html_to_text("<style>p { color: red; }</style><p>Fish &amp; chips &lt;3</p>"),
+            "Fish & chips <3"
+        );
+    }
+
+    #[test]
+    fn fetched_bodies_skips_responses_without_a_body() {
+        let mut answers = b"* 2 FETCH (UID 5 FLAGS (\\Seen))\r\n".to_vec();
+        answers.extend_from_slice(b"* 3 FETCH (FLAGS (\\Seen))\r\n");
+        answers.extend(fetch_response(4, 6, "RFC822", &message("six")));
+        answers.extend_from_slice(b"a2 OK fetched\r\n");
+
+        let mut session = imap_session(&answers);
+        let fetched = session.uid_fetch("5:6", "RFC822").unwrap();
+        let (bodies, missing) = fetched_bodies(&fetched, &[5, 6], Fetch::body);
+
+        let uids: Vec<Uid> = bodies.iter().map(|(uid, _)| *uid).collect();
+        assert_eq!(uids, vec![6]);
+        assert_eq!(missing.into_iter().collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn an_email_the_server_never_sends_the_body_of_is_skipped() {
+        let mut answers = b"* 2 EXISTS\r\na2 OK [READ-WRITE] selected\r\n".to_vec();
+        answers.extend_from_slice(b"* 1 FETCH (UID 5 FLAGS (\\Seen))\r\n");
+        answers.extend(fetch_response(2, 6, "RFC822", &message("six")));
+        answers.extend_from_slice(b"a3 OK fetched\r\n");
+        answers.extend_from_slice(b"* 1 FETCH (UID 5 FLAGS (\\Seen))\r\n");
+        answers.extend_from_slice(b"a4 OK fetched\r\n");
+
+        let mut session = imap_session(&answers);
+        let uids: BTreeSet<Uid> = [5, 6].iter().cloned().collect();
+        let emails = fetch_in_session(
+            &Config::default(),
+            &mut session,
+            vec![("INBOX".to_string(), uids)],
+        )
+        .unwrap();
+
+        assert_eq!(message_ids(&emails), vec!["<six@example.com>"]);
+    }
+
+    #[test]
+    fn fetch_batches_splits_a_backlog() {
+        let uids: Vec<Uid> = (1..=120).collect();
+        let batches = fetch_batches(&uids, 50);
+
+        let uid_sets: Vec<String> = batches.iter().map(|batch| collapse(batch)).collect();
+        assert_eq!(uid_sets, vec!["1:50", "51:100", "101:120"]);
+    }
+
+    #[test]
+    fn fetch_batches_of_a_short_backlog_is_one_batch() {
+        let uids: Vec<Uid> = (1..=20).collect();
+        assert_eq!(fetch_batches(&uids, 50).len(), 1);
+        assert!(fetch_batches(&[], 50).is_empty());
+    }
+
+    #[test]
+    fn collapse_uid_set_of_nothing_is_empty() {
+        assert_eq!(collapse(&[]), "");
+    }
+
+    #[test]
+    fn collapse_uid_set_of_one_uid_is_that_uid() {
+        assert_eq!(collapse(&[7]), "7");
+    }
+
+    #[test]
+    fn collapse_uid_set_makes_runs_into_ranges() {
+        assert_eq!(collapse(&[1, 2, 3, 4, 5]), "1:5");
+        assert_eq!(collapse(&[4, 5]), "4:5");
+    }
+
+    #[test]
+    fn collapse_uid_set_keeps_the_gaps() {
+        assert_eq!(collapse(&[1, 2, 3, 4, 5, 8, 10, 11, 12]), "1:5,8,10:12");
+        assert_eq!(collapse(&[1, 3, 5]), "1,3,5");
+    }
+
+    #[test]
+    fn collapse_uid_set_sorts_the_uids() {
+        assert_eq!(collapse(&[12, 3, 10, 1, 11, 2, 8]), "1:3,8,10:12");
+    }
+
+    fn from(header: &str) -> Email {
+        let raw = format!("From: {}\r\nSubject: Re: Journal\r\nDate: Tue, 1 Jan 2019 10:00:00 +0000\r\n\r\nEntry\r\n", header);
+        Email::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn is_from_a_bare_address() {
+        assert!(from("me@example.com").is_from("me@example.com"));
+    }
+
+    #[test]
+    fn is_from_an_address_with_a_name() {
+        assert!(from("Me <me@example.com>").is_from("me@example.com"));
+        assert!(from("\"Smith, Me\" <me@example.com>").is_from("me@example.com"));
+        assert!(from("\"<other@example.com>\" <me@example.com>").is_from("me@example.com"));
+        assert!(from("me@example.com (Me <other@example.com>)").is_from("me@example.com"));
+    }
+
+    #[test]
+    fn is_from_ignores_case() {
+        assert!(from("Me@Example.COM").is_from("me@example.com"));
+        assert!(from("Me <ME@example.com>").is_from("Me@Example.com"));
+    }
+
+    #[test]
+    fn is_from_someone_else() {
+        assert!(!from("Me <other@example.com>").is_from("me@example.com"));
+        assert!(!from("notme@example.com").is_from("me@example.com"));
+        assert!(!from("\"me@example.com\" <other@example.com>").is_from("me@example.com"));
+    }
+
+    #[test]
+    fn is_from_domain_ignores_case() {
+        assert!(from("Me <me@Example.com>").is_from_domain("example.COM"));
+        assert!(!from("Me <me@example.com.evil>").is_from_domain("example.com"));
+    }
+
+    #[test]
+    fn a_rejected_login_is_a_login_error() {
+        for answer in &[
+            &b"a1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n"[..],
+            &b"a1 BAD Unknown command\r\n"[..],
+        ] {
+            assert!(matches!(
+                log_in(&Config::default(), imap_client(answer)),
+                Err(ImapError::Login(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn a_dropped_connection_while_logging_in_is_another_error() {
+        for answer in &[&b""[..], &b"a1 OK [ALERT"[..], &b"nonsense\r\n"[..]] {
+            assert!(matches!(
+                log_in(&Config::default(), imap_client(answer)),
+                Err(ImapError::Other(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn an_accepted_login_is_a_session() {
+        assert!(log_in(&Config::default(), imap_client(b"a1 OK logged in\r\n")).is_ok());
+    }
+
+    fn ehlo_supports_utf8(ehlo: &str) -> bool {
+        let response: lettre::smtp::response::Response = ehlo.parse().unwrap();
+        ServerInfo::from_response(&response)
+            .unwrap()
+            .supports_feature(Extension::SmtpUtfEight)
+    }
+
+    #[test]
+    fn smtp_utf8_support_comes_from_the_ehlo() {
+        assert!(ehlo_supports_utf8(
+            "250-smtp.example.com\r\n250-8BITMIME\r\n250-SMTPUTF8\r\n250 AUTH PLAIN LOGIN\r\n"
+        ));
+        assert!(!ehlo_supports_utf8(
+            "250-smtp.example.com\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN\r\n"
+        ));
+    }
+
+    #[test]
+    fn smtp_utf8_support_is_only_asked_once() {
+        let mut probes = 0;
+        for _ in 0..3 {
+            assert!(cached_utf8_support("utf8.example.com", || {
+                probes += 1;
+                Some(true)
+            }));
+        }
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn smtp_utf8_support_is_remembered_for_each_server() {
+        assert!(!cached_utf8_support("ascii.example.com", || Some(false)));
+        assert!(cached_utf8_support("unicode.example.com", || Some(true)));
+        assert!(!cached_utf8_support("ascii.example.com", || panic!(
+            "asked again"
+        )));
+        assert!(cached_utf8_support("unicode.example.com", || panic!(
+            "asked again"
+        )));
+    }
+
+    #[test]
+    fn smtp_utf8_support_is_asked_again_if_it_couldnt_tell() {
+        assert!(!cached_utf8_support("flaky.example.com", || None));
+        assert!(cached_utf8_support("flaky.example.com", || Some(true)));
+    }
+
+    /// Archives UIDs 3, 4 and 7 of the inbox, with the server saying it has the
+    /// `capabilities` and answering `answers` after that. Returns the commands sent.
+    fn archive(capabilities: &str, answers: &[u8]) -> Vec<String> {
+        let mut all_answers = format!(
+            "* CAPABILITY IMAP4rev1 {}\r\na2 OK done\r\n* 9 EXISTS\r\na3 OK [READ-WRITE] selected\r\n",
+            capabilities
+        )
+        .into_bytes();
+        all_answers.extend_from_slice(answers);
+        let (mut session, sent) = recording_imap_session(&all_answers);
+
+        let mut by_mailbox = HashMap::new();
+        by_mailbox.insert("INBOX", [3, 4, 7].iter().cloned().collect());
+        archive_in_session(&mut session, "Journaled", by_mailbox).unwrap();
+
+        let sent = String::from_utf8(sent.borrow().clone()).unwrap();
+        sent.lines().map(str::to_string).collect()
+    }
+
+    /// Searches INBOX for the target's mail, with the server saying the mailbox has
+    /// the given UIDVALIDITY and UIDNEXT, and finding `found`. Returns what was
+    /// found along with the commands sent.
+    fn search(
+        config: &Config,
+        progress: &mut SearchProgress,
+        (uid_validity, uid_next): (u32, Uid),
+        found: &[Uid],
+    ) -> (Vec<Uid>, Vec<String>) {
+        let found: String = found.iter().map(|uid| format!(" {}", uid)).collect();
+        let answers = format!(
+            "* 300 EXISTS\r\n* OK [UIDVALIDITY {}] ok\r\n* OK [UIDNEXT {}] next\r\na2 OK [READ-WRITE] selected\r\n* SEARCH{}\r\na3 OK done\r\n",
+            uid_validity, uid_next, found
+        );
+        let (mut session, sent) = recording_imap_session(answers.as_bytes());
+
+        let new_mail = search_in_session(&mut session, config, progress).unwrap();
+        let uids = new_mail
+            .into_iter()
+            .flat_map(|(mailbox, uids)| {
+                assert_eq!(mailbox, "INBOX");
+                uids
+            })
+            .collect();
+
+        let sent = String::from_utf8(sent.borrow().clone()).unwrap();
+        (uids, sent.lines().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn the_first_search_of_a_mailbox_is_a_full_one() {
+        let dir = TempDir::new("search-full");
+        let config = test_config(&dir);
+        let mut progress = SearchProgress::default();
+
+        let (uids, sent) = search(&config, &mut progress, (7, 13), &[10, 12]);
+        assert_eq!(uids, vec![10, 12]);
+        assert_eq!(
+            sent,
+            vec![
+                "a2 SELECT \"INBOX\"",
+                "a3 UID SEARCH UNSEEN FROM me@example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn later_searches_only_look_past_the_last_uid_searched() {
+        let dir = TempDir::new("search-incremental");
+        let config = test_config(&dir);
+        let mut progress = SearchProgress::default();
+        search(&config, &mut progress, (7, 13), &[10, 12]);
+
+        let (uids, sent) = search(&config, &mut progress, (7, 15), &[13, 14]);
+        assert_eq!(uids, vec![13, 14]);
+        assert_eq!(sent[1], "a3 UID SEARCH UID 13:* UNSEEN FROM me@example.com");
+
+        // 15:* matches the newest message even though its UID is lower, so that's
+        // left out
+        let (uids, sent) = search(&config, &mut progress, (7, 15), &[14]);
+        assert!(uids.is_empty());
+        assert_eq!(sent[1], "a3 UID SEARCH UID 15:* UNSEEN FROM me@example.com");
+    }
+
+    #[test]
+    fn a_new_uid_validity_means_a_full_search() {
+        let dir = TempDir::new("search-uid-validity");
+        let config = test_config(&dir);
+        let mut progress = SearchProgress::default();
+        search(&config, &mut progress, (7, 13), &[10, 12]);
+
+        let (uids, sent) = search(&config, &mut progress, (8, 4), &[2, 3]);
+        assert_eq!(uids, vec![2, 3]);
+        assert_eq!(sent[1], "a3 UID SEARCH UNSEEN FROM me@example.com");
+    }
+
+    #[test]
+    fn a_backlog_is_spread_over_polls_of_max_emails_per_poll() {
+        let dir = TempDir::new("search-max-per-poll");
+        let config = test_config(&dir);
+        assert_eq!(config.max_emails_per_poll, 100);
+        let mut progress = SearchProgress::default();
+
+        // The server finds everything from the range asked for, up to UIDNEXT
+        let backlog: Vec<Uid> = (1..=250).collect();
+        let mut taken = Vec::new();
+        for (poll, from) in [(1, 1), (2, 101), (3, 201)] {
+            let (uids, sent) = search(&config, &mut progress, (7, 251), &backlog[from - 1..]);
+            assert_eq!(uids.len(), if poll < 3 { 100 } else { 50 });
+            if from > 1 {
+                assert_eq!(
+                    sent[1],
+                    format!("a3 UID SEARCH UID {}:* UNSEEN FROM me@example.com", from)
+                );
+            }
+            taken.extend(uids);
+        }
+        assert_eq!(taken, backlog);
+
+        // Nothing's left over for a fourth
+        let (uids, sent) = search(&config, &mut progress, (7, 251), &[250]);
+        assert!(uids.is_empty());
+        assert_eq!(
+            sent[1],
+            "a3 UID SEARCH UID 251:* UNSEEN FROM me@example.com"
+        );
+    }
+
+    #[test]
+    fn the_search_query_includes_every_allowed_sender() {
+        let dir = TempDir::new("search-query");
+        let mut config = test_config(&dir);
+        config.allowed_sender_domains = vec!["myfamily.com".to_string()];
+        config.detect_bounces = true;
+
+        assert_eq!(
+            search_query(&config, Some(41)),
+            "UID 42:* UNSEEN OR FROM me@example.com OR FROM @myfamily.com OR FROM mailer-daemon FROM postmaster"
+        );
+    }
+
+    #[test]
+    fn quote_mailbox_escapes_quotes_and_backslashes() {
+        assert_eq!(quote_mailbox("Journal Archive"), "\"Journal Archive\"");
+        assert_eq!(quote_mailbox("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    #[test]
+    fn archiving_moves_the_emails_when_the_server_can() {
+        assert_eq!(
+            archive("MOVE UIDPLUS", b"a4 OK moved\r\n"),
+            vec![
+                "a2 CAPABILITY",
+                "a3 SELECT \"INBOX\"",
+                "a4 UID MOVE 3:4,7 \"Journaled\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn archiving_copies_and_deletes_the_emails_without_move() {
+        assert_eq!(
+            archive(
+                "UIDPLUS",
+                b"a4 OK copied\r\na5 OK stored\r\n* 3 EXPUNGE\r\na6 OK expunged\r\n"
+            ),
+            vec![
+                "a2 CAPABILITY",
+                "a3 SELECT \"INBOX\"",
+                "a4 UID COPY 3:4,7 \"Journaled\"",
+                "a5 UID STORE 3:4,7 +FLAGS.SILENT (\\Deleted)",
+                "a6 UID EXPUNGE 3:4,7",
+            ]
+        );
+    }
+
+    #[test]
+    fn archiving_expunges_the_whole_mailbox_without_uidplus() {
+        let sent = archive(
+            "IDLE",
+            b"a4 OK copied\r\na5 OK stored\r\n* 3 EXPUNGE\r\na6 OK expunged\r\n",
+        );
+        assert_eq!(sent[4], "a6 EXPUNGE");
+    }
+}
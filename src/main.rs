@@ -1,104 +1,56 @@
 use chrono::prelude::*;
 use chrono::Duration;
-use imap::types::Seq;
-use lettre::smtp::authentication::{Credentials, Mechanism};
-use lettre::smtp::extension::ClientId;
-use lettre::smtp::ConnectionReuseParameters;
-use lettre::{EmailTransport, SmtpTransport};
-use lettre_email::EmailBuilder;
-use mailparse::*;
+use mail_journal::config::{Config, DbRollover, StorageBackend, CONFIG_PATH};
+use mail_journal::control;
+use mail_journal::daemon::{lock_instance, Daemon};
+use mail_journal::error::{self, Error};
+use mail_journal::export::{
+    encode_export, export_json, export_top_words, export_word_trend, import_entries, import_into,
+    parse_json_import, parse_markdown_import,
+};
+use mail_journal::journal::{merge_duplicate_days, DayMerge};
+use mail_journal::logging;
+use mail_journal::mail::run_selftest;
+use mail_journal::schedule::send_welcome_email;
+use mail_journal::store::{
+    db_path, initialize_db, open_db, open_storage, open_storage_with_history,
+};
+use mail_journal::{log, log_error};
 use ron::ser::PrettyConfig;
-use rusqlite::{Connection, NO_PARAMS};
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    target_email: String,
-    target_name: String,
-    db_filename: String,
-    journal_email_smtp: String,
-    journal_email_imap: String,
-    journal_email: String,
-    journal_email_password: String,
-    utc_reminder_hour: i64,
-}
+const SLEEP_TIME_SECONDS: i64 = 2;
 
-impl Default for Config {
-    fn default() -> Config {
-        Config {
-            target_email: "john.smith@example.com".to_string(),
-            target_name: "John Smith".to_string(),
-            db_filename: "mail-journal.db".to_string(),
-            journal_email_smtp: "smtp.example.com".to_string(),
-            journal_email_imap: "imap.example.com".to_string(),
-            journal_email: "mail-journal@example.com".to_string(),
-            journal_email_password: "password".to_string(),
-            utc_reminder_hour: 0,
-        }
+fn main() {
+    if let Err(error) = run() {
+        log_error!("{}", error);
+        std::process::exit(error.exit_code());
     }
 }
 
-struct JournalEntry {
-    _id: i32,
-    _day: i32,
-    _month: i32,
-    _year: i32,
-    body: String,
-}
-
-struct Email {
-    from: String,
-    _subject: String,
-    timestamp: DateTime<Utc>,
-    body: String,
-}
-
-impl Email {
-    pub fn from_bytes(bytes: &[u8]) -> Email {
-        let parsed = parse_mail(bytes).expect("Failed to parse email!");
-
-        let from = parsed.headers.get_first_value("From").unwrap().unwrap();
-        let subject = parsed.headers.get_first_value("Subject").unwrap().unwrap();
-
-        let timestamp_rfc2882 = parsed.headers.get_first_value("Date").unwrap().unwrap();
-        let timestamp: DateTime<Utc> = DateTime::parse_from_rfc2822(&timestamp_rfc2882)
-            .expect("Failed to parse email timestamp!")
-            .with_timezone(&Utc);
-
-        let body = {
-            if parsed.subparts.len() > 0 {
-                parsed.subparts[0].get_body().unwrap()
-            } else {
-                String::new()
-            }
-        };
-
-        Email {
-            from,
-            _subject: subject,
-            timestamp,
-            body,
-        }
+fn run() -> error::Result<()> {
+    // Answered before touching the config, so it works even without one
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() == 1 && (args[0] == "--version" || args[0] == "-V") {
+        print_version();
+        return Ok(());
     }
-}
 
-pub const CONFIG_PATH: &'static str = "config.ron";
-pub const SLEEP_TIME_SECONDS: i64 = 2;
-
-fn main() {
     // Load config file
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(CONFIG_PATH)
-        .expect("Failed to open config file!");
+        .map_err(|e| Error::Config(format!("Failed to open config file! {}", e)))?;
 
     let mut config_str = String::new();
-    file.read_to_string(&mut config_str).unwrap();
+    file.read_to_string(&mut config_str)
+        .map_err(|e| Error::Config(format!("Failed to read config file! {}", e)))?;
 
     // Config is empty, create default config and exit.
     if config_str.is_empty() {
@@ -110,306 +62,307 @@ fn main() {
         };
 
         let s = ron::ser::to_string_pretty(&Config::default(), pretty)
-            .expect("Failed to serialize config!");
+            .map_err(|e| Error::Config(format!("Failed to create config file! {}", e)))?;
         file.write_all(s.as_bytes())
-            .expect("Failed to write config file!");
+            .map_err(|e| Error::Config(format!("Failed to write config file! {}", e)))?;
 
         println!("No config file was found, so a default one was created. Please edit it and run Mail Journal again.");
-        return;
+        return Ok(());
     }
 
     // Deserialize config
-    let config: Config = match ron::de::from_str(&config_str) {
-        Ok(x) => x,
-        Err(e) => {
-            eprintln!("Failed to load config: {}", e);
-            std::process::exit(1);
-        }
-    };
+    let config: Config = ron::de::from_str(&config_str)
+        .map_err(|e| Error::Config(format!("Failed to load config: {}", e)))?;
 
-    // Validate reminder_hour
-    if config.utc_reminder_hour < 0 || config.utc_reminder_hour > 23 {
-        eprintln!("Config error! reminder_hour must be an integer between 0 and 23 (inclusive).");
-        return;
-    }
+    config
+        .validate()
+        .map_err(|e| Error::Config(format!("Config error! {}", e)))?;
 
-    initialize_db(&config);
+    if let Some(path) = &config.log_file {
+        logging::init(path, config.log_max_size, config.log_keep_files);
+    }
 
-    let utc: DateTime<Utc> = Utc::now();
-    let today: DateTime<Utc> = Utc.ymd(utc.year(), utc.month(), utc.day()).and_hms(0, 0, 0);
-
-    let mut did_remind = false;
-    let mut remind_time = today
-        .checked_add_signed(Duration::hours(config.utc_reminder_hour))
-        .unwrap();
-
-    if utc < remind_time {
-        println!("Journal reminder for today is scheduled at {}", remind_time);
-    } else {
-        did_remind = true;
-        println!("Journal reminder for today has been sent.");
+    let today = Utc::today().naive_utc();
+    let mailbox_configs = config.mailbox_configs();
+    for mailbox_config in &mailbox_configs {
+        initialize_db(mailbox_config, today)?;
     }
 
-    println!("Mail Journal running.");
+    // If we were given a command line command, run it instead of the daemon
+    if !args.is_empty() {
+        return run_cli(&config, &args, today);
+    }
 
-    let sleep_duration = Duration::milliseconds(SLEEP_TIME_SECONDS).to_std().unwrap();
-    loop {
-        let utc: DateTime<Utc> = Utc::now();
+    // Held until we return, so the lock only goes once we've stopped
+    let _lock = match &config.lock_file {
+        Some(path) => Some(lock_instance(path)?),
+        None => None,
+    };
 
-        // Check for new journal emails
-        let seqs = search_inbox_latest(&config).expect("Failed to search for latest emails!");
+    handle_shutdown_signals();
 
-        // Check for new journal emails
-        if seqs.len() > 0 {
-            println!("{} new email(s)", seqs.len());
+    // With several mailboxes, the control socket answers for the first
+    if let Some(path) = &config.control_socket {
+        control::spawn(&mailbox_configs[0], path).map_err(Error::Config)?;
+    }
 
-            let emails = fetch_emails(&config, seqs).expect("Failed to fetch emails!");
+    if mailbox_configs.len() == 1 {
+        return run_daemon(&mailbox_configs[0]);
+    }
 
-            for email in emails {
-                store_journal_email(&config, &email);
+    // Each mailbox gets a thread of its own, named after it so its log lines can be
+    // told apart. One mailbox failing doesn't stop the others.
+    let workers: Vec<_> = config
+        .mailboxes
+        .iter()
+        .zip(mailbox_configs)
+        .map(|(mailbox, mailbox_config)| {
+            std::thread::Builder::new()
+                .name(mailbox.name.clone())
+                .spawn(move || {
+                    let result = run_daemon(&mailbox_config);
+                    if let Err(error) = &result {
+                        log_error!("{}", error);
+                    }
+                    result
+                })
+                .expect("Failed to start mailbox thread!")
+        })
+        .collect();
+
+    let mut first_failure = None;
+    let mut panicked = false;
+    for worker in workers {
+        match worker.join() {
+            Ok(result) => {
+                if first_failure.is_none() {
+                    first_failure = result.err();
+                }
             }
+            Err(_) => panicked = true,
         }
+    }
 
-        // Handle journal reminder
-        if !did_remind && (utc >= remind_time) {
-            // Remind the user again in exactly 1 day
-            remind_time = remind_time.checked_add_signed(Duration::days(1)).unwrap();
-
-            did_remind = true;
-            send_reminder_email(&config);
-
-            println!(
-                "Journal reminder for {} sent. Next reminder scheduled for {}",
-                utc.to_string(),
-                remind_time.to_string()
-            );
-        }
+    // Exit the way a panic on the main thread would have
+    if panicked {
+        std::process::exit(101);
+    }
 
-        std::thread::sleep(sleep_duration);
+    match first_failure {
+        Some(failure) => Err(failure),
+        None => Ok(()),
     }
 }
 
-fn initialize_db(config: &Config) {
-    let sql_conn = Connection::open(&config.db_filename).expect("Failed to open database!");
-
-    // NOTE (Declan, 12/12/2018)
-    // I am using separate day, month, year columns in this database
-    // because SQLite does not have a sufficient DATETIME type, or functions
-    // to do complicated queries with them. Therefore it's just easier to manage
-    // each date component as an integer in our case.
-
-    sql_conn
-        .execute(
-            "CREATE TABLE IF NOT EXISTS entries (
-                  id    INTEGER PRIMARY KEY,
-                  day   INTEGER NOT NULL,
-                  month INTEGER NOT NULL,
-                  year  INTEGER NOT NULL,
-                  body  TEXT NOT NULL
-                  )",
-            NO_PARAMS,
-        )
-        .unwrap();
+/// Set when we're asked to stop, by SIGINT or SIGTERM.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
 }
 
-fn send_reminder_email(config: &Config) {
-    let mut message =
-        String::from("How was your day today? Reply to this email with your daily journal entry.");
+/// Makes SIGINT and SIGTERM stop the daemon loops cleanly, at the end of their
+/// current pass, instead of killing the process midway through storing an entry.
+fn handle_shutdown_signals() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
 
-    // Fetch past journal entries on this day and add them to the message
-    let entries = fetch_past_journal_entries(&config);
-    if entries.len() > 0 {
-        message.push_str("\n\nOn this day, one year ago:\n");
-        for entry in entries {
-            message.push_str(&format!("\"{}\"", entry.body.trim()));
-        }
+    // Safe, since the handler does nothing but set an atomic flag
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
     }
-
-    let email = EmailBuilder::new()
-        .to((config.target_email.clone(), config.target_name.clone()))
-        .from((config.journal_email.clone(), "Mail Journal"))
-        .subject("Daily Journal Entry")
-        .text(message)
-        .build()
-        .unwrap();
-
-    let mut mailer = SmtpTransport::simple_builder(&config.journal_email_smtp)
-        .unwrap()
-        .hello_name(ClientId::Domain(config.journal_email_smtp.clone()))
-        .credentials(Credentials::new(
-            config.journal_email.clone(),
-            config.journal_email_password.clone(),
-        ))
-        // Enable SMTPUTF8 if the server supports it
-        .smtp_utf8(true)
-        // Configure expected authentication mechanism
-        .authentication_mechanism(Mechanism::Plain)
-        // Enable connection reuse
-        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
-        .build();
-
-    let result = mailer.send(&email);
-    assert!(result.is_ok());
-
-    // Explicitly close the SMTP transaction as we enabled connection reuse
-    mailer.close();
 }
 
-fn send_error_email(config: &Config, msg: &str) {
-    let email = EmailBuilder::new()
-        .to((config.target_email.clone(), config.target_name.clone()))
-        .from((config.journal_email.clone(), "Mail Journal"))
-        .subject("Error")
-        .text(msg)
-        .build()
-        .unwrap();
-
-    let mut mailer = SmtpTransport::simple_builder(&config.journal_email_smtp)
-        .unwrap()
-        .hello_name(ClientId::Domain(config.journal_email_smtp.clone()))
-        .credentials(Credentials::new(
-            config.journal_email.clone(),
-            config.journal_email_password.clone(),
-        ))
-        // Enable SMTPUTF8 if the server supports it
-        .smtp_utf8(true)
-        // Configure expected authentication mechanism
-        .authentication_mechanism(Mechanism::Plain)
-        // Enable connection reuse
-        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
-        .build();
-
-    let result = mailer.send(&email);
-    assert!(result.is_ok());
-
-    // Explicitly close the SMTP transaction as we enabled connection reuse
-    mailer.close();
-}
-
-fn store_journal_email(config: &Config, email: &Email) {
-    if (email.from != config.target_email)
-        && (!email.from.contains(&format!("<{}>", config.target_email)))
-    {
-        println!("Ignoring email from {}", email.from);
-        return;
+/// Checks for new journal emails and sends the daily reminders for one mailbox,
+/// until we're asked to stop.
+fn run_daemon(config: &Config) -> error::Result<()> {
+    if config.startup_selftest {
+        run_selftest(config);
     }
 
-    let day = &email.timestamp.day().to_string();
-    let month = &email.timestamp.month().to_string();
-    let year = &email.timestamp.year().to_string();
-
-    let sql_conn = Connection::open(&config.db_filename).expect("Failed to open database!");
-
-    // We need to check if there is already an entry for this day
-    let stmt_str = format!(
-        "SELECT day, month, year FROM entries WHERE day = {} AND month = {} AND year = {}",
-        email.timestamp.day(),
-        email.timestamp.month(),
-        email.timestamp.year()
-    );
+    let utc: DateTime<Utc> = Utc::now();
+    send_welcome_email(config, utc)?;
 
-    let mut stmt = sql_conn.prepare(&stmt_str).unwrap();
-    if stmt.exists(NO_PARAMS).unwrap() {
-        println!("Journal entry for today was already submitted, ignoring new entry.");
-        send_error_email(config, "You already submitted a journal entry for today!");
+    let mut daemon = Daemon::start(config, utc)?;
+    log!("Mail Journal running.");
 
-        return;
+    let sleep_duration = Duration::milliseconds(SLEEP_TIME_SECONDS).to_std().unwrap();
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        daemon.process_once(config, Utc::now())?;
+        std::thread::sleep(sleep_duration);
     }
 
-    // Store the entry
-    sql_conn
-        .execute(
-            "INSERT INTO entries (day, month, year, body) values (?1, ?2, ?3, ?4)",
-            &[&day, &month, &year, &email.body],
-        )
-        .unwrap();
+    log!("Mail Journal stopped.");
+    Ok(())
 }
 
-fn fetch_past_journal_entries(config: &Config) -> Vec<JournalEntry> {
-    let sql_conn = Connection::open(&config.db_filename).expect("Failed to open database!");
-
-    let date = Utc::now();
-    let date = Utc
-        .ymd(date.year(), date.month(), date.day())
-        .checked_sub_signed(Duration::days(365))
-        .unwrap();
-
-    let query_str = format!(
-        "SELECT id, day, month, year, body FROM entries WHERE month = {} AND day = {} AND year = {}",
-        date.month(), date.day(), date.year()
+/// Prints what's needed to tell exactly which build is running, for bug reports.
+fn print_version() {
+    println!(
+        "mail-journal {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_HASH")
     );
-
-    let mut stmt = sql_conn.prepare(&query_str).unwrap();
-
-    let entry_iter = stmt
-        .query_map(NO_PARAMS, |row| JournalEntry {
-            _id: row.get(0),
-            _day: row.get(1),
-            _month: row.get(2),
-            _year: row.get(3),
-            body: row.get(4),
-        })
-        .unwrap()
-        .map(|s| s.unwrap());
-
-    return entry_iter.collect::<Vec<JournalEntry>>();
+    println!("ron {}", env!("RON_VERSION"));
+    println!("SQLite {}", rusqlite::version());
 }
 
-fn fetch_emails(config: &Config, seqs: HashSet<Seq>) -> imap::error::Result<Vec<Email>> {
-    let domain = config.journal_email_imap.as_str();
-    let tls = native_tls::TlsConnector::builder().build().unwrap();
+const CLI_USAGE: &str = "Usage: mail-journal [--version | --check | export --format wordtrend|words|json [--out <path>] | import --format md|json --in <path> | dedup-days [--keep all|longest]]";
 
-    // Connect to the email server and login
-    let client = imap::connect((domain, 993), domain, &tls).unwrap();
-    let mut imap_session = client
-        .login(&config.journal_email, &config.journal_email_password)
-        .map_err(|e| e.0)?;
+/// Runs a one-off command given on the command line, like an export, on `today`.
+fn run_cli(config: &Config, args: &[String], today: NaiveDate) -> error::Result<()> {
+    match args[0].as_str() {
+        "--check" => {
+            if run_selftest(config) {
+                Ok(())
+            } else {
+                Err(Error::Config("Connectivity check failed.".to_string()))
+            }
+        }
+        "export" => {
+            let mut format = None;
+            let mut out = None;
+
+            let mut options = args[1..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--format" => format = options.next(),
+                    "--out" => out = options.next(),
+                    _ => {
+                        return Err(Error::Config(format!(
+                            "Unknown option \"{}\"\n{}",
+                            option, CLI_USAGE
+                        )))
+                    }
+                }
+            }
 
-    imap_session.select("INBOX")?;
+            let storage = open_storage_with_history(config, today)?;
+            let exported = match format.map(String::as_str) {
+                Some("wordtrend") => export_word_trend(&*storage, today)?,
+                Some("words") => export_top_words(config, &*storage, config.top_words_count)?,
+                Some("json") => export_json(&*storage)?,
+                Some(format) => {
+                    return Err(Error::Config(format!(
+                        "Unknown export format \"{}\"",
+                        format
+                    )))
+                }
+                None => return Err(Error::Config(CLI_USAGE.to_string())),
+            };
+
+            let exported = encode_export(config, &exported);
+            match out {
+                Some(path) => std::fs::write(path, exported).map_err(|e| {
+                    Error::Config(format!("Failed to write export to {}: {}", path, e))
+                })?,
+                None => std::io::stdout().write_all(&exported)?,
+            }
 
-    // Construct the sequence string, which is just
-    // the email sequence numbers separated by spaces
-    let mut seq_str = String::new();
-    for seq in seqs {
-        seq_str.push_str(&format!("{},", seq));
-    }
+            Ok(())
+        }
+        "import" => {
+            let mut format = None;
+            let mut path = None;
+
+            let mut options = args[1..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--format" => format = options.next(),
+                    "--in" => path = options.next(),
+                    _ => {
+                        return Err(Error::Config(format!(
+                            "Unknown option \"{}\"\n{}",
+                            option, CLI_USAGE
+                        )))
+                    }
+                }
+            }
 
-    // Trim the extra whitespace and comma off the sequence string
-    seq_str = seq_str.trim_end().trim_end_matches(',').to_string();
+            let path = path.ok_or_else(|| Error::Config(CLI_USAGE.to_string()))?;
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| Error::Config(format!("Failed to read {}: {}", path, e)))?;
+
+            let (entries, mut skipped) = match format.map(String::as_str) {
+                Some("md") => parse_markdown_import(&text),
+                Some("json") => parse_json_import(&text).map_err(Error::Parse)?,
+                Some(format) => {
+                    return Err(Error::Config(format!(
+                        "Unknown import format \"{}\"",
+                        format
+                    )))
+                }
+                None => return Err(Error::Config(CLI_USAGE.to_string())),
+            };
+
+            // Entry files have no transaction to wrap the import in, so they're just
+            // written one by one
+            if let StorageBackend::Files { .. } = config.storage_backend {
+                let storage = open_storage(config, today.year())?;
+                let imported =
+                    import_into(config, &*storage, entries, &mut skipped).map_err(Error::Config)?;
+                print_import_summary(imported, &skipped);
+
+                return Ok(());
+            }
 
-    // Fetch emails
-    let mut emails: Vec<Email> = Vec::new();
+            let imported = import_entries(config, entries, &mut skipped).map_err(Error::Config)?;
+            print_import_summary(imported, &skipped);
 
-    println!("Fetching emails from sequence: {}", seq_str);
-    let fetched = imap_session.fetch(seq_str, "RFC822")?;
-    for m in fetched.iter() {
-        emails.push(Email::from_bytes(m.body().unwrap()));
+            Ok(())
+        }
+        "dedup-days" => dedup_days(config, &args[1..], today),
+        _ => Err(Error::Config(CLI_USAGE.to_string())),
     }
-
-    imap_session.logout()?;
-
-    Ok(emails)
 }
 
-fn search_inbox_latest(config: &Config) -> imap::error::Result<HashSet<imap::types::Seq>> {
-    let domain = config.journal_email_imap.as_str();
-    let tls = native_tls::TlsConnector::builder().build().unwrap();
+/// Merges the entries of days with more than one, in every database file.
+fn dedup_days(config: &Config, args: &[String], today: NaiveDate) -> error::Result<()> {
+    let merge = match args {
+        [] => DayMerge::Concatenate,
+        [option, keep] if option == "--keep" => match keep.as_str() {
+            "all" => DayMerge::Concatenate,
+            "longest" => DayMerge::KeepLongest,
+            _ => {
+                return Err(Error::Config(format!(
+                    "Unknown --keep \"{}\"\n{}",
+                    keep, CLI_USAGE
+                )))
+            }
+        },
+        _ => return Err(Error::Config(CLI_USAGE.to_string())),
+    };
 
-    // Connect to the email server and login
-    let client = imap::connect((domain, 993), domain, &tls).unwrap();
-    let mut imap_session = client
-        .login(&config.journal_email, &config.journal_email_password)
-        .map_err(|e| e.0)?;
+    // Entry files can only hold one entry a day
+    if let StorageBackend::Files { .. } = config.storage_backend {
+        return Err(Error::Config(
+            "dedup-days only applies to entries kept in the database.".to_string(),
+        ));
+    }
 
-    imap_session.select("INBOX")?;
+    let current_year = today.year();
+    let years: Vec<i32> = match config.db_rollover {
+        DbRollover::Single => vec![current_year],
+        DbRollover::Yearly => (current_year - 200..=current_year)
+            .filter(|&year| Path::new(&db_path(config, year)).exists())
+            .collect(),
+    };
 
-    let query = format!(
-        "UNSEEN FROM {}",
-        &config.target_email
-    );
-    let seqs = imap_session.search(query)?;
+    let mut merged = 0;
+    for year in years {
+        let mut sql_conn = open_db(config, year)?;
+        let tx = sql_conn.transaction()?;
+        merged += merge_duplicate_days(&tx, merge, &config.append_delimiter)?;
+        tx.commit()?;
+    }
 
-    imap_session.logout()?;
+    println!("Merged {} duplicate entries.", merged);
+    Ok(())
+}
 
-    Ok(seqs)
+fn print_import_summary(imported: usize, skipped: &[String]) {
+    for reason in skipped {
+        println!("Skipped {}", reason);
+    }
+    println!("Imported {} entries, skipped {}.", imported, skipped.len());
 }
@@ -0,0 +1,1099 @@
+//! Deciding when the daily reminder is due, and sending it.
+
+use crate::command::help_text;
+use crate::config::{Config, FlashbackOrder, PromptOrder, ReminderDelivery, ReminderMode};
+use crate::error::{self, Error};
+use crate::export::word_frequencies;
+use crate::journal::{self, Entry};
+use crate::mail::{send_email, send_reply, Bounce};
+use crate::store::{
+    get_state, open_current_db, open_storage, open_storage_with_history, set_state,
+};
+use chrono::prelude::*;
+use chrono::Duration;
+use lettre_email::IntoMailbox;
+use rand::Rng;
+use rusqlite::Result;
+
+/// What the reminder loop should do at a given moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReminderDecision {
+    /// The day whose reminder should be sent right now, if any.
+    pub send_for: Option<NaiveDate>,
+    /// When the next reminder after that one is due.
+    pub next: DateTime<Utc>,
+}
+
+/// Works out whether a reminder is due at `now`, given the (UTC) hour reminders go
+/// out at and when a reminder was last taken care of. A reminder taken care of up to
+/// `grace` before today's reminder time counts for today, so one sent a little early
+/// isn't repeated. This does no I/O, so whether a missed or interrupted reminder
+/// counts as taken care of is for the caller to decide. Only today's reminder is
+/// ever sent; missed days aren't made up.
+pub fn next_reminder_time(
+    now: DateTime<Utc>,
+    reminder_hour: i64,
+    grace: Duration,
+    last_reminded: Option<DateTime<Utc>>,
+) -> ReminderDecision {
+    let today = now.naive_utc().date();
+    let remind_time =
+        Utc.from_utc_datetime(&today.and_hms(0, 0, 0)) + Duration::hours(reminder_hour);
+
+    if now < remind_time {
+        return ReminderDecision {
+            send_for: None,
+            next: remind_time,
+        };
+    }
+
+    let already_reminded = last_reminded.is_some_and(|time| time >= remind_time - grace);
+    ReminderDecision {
+        send_for: if already_reminded { None } else { Some(today) },
+        next: remind_time + Duration::days(1),
+    }
+}
+
+/// Checks whether the reminder for the given day should be skipped, either because
+/// reminders are off, it falls on one of the `reminder_skip_weekdays` (or, with only
+/// digests, isn't the `digest_weekday`) or because of a `PAUSE` command.
+pub fn skip_reminder(config: &Config, date: NaiveDate) -> Result<bool> {
+    let skipped_day = match config.reminder_mode {
+        ReminderMode::Off => return Ok(true),
+        ReminderMode::Daily => config.reminder_skip_weekdays.contains(&date.weekday()),
+        ReminderMode::DigestOnly => date.weekday() != config.digest_weekday,
+    };
+    if skipped_day {
+        return Ok(true);
+    }
+
+    let sql_conn = open_current_db(config, date)?;
+
+    let paused_until = get_state(&sql_conn, "pause_until")?
+        .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok());
+    if paused_until.is_some_and(|until| date <= until) {
+        return Ok(true);
+    }
+
+    let is_weekend = date.weekday() == Weekday::Sat || date.weekday() == Weekday::Sun;
+    Ok(is_weekend && get_state(&sql_conn, "pause_weekends")?.as_deref() == Some("true"))
+}
+
+/// Records the progress of the reminder for the given day, so it survives a restart.
+pub fn set_reminder_state(
+    config: &Config,
+    date: NaiveDate,
+    status: &str,
+    time: DateTime<Utc>,
+) -> Result<()> {
+    let sql_conn = open_current_db(config, date)?;
+    set_state(&sql_conn, "reminder_date", &date.to_string())?;
+    set_state(&sql_conn, "reminder_status", status)?;
+    set_state(&sql_conn, "reminder_time", &time.to_rfc3339())
+}
+
+/// Finds when the last reminder went out, according to the saved state. A reminder
+/// that was still pending when we stopped may or may not have been sent, so
+/// `reminder_delivery` decides whether that counts.
+pub fn last_reminder_time(config: &Config, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    let sql_conn = open_current_db(config, now.naive_utc().date())?;
+    let attempted = match get_state(&sql_conn, "reminder_time")?
+        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+    {
+        Some(t) => t.with_timezone(&Utc),
+        None => return Ok(None),
+    };
+
+    let last = match get_state(&sql_conn, "reminder_status")?.as_deref() {
+        // A reminder that was given up on isn't tried again either
+        Some("sent") | Some("expired") => Some(attempted),
+        Some("pending") => match config.reminder_delivery {
+            ReminderDelivery::AtMostOnce => {
+                log!("The last reminder may not have been sent, but it won't be resent.");
+                Some(attempted)
+            }
+            ReminderDelivery::AtLeastOnce => {
+                // A send that started only moments ago most likely went through before
+                // we died, and resending then is how a crash loop spams the inbox
+                if now - attempted < Duration::minutes(config.reminder_pending_grace_minutes) {
+                    Some(attempted)
+                } else {
+                    None
+                }
+            }
+        },
+        _ => None,
+    };
+
+    Ok(last)
+}
+
+/// How long to wait between tries at sending a reminder that couldn't be sent.
+const REMINDER_RETRY_MINUTES: i64 = 5;
+
+/// Tries sending a reminder that couldn't be sent again (see `defer_reminder`),
+/// every few minutes, until it goes through or is no use anymore: once
+/// `reminder_max_defer_hours` have passed, the day is over, or the day has an
+/// entry after all.
+pub fn retry_deferred_reminder(config: &Config, now: DateTime<Utc>) -> error::Result<()> {
+    // A reminder deferred before reminders were turned off stays unsent
+    if config.reminder_mode == ReminderMode::Off {
+        return Ok(());
+    }
+
+    let today = now.naive_utc().date();
+    let sql_conn = open_current_db(config, today)?;
+    if get_state(&sql_conn, "reminder_status")?.as_deref() != Some("deferred") {
+        return Ok(());
+    }
+
+    let parse_time = |key: &str| -> Result<Option<DateTime<Utc>>> {
+        Ok(get_state(&sql_conn, key)?
+            .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+            .map(|t| t.with_timezone(&Utc)))
+    };
+    let date = get_state(&sql_conn, "reminder_date")?
+        .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok());
+    let (date, first_try) = match (date, parse_time("reminder_time")?) {
+        (Some(date), Some(first_try)) => (date, first_try),
+        _ => return Ok(()),
+    };
+
+    if parse_time("reminder_retry_time")?
+        .is_some_and(|last_try| now - last_try < Duration::minutes(REMINDER_RETRY_MINUTES))
+    {
+        return Ok(());
+    }
+
+    let expired =
+        now - first_try >= Duration::hours(config.reminder_max_defer_hours) || today != date;
+    if expired {
+        log_error!("Gave up on sending the journal reminder for {}", date);
+        return Ok(set_state(&sql_conn, "reminder_status", "expired")?);
+    }
+
+    // An entry makes the reminder moot, but not the digest
+    if config.reminder_mode == ReminderMode::Daily
+        && open_storage(config, date.year())?
+            .fetch_by_date(date)?
+            .is_some()
+    {
+        log!(
+            "Not sending the journal reminder for {}, the day already has an entry",
+            date
+        );
+        return Ok(set_state(&sql_conn, "reminder_status", "expired")?);
+    }
+
+    set_state(&sql_conn, "reminder_retry_time", &now.to_rfc3339())?;
+    match send_reminder_email(config, now) {
+        Ok(()) => {
+            set_state(&sql_conn, "reminder_status", "sent")?;
+            log!("Journal reminder for {} sent, after trying again", date);
+            Ok(())
+        }
+        Err(Error::Smtp(e)) => {
+            log_error!(
+                "Failed to send the journal reminder for {} again, will keep trying: {}",
+                date,
+                e
+            );
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Marks the reminder for the given day as not sent, to be tried again by
+/// `retry_deferred_reminder`, counting from when it was first tried.
+pub fn defer_reminder(config: &Config, date: NaiveDate, first_try: DateTime<Utc>) -> Result<()> {
+    let sql_conn = open_current_db(config, date)?;
+    set_state(&sql_conn, "reminder_retry_time", &first_try.to_rfc3339())?;
+    set_reminder_state(config, date, "deferred", first_try)
+}
+
+const DEFAULT_PROMPT: &str = "How was your day today?";
+
+const REMINDER_SUBJECT: &str = "Daily Journal Entry";
+
+/// What the reminder says, as plain text and as HTML for `reminder_html`.
+pub struct ReminderBody {
+    pub text: String,
+    pub html: String,
+}
+
+const DIGEST_SUBJECT: &str = "Your Week in the Journal";
+
+/// Sends the daily reminder, or with `DigestOnly` the weekly digest, to the target
+/// address, and anyone in `reminder_cc`.
+pub fn send_reminder_email(config: &Config, now: DateTime<Utc>) -> error::Result<()> {
+    if config.reminder_mode == ReminderMode::DigestOnly {
+        let to = (config.target_email.clone(), config.target_name.clone());
+        let body = build_digest_body(config, now.naive_utc().date())?;
+        return send_email(
+            config,
+            to,
+            DIGEST_SUBJECT,
+            &body,
+            None,
+            &config.reminder_cc,
+            None,
+        );
+    }
+
+    let body = build_reminder_body(config, now.naive_utc().date())?;
+    send_reminder(
+        config,
+        (config.target_email.clone(), config.target_name.clone()),
+        &config.reminder_cc,
+        &body,
+    )
+}
+
+/// Sends a reminder, under the usual subject so replying to it saves an entry.
+pub fn send_reminder<A: IntoMailbox>(
+    config: &Config,
+    to: A,
+    cc: &[String],
+    body: &ReminderBody,
+) -> error::Result<()> {
+    let html = if config.reminder_html {
+        Some(body.html.as_str())
+    } else {
+        None
+    };
+
+    send_email(config, to, REMINDER_SUBJECT, &body.text, html, cc, None)
+}
+
+/// Puts together the weekly digest sent on `today`: how much was written in the 7
+/// days before it, and each of those days' entries, cut short like flashbacks are.
+pub fn build_digest_body(config: &Config, today: NaiveDate) -> error::Result<String> {
+    let entries = open_storage_with_history(config, today)?
+        .fetch_between(today - Duration::days(7), today.pred())?;
+
+    let mut message = if entries.is_empty() {
+        "You didn't write any journal entries this week.".to_string()
+    } else {
+        let words: usize = entries
+            .iter()
+            .map(|e| e.body.split_whitespace().count())
+            .sum();
+        format!(
+            "This week you wrote {} journal {}, {} {} in all.",
+            entries.len(),
+            if entries.len() == 1 {
+                "entry"
+            } else {
+                "entries"
+            },
+            words,
+            if words == 1 { "word" } else { "words" }
+        )
+    };
+    message.push_str(" Reply to this email to write today's entry.");
+
+    for entry in &entries {
+        let body = entry.body.trim();
+        let body = match config.flashback_max_chars {
+            Some(max_chars) => truncate_chars(body, max_chars),
+            None => body.to_string(),
+        };
+        message.push_str(&format!(
+            "\n\n{}:\n{}",
+            entry.date.format(&config.date_format),
+            body
+        ));
+    }
+
+    if let Some(footer) = reminder_footer(config) {
+        message.push_str(&format!("\n\n{}", footer));
+    }
+
+    Ok(message)
+}
+
+/// Puts together the reminder for `today`: the prompt, and whatever else the config
+/// asks for, like the flashbacks.
+pub fn build_reminder_body(config: &Config, today: NaiveDate) -> error::Result<ReminderBody> {
+    let prompt = format!(
+        "{} Reply to this email with your daily journal entry.",
+        reminder_prompt(config, today)
+    );
+    let mut message = prompt.clone();
+    let mut html = format!("<p>{}</p>\n", escape_html(&prompt));
+
+    // Right under the prompt, so replying starts with the template to fill in
+    if let Some(template) = &config.entry_template {
+        message.push_str(&format!("\n\n{}", template.trim()));
+        html.push_str(&format!(
+            "<p>{}</p>\n",
+            escape_html(template.trim()).replace('\n', "<br>\n")
+        ));
+    }
+
+    if config.include_weekly_themes_in_reminder {
+        if let Some(themes) = weekly_themes(config, today)? {
+            message.push_str(&format!("\n\nThis week you wrote about: {}.", themes));
+            html.push_str(&format!(
+                "<p>This week you wrote about: <strong>{}</strong>.</p>\n",
+                escape_html(&themes)
+            ));
+        }
+    }
+
+    // Entries are filed under UTC dates, so yesterday is worked out in UTC too
+    if config.include_yesterday_in_reminder {
+        if let Some(entry) =
+            open_storage_with_history(config, today)?.fetch_by_date(today.pred())?
+        {
+            message.push_str(&format!(
+                "\n\nYesterday you wrote:\n\"{}\"",
+                entry.body.trim()
+            ));
+            html.push_str(&html_quote("Yesterday you wrote:", &[entry.body.trim()]));
+        }
+    }
+
+    // Fetch past journal entries on this day and add them to the message
+    let entries = fetch_past_journal_entries(config, today)?;
+    if !entries.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&render_flashbacks(config, today, &entries));
+        for (label, bodies) in flashback_sections(config, today, &entries) {
+            let bodies: Vec<&str> = bodies.iter().map(String::as_str).collect();
+            html.push_str(&html_quote(&label, &bodies));
+        }
+    }
+
+    if let Some(footer) = reminder_footer(config) {
+        message.push_str("\n\n");
+        message.push_str(&footer);
+        html.push_str(&format!(
+            "<p style=\"color: #777;\">{}</p>\n",
+            escape_html(&footer).replace('\n', "<br>\n")
+        ));
+    }
+
+    let html = format!(
+        "<html>\n<body style=\"font-family: sans-serif; line-height: 1.5;\">\n{}</body>\n</html>\n",
+        html
+    );
+
+    Ok(ReminderBody {
+        text: message,
+        html,
+    })
+}
+
+/// Sends the welcome email, the first time Mail Journal runs with an empty journal.
+/// A journal that already has entries is taken to be in use already, so it never
+/// gets one.
+pub fn send_welcome_email(config: &Config, now: DateTime<Utc>) -> error::Result<()> {
+    if !config.send_welcome_email {
+        return Ok(());
+    }
+
+    let today = now.naive_utc().date();
+    let sql_conn = open_current_db(config, today)?;
+    if get_state(&sql_conn, "welcome_sent")?.is_some() {
+        return Ok(());
+    }
+
+    if open_storage_with_history(config, today)?
+        .fetch_first_date()?
+        .is_none()
+    {
+        let message = format!(
+            "Welcome to Mail Journal!\n\nEvery day at {}:00 UTC you'll get an email asking how your day was. Just reply to it with your journal entry, and it's saved under that day. On years to come, the reminder also shows what you wrote on the same day in years past.\n\nYou can also send commands, by putting them in the subject line of an email:\n\n{}",
+            config.utc_reminder_hour,
+            help_text()
+        );
+
+        // Tried again on the next start if it doesn't go through
+        if let Err(e) = send_email(
+            config,
+            (config.target_email.clone(), config.target_name.clone()),
+            "Welcome to Mail Journal",
+            &message,
+            None,
+            &[],
+            None,
+        ) {
+            log_error!("Failed to send the welcome email: {}", e);
+            return Ok(());
+        }
+
+        log!("Sent the welcome email to {}", config.target_email);
+    }
+
+    set_state(&sql_conn, "welcome_sent", &now.to_rfc3339())?;
+    Ok(())
+}
+
+/// Deals with a delivery report. If it's for a reminder, that's worth shouting
+/// about, since the reminders will keep on bouncing until something is done.
+pub fn handle_bounce(config: &Config, bounce: &Bounce) {
+    let subject = bounce.subject.as_deref().unwrap_or("(unknown)");
+    if bounce.subject.as_deref() != Some(REMINDER_SUBJECT) {
+        log!(
+            "Ignoring a delivery report for an email titled \"{}\"",
+            subject
+        );
+        return;
+    }
+
+    if !config.detect_bounces {
+        log!("Ignoring a delivery report for a journal reminder");
+        return;
+    }
+
+    log_error!(
+        "The journal reminder to {} bounced! Reminders won't arrive until this is fixed.",
+        config.target_email
+    );
+
+    if let Some(address) = &config.backup_notify_email {
+        send_reply(
+            config,
+            address,
+            "Journal reminders are bouncing",
+            &format!(
+                "The daily journal reminder sent to {} couldn't be delivered. Reminders won't arrive until this is fixed, so check that the mailbox isn't full and the address is still right.",
+                config.target_email
+            ),
+        );
+    }
+}
+
+/// The fewest entries in the last week that are worth finding themes in.
+const MIN_THEME_ENTRIES: usize = 3;
+
+/// Lists the words used most in the entries of the 7 days before today, or returns
+/// `None` if there were too few entries to say.
+fn weekly_themes(config: &Config, today: NaiveDate) -> error::Result<Option<String>> {
+    let entries = open_storage_with_history(config, today)?
+        .fetch_between(today - Duration::days(7), today.pred())?;
+    if entries.len() < MIN_THEME_ENTRIES {
+        return Ok(None);
+    }
+
+    let words: Vec<String> = word_frequencies(&entries, &config.stopwords)
+        .into_iter()
+        .take(config.weekly_themes_count)
+        .map(|(word, _)| word)
+        .collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(words.join(", ")))
+}
+
+/// Picks the prompt that opens the reminder for the given day.
+fn reminder_prompt(config: &Config, today: NaiveDate) -> &str {
+    let prompts = &config.reminder_prompts;
+    if prompts.is_empty() {
+        return DEFAULT_PROMPT;
+    }
+
+    let index = match config.reminder_prompt_order {
+        // Counted in days since the epoch, so the turn keeps going across new years
+        PromptOrder::RoundRobin => {
+            let days = (today - NaiveDate::from_ymd(1970, 1, 1)).num_days();
+            days.rem_euclid(prompts.len() as i64) as usize
+        }
+        PromptOrder::Random => rand::thread_rng().gen_range(0, prompts.len()),
+    };
+
+    prompts[index].trim()
+}
+
+/// The footer to end the reminder with, if there is one. The file is read each
+/// time, and a reminder without its footer is better than no reminder, so if it
+/// can't be read that's only logged.
+fn reminder_footer(config: &Config) -> Option<String> {
+    let footer = match (&config.reminder_footer, &config.reminder_footer_file) {
+        (Some(footer), _) => footer.clone(),
+        (None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(footer) => footer,
+            Err(e) => {
+                log_error!("Failed to read the reminder footer from {}: {}", path, e);
+                return None;
+            }
+        },
+        (None, None) => return None,
+    };
+
+    let footer = footer.trim();
+    if footer.is_empty() {
+        None
+    } else {
+        Some(footer.to_string())
+    }
+}
+
+/// Renders the "on this day" section of the reminder. Entries are grouped by date,
+/// each group headed by the configured label, where `{years_ago}` and `{date}` are
+/// replaced with how long ago and on what date the entries were written. Dates
+/// closest to today's day of the year come first, then `flashback_order` decides.
+fn render_flashbacks(config: &Config, today: NaiveDate, entries: &[Entry]) -> String {
+    flashback_sections(config, today, entries)
+        .into_iter()
+        .map(|(label, bodies)| {
+            let quoted: Vec<String> = bodies.iter().map(|body| format!("\"{}\"", body)).collect();
+            format!("{}\n{}", label, quoted.join("\n"))
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The flashbacks in the order they're shown, each a label for the date along with
+/// the entries of that date, shortened to `flashback_max_chars`.
+fn flashback_sections(
+    config: &Config,
+    today: NaiveDate,
+    entries: &[Entry],
+) -> Vec<(String, Vec<String>)> {
+    let mut dates: Vec<NaiveDate> = entries.iter().map(|e| e.date).collect();
+    dates.sort();
+    dates.dedup();
+    if config.flashback_order == FlashbackOrder::Desc {
+        dates.reverse();
+    }
+    // A stable sort, so equally close dates keep the order above
+    dates.sort_by_key(|&date| days_from_anniversary(date, today));
+
+    dates
+        .into_iter()
+        .map(|date| {
+            // Rounded, since an entry near the turn of the year can be a few days
+            // more or less than a whole number of years ago
+            let years_ago = ((today - date).num_days() as f64 / 365.25).round();
+            let label = config
+                .flashback_label
+                .replace("{years_ago}", &years_ago.to_string())
+                .replace("{date}", &date.format(&config.date_format).to_string());
+
+            let bodies = entries
+                .iter()
+                .filter(|e| e.date == date)
+                .map(|entry| {
+                    let body = entry.body.trim();
+                    match config.flashback_max_chars {
+                        Some(max_chars) => truncate_chars(body, max_chars),
+                        None => body.to_string(),
+                    }
+                })
+                .collect();
+
+            (label, bodies)
+        })
+        .collect()
+}
+
+/// A label with the entries under it set apart as quotes, for the HTML reminder.
+fn html_quote(label: &str, bodies: &[&str]) -> String {
+    let mut html = format!(
+        "<p style=\"margin-bottom: 0.25em;\"><strong>{}</strong></p>\n",
+        escape_html(label)
+    );
+    for body in bodies {
+        html.push_str(&format!(
+            "<blockquote style=\"margin: 0 0 1em 0; padding-left: 1em; border-left: 3px solid #ccc; color: #555;\">{}</blockquote>\n",
+            escape_html(body).replace('\n', "<br>\n")
+        ));
+    }
+
+    html
+}
+
+/// Makes text safe to put in HTML, since entries can contain anything.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Shortens text to at most `max_chars` characters (not bytes, so multibyte characters
+/// are never split), cutting at the last word boundary and marking the cut.
+pub fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    let mut truncated: String = text.chars().take(max_chars).collect();
+
+    // Back off to the end of the last whole word, unless the cut already landed on one
+    let cut_mid_word = text
+        .chars()
+        .nth(max_chars)
+        .is_some_and(|c| !c.is_whitespace());
+    if cut_mid_word {
+        if let Some(ix) = truncated.rfind(char::is_whitespace) {
+            truncated.truncate(ix);
+        }
+    }
+
+    let mut truncated = truncated.trim_end().to_string();
+    truncated.push_str("… (truncated)");
+    truncated
+}
+
+/// Counts the days between a date and the nearest anniversary of `today`, in any year.
+fn days_from_anniversary(date: NaiveDate, today: NaiveDate) -> i64 {
+    (date.year() - 1..=date.year() + 1)
+        .map(|year| (date - journal::same_day_in(today, year)).num_days().abs())
+        .min()
+        .unwrap_or(0)
+}
+
+/// Fetches the entries written on this day in previous years, for the reminder,
+/// or near it if `flashback_window_days` is set.
+fn fetch_past_journal_entries(config: &Config, today: NaiveDate) -> error::Result<Vec<Entry>> {
+    let storage = open_storage_with_history(config, today)?;
+
+    match config.flashback_window_days {
+        0 => storage.fetch_on_this_day(today),
+        window_days => storage.fetch_near_this_day(today, window_days),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DbRollover;
+    use crate::store::open_db;
+    use crate::store::set_state;
+    use crate::test_util::{sent_emails, test_config, TempDir};
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    /// What `next_reminder_time` decides with reminders at 9:00 and an hour's grace.
+    fn decide(now: DateTime<Utc>, last_reminded: Option<DateTime<Utc>>) -> ReminderDecision {
+        next_reminder_time(now, 9, Duration::minutes(60), last_reminded)
+    }
+
+    #[test]
+    fn no_reminder_is_due_before_the_reminder_time() {
+        let decision = decide(at(2024, 5, 14, 8, 59), None);
+        assert_eq!(decision.send_for, None);
+        assert_eq!(decision.next, at(2024, 5, 14, 9, 0));
+    }
+
+    #[test]
+    fn the_reminder_is_due_at_the_reminder_time() {
+        let decision = decide(at(2024, 5, 14, 9, 0), None);
+        assert_eq!(decision.send_for, Some(NaiveDate::from_ymd(2024, 5, 14)));
+        assert_eq!(decision.next, at(2024, 5, 15, 9, 0));
+    }
+
+    #[test]
+    fn a_reminder_not_sent_yet_is_still_due_later_in_the_day() {
+        let yesterday = Some(at(2024, 5, 13, 9, 0));
+        let decision = decide(at(2024, 5, 14, 22, 30), yesterday);
+        assert_eq!(decision.send_for, Some(NaiveDate::from_ymd(2024, 5, 14)));
+        assert_eq!(decision.next, at(2024, 5, 15, 9, 0));
+    }
+
+    #[test]
+    fn a_reminder_already_sent_today_isnt_sent_again() {
+        let decision = decide(at(2024, 5, 14, 15, 0), Some(at(2024, 5, 14, 9, 0)));
+        assert_eq!(decision.send_for, None);
+        assert_eq!(decision.next, at(2024, 5, 15, 9, 0));
+    }
+
+    #[test]
+    fn a_reminder_sent_within_the_grace_counts_for_today() {
+        let early = Some(at(2024, 5, 14, 8, 0));
+        assert_eq!(decide(at(2024, 5, 14, 9, 0), early).send_for, None);
+
+        let too_early = Some(at(2024, 5, 14, 7, 59));
+        assert!(decide(at(2024, 5, 14, 9, 0), too_early).send_for.is_some());
+    }
+
+    #[test]
+    fn a_restart_just_after_the_reminder_doesnt_resend_it() {
+        let sent = Some(at(2024, 5, 14, 9, 0));
+        assert_eq!(decide(at(2024, 5, 14, 9, 5), sent).send_for, None);
+    }
+
+    #[test]
+    fn a_restart_the_next_day_sends_that_days_reminder() {
+        let sent = Some(at(2024, 5, 14, 9, 0));
+        assert_eq!(
+            decide(at(2024, 5, 15, 9, 5), sent).send_for,
+            Some(NaiveDate::from_ymd(2024, 5, 15))
+        );
+    }
+
+    #[test]
+    fn missed_days_only_get_todays_reminder() {
+        let last_week = Some(at(2024, 5, 7, 9, 0));
+        let decision = decide(at(2024, 5, 14, 10, 0), last_week);
+        assert_eq!(decision.send_for, Some(NaiveDate::from_ymd(2024, 5, 14)));
+        assert_eq!(decision.next, at(2024, 5, 15, 9, 0));
+    }
+
+    #[test]
+    fn the_next_reminder_crosses_month_and_year_boundaries() {
+        assert_eq!(
+            decide(at(2024, 1, 31, 12, 0), None).next,
+            at(2024, 2, 1, 9, 0)
+        );
+        assert_eq!(
+            decide(at(2024, 2, 28, 12, 0), None).next,
+            at(2024, 2, 29, 9, 0)
+        );
+        assert_eq!(
+            decide(at(2023, 2, 28, 12, 0), None).next,
+            at(2023, 3, 1, 9, 0)
+        );
+        assert_eq!(
+            decide(at(2024, 12, 31, 12, 0), None).next,
+            at(2025, 1, 1, 9, 0)
+        );
+    }
+
+    #[test]
+    fn a_reminder_at_midnight_is_due_first_thing_in_the_new_year() {
+        let new_years_eve = Some(at(2024, 12, 31, 0, 0));
+        let decision = next_reminder_time(at(2025, 1, 1, 0, 0), 0, Duration::zero(), new_years_eve);
+        assert_eq!(decision.send_for, Some(NaiveDate::from_ymd(2025, 1, 1)));
+        assert_eq!(decision.next, at(2025, 1, 2, 0, 0));
+    }
+
+    #[test]
+    fn reminders_are_skipped_on_skip_weekdays_and_when_paused() {
+        let dir = TempDir::new("skip-reminder");
+        let mut config = Config {
+            db_filename: dir.file("journal.db"),
+            reminder_skip_weekdays: vec![Weekday::Wed],
+            ..Config::default()
+        };
+        let tuesday = NaiveDate::from_ymd(2024, 5, 14);
+        let wednesday = NaiveDate::from_ymd(2024, 5, 15);
+        let saturday = NaiveDate::from_ymd(2024, 5, 18);
+        assert!(!skip_reminder(&config, tuesday).unwrap());
+        assert!(skip_reminder(&config, wednesday).unwrap());
+        assert!(!skip_reminder(&config, saturday).unwrap());
+
+        let sql_conn = open_current_db(&config, saturday).unwrap();
+        set_state(&sql_conn, "pause_weekends", "true").unwrap();
+        assert!(skip_reminder(&config, saturday).unwrap());
+
+        set_state(&sql_conn, "pause_until", "2024-05-14").unwrap();
+        assert!(skip_reminder(&config, tuesday).unwrap());
+        assert!(!skip_reminder(&config, NaiveDate::from_ymd(2024, 5, 16)).unwrap());
+
+        config.reminder_mode = ReminderMode::Off;
+        assert!(skip_reminder(&config, NaiveDate::from_ymd(2024, 5, 16)).unwrap());
+    }
+
+    #[test]
+    fn digest_only_sends_the_digest_on_its_day_and_no_daily_reminder() {
+        let dir = TempDir::new("digest_only");
+        let config = Config {
+            reminder_mode: ReminderMode::DigestOnly,
+            digest_weekday: Weekday::Sun,
+            reminder_skip_weekdays: vec![Weekday::Sun],
+            date_format: "%Y-%m-%d".to_string(),
+            ..test_config(&dir)
+        };
+
+        // May 19, 2024 is a Sunday
+        for day in 13..19 {
+            assert!(skip_reminder(&config, NaiveDate::from_ymd(2024, 5, day)).unwrap());
+        }
+        let sunday = NaiveDate::from_ymd(2024, 5, 19);
+        assert!(!skip_reminder(&config, sunday).unwrap());
+
+        let sql_conn = open_current_db(&config, sunday).unwrap();
+        let storage: &dyn crate::storage::Storage = &sql_conn;
+        storage
+            .insert_entry(NaiveDate::from_ymd(2024, 5, 11), "Too long ago")
+            .unwrap();
+        storage
+            .insert_entry(NaiveDate::from_ymd(2024, 5, 12), "Last Sunday")
+            .unwrap();
+        storage
+            .insert_entry(NaiveDate::from_ymd(2024, 5, 18), "A quiet Saturday")
+            .unwrap();
+        storage.insert_entry(sunday, "Not in this digest").unwrap();
+
+        send_reminder_email(&config, at(2024, 5, 19, 9, 0)).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), DIGEST_SUBJECT);
+        assert_eq!(
+            sent[0].body().trim(),
+            "This week you wrote 2 journal entries, 5 words in all. Reply to this email to write today's entry.\n\n2024-05-12:\nLast Sunday\n\n2024-05-18:\nA quiet Saturday"
+        );
+    }
+
+    #[test]
+    fn a_daily_reminder_is_not_a_digest() {
+        let dir = TempDir::new("daily_not_digest");
+        let config = test_config(&dir);
+        assert!(!skip_reminder(&config, NaiveDate::from_ymd(2024, 5, 14)).unwrap());
+
+        send_reminder_email(&config, at(2024, 5, 14, 9, 0)).unwrap();
+
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), REMINDER_SUBJECT);
+    }
+
+    #[test]
+    fn an_empty_week_still_gets_a_digest() {
+        let dir = TempDir::new("digest_empty");
+        let config = Config {
+            reminder_mode: ReminderMode::DigestOnly,
+            ..test_config(&dir)
+        };
+
+        assert_eq!(
+            build_digest_body(&config, NaiveDate::from_ymd(2024, 5, 19)).unwrap(),
+            "You didn't write any journal entries this week. Reply to this email to write today's entry."
+        );
+    }
+
+    fn entry(date: NaiveDate, body: &str) -> Entry {
+        Entry {
+            id: 0,
+            date,
+            body: body.to_string(),
+            is_favorite: false,
+        }
+    }
+
+    fn two_years_of_entries() -> Vec<Entry> {
+        vec![
+            entry(NaiveDate::from_ymd(2018, 6, 1), "  Two years ago  "),
+            entry(NaiveDate::from_ymd(2019, 6, 1), "One year ago"),
+        ]
+    }
+
+    #[test]
+    fn flashbacks_are_labelled_newest_first() {
+        let config = Config {
+            flashback_label: "{years_ago} year(s) ago, on {date}:".to_string(),
+            date_format: "%Y-%m-%d".to_string(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            render_flashbacks(&config, NaiveDate::from_ymd(2020, 6, 1), &two_years_of_entries()),
+            "1 year(s) ago, on 2019-06-01:\n\"One year ago\"\n\n2 year(s) ago, on 2018-06-01:\n\"Two years ago\""
+        );
+    }
+
+    #[test]
+    fn flashbacks_can_be_oldest_first_and_truncated() {
+        let config = Config {
+            flashback_label: "{years_ago}:".to_string(),
+            flashback_order: FlashbackOrder::Asc,
+            flashback_max_chars: Some(3),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            render_flashbacks(
+                &config,
+                NaiveDate::from_ymd(2020, 6, 1),
+                &two_years_of_entries()
+            ),
+            "2:\n\"Two… (truncated)\"\n\n1:\n\"One… (truncated)\""
+        );
+    }
+
+    #[test]
+    fn truncate_chars_leaves_short_text_alone() {
+        assert_eq!(truncate_chars("Short 😀", 7), "Short 😀");
+    }
+
+    #[test]
+    fn truncate_chars_never_splits_an_emoji() {
+        assert_eq!(truncate_chars("😀😀😀😀", 2), "😀😀… (truncated)");
+        assert_eq!(
+            truncate_chars("Hello 😀😀😀 world", 8),
+            "Hello… (truncated)"
+        );
+    }
+
+    #[test]
+    fn truncate_chars_keeps_a_word_that_ends_at_the_cut() {
+        assert_eq!(
+            truncate_chars("one two😀 three", 8),
+            "one two😀… (truncated)"
+        );
+    }
+
+    #[test]
+    fn flashbacks_come_from_every_yearly_database() {
+        let dir = TempDir::new("flashbacks-across-files");
+        let config = Config {
+            db_filename: dir.file("journal-{year}.db"),
+            db_rollover: DbRollover::Yearly,
+            ..Config::default()
+        };
+
+        let today = NaiveDate::from_ymd(2024, 6, 1);
+        for years_ago in 1..=2 {
+            let year = today.year() - years_ago;
+            let sql_conn = open_db(&config, year).unwrap();
+            let body = format!("{} years ago", years_ago);
+            journal::insert_entry(&sql_conn, journal::same_day_in(today, year), &body).unwrap();
+        }
+
+        let mut bodies: Vec<String> = fetch_past_journal_entries(&config, today)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.body)
+            .collect();
+        bodies.sort();
+        assert_eq!(bodies, vec!["1 years ago", "2 years ago"]);
+    }
+
+    /// The `reminder_status` left in the state of the current database.
+    fn reminder_status(config: &Config, now: DateTime<Utc>) -> Option<String> {
+        get_state(&open_db(config, now.year()).unwrap(), "reminder_status").unwrap()
+    }
+
+    #[test]
+    fn a_deferred_reminder_is_sent_when_tried_again() {
+        let dir = TempDir::new("reminder-deferred");
+        let config = test_config(&dir);
+        let first_try = at(2024, 5, 14, 9, 0);
+        defer_reminder(&config, first_try.naive_utc().date(), first_try).unwrap();
+
+        // Not before it's time to try again
+        retry_deferred_reminder(&config, at(2024, 5, 14, 9, 2)).unwrap();
+        assert!(sent_emails().is_empty());
+
+        let later = at(2024, 5, 14, 9, 10);
+        retry_deferred_reminder(&config, later).unwrap();
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject(), REMINDER_SUBJECT);
+        assert_eq!(reminder_status(&config, later).as_deref(), Some("sent"));
+
+        retry_deferred_reminder(&config, at(2024, 5, 14, 9, 30)).unwrap();
+        assert!(sent_emails().is_empty());
+    }
+
+    #[test]
+    fn a_deferred_reminder_expires_after_reminder_max_defer_hours() {
+        let dir = TempDir::new("reminder-deferred-expired");
+        let config = test_config(&dir);
+        let first_try = at(2024, 5, 14, 9, 0);
+        defer_reminder(&config, first_try.naive_utc().date(), first_try).unwrap();
+
+        let too_late = first_try + Duration::hours(config.reminder_max_defer_hours);
+        retry_deferred_reminder(&config, too_late).unwrap();
+        assert!(sent_emails().is_empty());
+        assert_eq!(
+            reminder_status(&config, too_late).as_deref(),
+            Some("expired")
+        );
+    }
+
+    #[test]
+    fn a_deferred_reminder_expires_once_the_day_has_an_entry() {
+        let dir = TempDir::new("reminder-deferred-entry");
+        let config = test_config(&dir);
+        let first_try = at(2024, 5, 14, 9, 0);
+        let date = first_try.naive_utc().date();
+        defer_reminder(&config, date, first_try).unwrap();
+        open_storage(&config, 2024)
+            .unwrap()
+            .insert_entry(date, "Wrote it before the reminder got through")
+            .unwrap();
+
+        let later = at(2024, 5, 14, 9, 10);
+        retry_deferred_reminder(&config, later).unwrap();
+        assert!(sent_emails().is_empty());
+        assert_eq!(reminder_status(&config, later).as_deref(), Some("expired"));
+    }
+
+    #[test]
+    fn the_welcome_email_is_sent_once() {
+        let dir = TempDir::new("welcome-once");
+        let config = test_config(&dir);
+
+        send_welcome_email(&config, at(2024, 5, 14, 9, 0)).unwrap();
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, vec!["me@example.com".to_string()]);
+        assert_eq!(sent[0].subject(), "Welcome to Mail Journal");
+        assert!(sent[0].body().contains("HELP: This list of commands"));
+
+        send_welcome_email(&config, at(2024, 5, 15, 9, 0)).unwrap();
+        assert!(sent_emails().is_empty());
+    }
+
+    #[test]
+    fn a_journal_with_entries_gets_no_welcome_email() {
+        let dir = TempDir::new("welcome-in-use");
+        let config = test_config(&dir);
+        open_storage(&config, 2024)
+            .unwrap()
+            .insert_entry(
+                NaiveDate::from_ymd(2024, 5, 1),
+                "Written before the upgrade",
+            )
+            .unwrap();
+
+        send_welcome_email(&config, at(2024, 5, 14, 9, 0)).unwrap();
+        assert!(sent_emails().is_empty());
+    }
+
+    #[test]
+    fn the_reminder_footer_can_be_set_inline() {
+        let dir = TempDir::new("footer-inline");
+        let mut config = test_config(&dir);
+        config.reminder_footer = Some("  Sent with love  \n".to_string());
+        assert_eq!(reminder_footer(&config).as_deref(), Some("Sent with love"));
+
+        let body = build_reminder_body(&config, NaiveDate::from_ymd(2024, 5, 14)).unwrap();
+        assert!(body.text.ends_with("\n\nSent with love"));
+    }
+
+    #[test]
+    fn the_reminder_footer_can_come_from_a_file() {
+        let dir = TempDir::new("footer-file");
+        let mut config = test_config(&dir);
+        let path = dir.file("footer.txt");
+        std::fs::write(&path, "Unsubscribe by replying PAUSE 365\n").unwrap();
+        config.reminder_footer_file = Some(path.clone());
+        assert_eq!(
+            reminder_footer(&config).as_deref(),
+            Some("Unsubscribe by replying PAUSE 365")
+        );
+
+        // Read each time, so changes show up without a restart
+        std::fs::write(&path, "Keep writing!").unwrap();
+        assert_eq!(reminder_footer(&config).as_deref(), Some("Keep writing!"));
+    }
+
+    #[test]
+    fn an_unreadable_or_blank_footer_is_left_out() {
+        let dir = TempDir::new("footer-missing");
+        let mut config = test_config(&dir);
+        config.reminder_footer_file = Some(dir.file("gone.txt"));
+        assert_eq!(reminder_footer(&config), None);
+
+        let body = build_reminder_body(&config, NaiveDate::from_ymd(2024, 5, 14)).unwrap();
+        assert!(body
+            .text
+            .ends_with("Reply to this email with your daily journal entry."));
+
+        config.reminder_footer_file = None;
+        config.reminder_footer = Some(" \n ".to_string());
+        assert_eq!(reminder_footer(&config), None);
+    }
+}
@@ -0,0 +1,645 @@
+//! Where journal entries are kept: either the SQLite database, or a directory of
+//! Markdown files, one per day. Both are used through the `Storage` trait.
+
+use crate::error::Result;
+use crate::journal::{self, Entry};
+use chrono::prelude::*;
+use chrono::Duration;
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+
+/// Storage and lookup of journal entries. The queries have default implementations
+/// built on `fetch_all`, which backends can replace with something quicker.
+pub trait Storage {
+    /// Fetches every entry, oldest first.
+    fn fetch_all(&self) -> Result<Vec<Entry>>;
+
+    /// Fetches the entry written on the given date, if there is one.
+    fn fetch_by_date(&self, date: NaiveDate) -> Result<Option<Entry>>;
+
+    /// Stores a new entry for the given date, returning its id.
+    fn insert_entry(&self, date: NaiveDate, body: &str) -> Result<i64>;
+
+    /// Replaces the body of an existing entry.
+    fn update_body(&self, id: i64, body: &str) -> Result<()>;
+
+    /// Deletes the entry for the given date, returning what was removed.
+    fn delete_by_date(&self, date: NaiveDate) -> Result<Option<Entry>>;
+
+    /// Stars or unstars the entry for the given date. Returns false if there is no
+    /// entry for that date.
+    fn set_favorite(&self, date: NaiveDate, is_favorite: bool) -> Result<bool>;
+
+    /// Tags an entry. Tagging an entry with a tag it already has does nothing.
+    fn add_tag(&self, id: i64, tag: &str) -> Result<()>;
+
+    /// Fetches the tags of an entry, in alphabetical order.
+    fn fetch_tags(&self, id: i64) -> Result<Vec<String>>;
+
+    /// Fetches all entries written between `start` and `end` (inclusive), oldest first.
+    fn fetch_between(&self, start: NaiveDate, end: NaiveDate) -> Result<Vec<Entry>> {
+        let entries = self.fetch_all()?;
+        Ok(entries
+            .into_iter()
+            .filter(|e| e.date >= start && e.date <= end)
+            .collect())
+    }
+
+    /// Fetches the date of the oldest entry, if there are any entries at all.
+    fn fetch_first_date(&self) -> Result<Option<NaiveDate>> {
+        Ok(self.fetch_all()?.first().map(|e| e.date))
+    }
+
+    /// Fetches every starred entry, oldest first.
+    fn fetch_favorites(&self) -> Result<Vec<Entry>> {
+        let entries = self.fetch_all()?;
+        Ok(entries.into_iter().filter(|e| e.is_favorite).collect())
+    }
+
+    /// Fetches the entries written on the same day as `date` in previous years, oldest first.
+    fn fetch_on_this_day(&self, date: NaiveDate) -> Result<Vec<Entry>> {
+        let entries = self.fetch_all()?;
+        Ok(entries
+            .into_iter()
+            .filter(|e| {
+                e.date.day() == date.day()
+                    && e.date.month() == date.month()
+                    && e.date.year() < date.year()
+            })
+            .collect())
+    }
+
+    /// Fetches the entries written within `window_days` of the same day as `date` in
+    /// previous years, oldest first.
+    fn fetch_near_this_day(&self, date: NaiveDate, window_days: i64) -> Result<Vec<Entry>> {
+        let first = match self.fetch_first_date()? {
+            Some(first) => first,
+            None => return Ok(Vec::new()),
+        };
+
+        let window = Duration::days(window_days);
+        let mut entries = Vec::new();
+        for year in first.year() - 1..date.year() {
+            let anniversary = journal::same_day_in(date, year);
+            entries.extend(self.fetch_between(anniversary - window, anniversary + window)?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Fetches every entry whose body contains `term` (ignoring case), oldest first.
+    fn search(&self, term: &str) -> Result<Vec<Entry>> {
+        let term = term.to_lowercase();
+        let entries = self.fetch_all()?;
+        Ok(entries
+            .into_iter()
+            .filter(|e| e.body.to_lowercase().contains(&term))
+            .collect())
+    }
+}
+
+impl Storage for Connection {
+    fn fetch_all(&self) -> Result<Vec<Entry>> {
+        Ok(journal::fetch_all(self)?)
+    }
+
+    fn fetch_by_date(&self, date: NaiveDate) -> Result<Option<Entry>> {
+        Ok(journal::fetch_by_date(self, date)?)
+    }
+
+    fn insert_entry(&self, date: NaiveDate, body: &str) -> Result<i64> {
+        Ok(journal::insert_entry(self, date, body)?)
+    }
+
+    fn update_body(&self, id: i64, body: &str) -> Result<()> {
+        Ok(journal::update_body(self, id, body)?)
+    }
+
+    fn delete_by_date(&self, date: NaiveDate) -> Result<Option<Entry>> {
+        Ok(journal::delete_by_date(self, date)?)
+    }
+
+    fn set_favorite(&self, date: NaiveDate, is_favorite: bool) -> Result<bool> {
+        Ok(journal::set_favorite(self, date, is_favorite)?)
+    }
+
+    fn add_tag(&self, id: i64, tag: &str) -> Result<()> {
+        Ok(journal::add_tag(self, id, tag)?)
+    }
+
+    fn fetch_tags(&self, id: i64) -> Result<Vec<String>> {
+        Ok(journal::fetch_tags(self, id)?)
+    }
+
+    fn fetch_between(&self, start: NaiveDate, end: NaiveDate) -> Result<Vec<Entry>> {
+        Ok(journal::fetch_between(self, start, end)?)
+    }
+
+    fn fetch_first_date(&self) -> Result<Option<NaiveDate>> {
+        Ok(journal::fetch_first_date(self)?)
+    }
+
+    fn fetch_favorites(&self) -> Result<Vec<Entry>> {
+        Ok(journal::fetch_favorites(self)?)
+    }
+
+    fn fetch_on_this_day(&self, date: NaiveDate) -> Result<Vec<Entry>> {
+        Ok(journal::fetch_on_this_day(self, date)?)
+    }
+
+    fn fetch_near_this_day(&self, date: NaiveDate, window_days: i64) -> Result<Vec<Entry>> {
+        Ok(journal::fetch_near_this_day(self, date, window_days)?)
+    }
+
+    fn search(&self, term: &str) -> Result<Vec<Entry>> {
+        Ok(journal::search(self, term)?)
+    }
+}
+
+/// Keeps each entry in a Markdown file of its own, at `dir/YYYY/MM/DD.md`. Favorites
+/// and tags go in a small header at the top of the file, which is left out until
+/// an entry has either. An entry's id is its date, as a YYYYMMDD number.
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+/// What an entry file holds.
+struct EntryFile {
+    body: String,
+    is_favorite: bool,
+    tags: Vec<String>,
+}
+
+const HEADER_FENCE: &str = "---";
+
+impl FileStorage {
+    pub fn new(dir: &str) -> FileStorage {
+        FileStorage {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    /// The file the entry for the given date is kept in.
+    pub fn path(&self, date: NaiveDate) -> PathBuf {
+        self.dir
+            .join(format!("{:04}", date.year()))
+            .join(format!("{:02}", date.month()))
+            .join(format!("{:02}.md", date.day()))
+    }
+
+    fn read(&self, date: NaiveDate) -> Result<Option<EntryFile>> {
+        match std::fs::read_to_string(self.path(date)) {
+            Ok(text) => Ok(Some(parse_entry_file(&text))),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, date: NaiveDate, file: &EntryFile) -> Result<()> {
+        let path = self.path(date);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Written to the side and then moved into place, so that an entry is never
+        // left half written
+        let temp_path = path.with_extension("md.tmp");
+        std::fs::write(&temp_path, render_entry_file(file))?;
+        std::fs::rename(&temp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn entry(date: NaiveDate, file: EntryFile) -> Entry {
+        Entry {
+            id: date_id(date),
+            date,
+            body: file.body,
+            is_favorite: file.is_favorite,
+        }
+    }
+
+    /// Lists the dates of every entry file, oldest first.
+    fn dates(&self) -> Result<Vec<NaiveDate>> {
+        let mut dates = Vec::new();
+        for year in numbered_children(&self.dir, "")? {
+            let year_dir = self.dir.join(format!("{:04}", year));
+            for month in numbered_children(&year_dir, "")? {
+                let month_dir = year_dir.join(format!("{:02}", month));
+                for day in numbered_children(&month_dir, ".md")? {
+                    if let Some(date) = NaiveDate::from_ymd_opt(year, month as u32, day as u32) {
+                        dates.push(date);
+                    }
+                }
+            }
+        }
+
+        dates.sort();
+        Ok(dates)
+    }
+}
+
+impl Storage for FileStorage {
+    fn fetch_all(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for date in self.dates()? {
+            if let Some(file) = self.read(date)? {
+                entries.push(FileStorage::entry(date, file));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn fetch_by_date(&self, date: NaiveDate) -> Result<Option<Entry>> {
+        Ok(self.read(date)?.map(|file| FileStorage::entry(date, file)))
+    }
+
+    fn insert_entry(&self, date: NaiveDate, body: &str) -> Result<i64> {
+        self.write(
+            date,
+            &EntryFile {
+                body: body.to_string(),
+                is_favorite: false,
+                tags: Vec::new(),
+            },
+        )?;
+
+        Ok(date_id(date))
+    }
+
+    fn update_body(&self, id: i64, body: &str) -> Result<()> {
+        if let Some(date) = id_date(id) {
+            if let Some(mut file) = self.read(date)? {
+                file.body = body.to_string();
+                self.write(date, &file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_by_date(&self, date: NaiveDate) -> Result<Option<Entry>> {
+        let entry = self.fetch_by_date(date)?;
+        if entry.is_some() {
+            std::fs::remove_file(self.path(date))?;
+        }
+
+        Ok(entry)
+    }
+
+    fn set_favorite(&self, date: NaiveDate, is_favorite: bool) -> Result<bool> {
+        match self.read(date)? {
+            Some(mut file) => {
+                file.is_favorite = is_favorite;
+                self.write(date, &file)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn add_tag(&self, id: i64, tag: &str) -> Result<()> {
+        if let Some(date) = id_date(id) {
+            if let Some(mut file) = self.read(date)? {
+                if !file.tags.iter().any(|t| t == tag) {
+                    file.tags.push(tag.to_string());
+                    self.write(date, &file)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fetch_tags(&self, id: i64) -> Result<Vec<String>> {
+        let mut tags = match id_date(id) {
+            Some(date) => self.read(date)?.map(|file| file.tags).unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        tags.sort();
+        Ok(tags)
+    }
+}
+
+fn date_id(date: NaiveDate) -> i64 {
+    i64::from(date.year()) * 10000 + i64::from(date.month()) * 100 + i64::from(date.day())
+}
+
+fn id_date(id: i64) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(
+        (id / 10000) as i32,
+        (id / 100 % 100) as u32,
+        (id % 100) as u32,
+    )
+}
+
+/// Lists the numbers that the entries of a directory are named with, like the `2019`
+/// directory or the `31.md` file, leaving out anything else.
+fn numbered_children(dir: &Path, suffix: &str) -> Result<Vec<i32>> {
+    let children = match std::fs::read_dir(dir) {
+        Ok(children) => children,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut numbers = Vec::new();
+    for child in children {
+        let name = child?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(suffix))
+            .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|name| name.parse().ok());
+        if let Some(number) = number {
+            numbers.push(number);
+        }
+    }
+
+    Ok(numbers)
+}
+
+fn parse_entry_file(text: &str) -> EntryFile {
+    let mut file = EntryFile {
+        body: text.to_string(),
+        is_favorite: false,
+        tags: Vec::new(),
+    };
+
+    // Keeps the newline after the opening fence, so an empty header still ends in
+    // a fence on a line of its own
+    let rest = match text.strip_prefix(HEADER_FENCE) {
+        Some(rest) if rest.starts_with('\n') => rest,
+        _ => return file,
+    };
+
+    let end = match rest.find(&format!("\n{}\n", HEADER_FENCE)) {
+        Some(end) => end,
+        None => return file,
+    };
+
+    for line in rest[..end].lines() {
+        match line.split_once(':') {
+            Some(("favorite", value)) => file.is_favorite = value.trim() == "true",
+            Some(("tags", value)) => {
+                file.tags = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+
+    file.body = rest[end + HEADER_FENCE.len() + 2..].to_string();
+    file
+}
+
+fn render_entry_file(file: &EntryFile) -> String {
+    // A body that starts like a header needs one in front of it, so it isn't read
+    // as one
+    if !file.is_favorite && file.tags.is_empty() && !file.body.starts_with(HEADER_FENCE) {
+        return file.body.clone();
+    }
+
+    let mut text = format!("{}\n", HEADER_FENCE);
+    if file.is_favorite {
+        text.push_str("favorite: true\n");
+    }
+    if !file.tags.is_empty() {
+        text.push_str(&format!("tags: {}\n", file.tags.join(", ")));
+    }
+    text.push_str(&format!("{}\n{}", HEADER_FENCE, file.body));
+
+    text
+}
+
+/// The same tests are run against both backends, since they have to behave the same.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    fn bodies(entries: Vec<Entry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.body).collect()
+    }
+
+    fn fetches_an_entry_by_its_date(storage: &dyn Storage) {
+        let id = storage
+            .insert_entry(date(2020, 5, 17), "A good day")
+            .unwrap();
+
+        let entry = storage.fetch_by_date(date(2020, 5, 17)).unwrap().unwrap();
+        assert_eq!(entry.id, id);
+        assert_eq!(entry.date, date(2020, 5, 17));
+        assert_eq!(entry.body, "A good day");
+        assert!(!entry.is_favorite);
+        assert_eq!(storage.fetch_by_date(date(2020, 5, 18)).unwrap(), None);
+    }
+
+    fn keeps_a_body_that_looks_like_a_header(storage: &dyn Storage) {
+        let body = "---\nfavorite: true\n---\nNot a header";
+        storage.insert_entry(date(2020, 5, 17), body).unwrap();
+
+        let entry = storage.fetch_by_date(date(2020, 5, 17)).unwrap().unwrap();
+        assert_eq!(entry.body, body);
+        assert!(!entry.is_favorite);
+    }
+
+    fn updates_the_body(storage: &dyn Storage) {
+        let id = storage.insert_entry(date(2020, 5, 17), "Before").unwrap();
+        storage.update_body(id, "After").unwrap();
+
+        let entry = storage.fetch_by_date(date(2020, 5, 17)).unwrap().unwrap();
+        assert_eq!(entry.body, "After");
+    }
+
+    fn deletes_an_entry(storage: &dyn Storage) {
+        storage
+            .insert_entry(date(2020, 5, 17), "Gone soon")
+            .unwrap();
+
+        let deleted = storage.delete_by_date(date(2020, 5, 17)).unwrap().unwrap();
+        assert_eq!(deleted.body, "Gone soon");
+        assert_eq!(storage.fetch_by_date(date(2020, 5, 17)).unwrap(), None);
+        assert_eq!(storage.delete_by_date(date(2020, 5, 17)).unwrap(), None);
+    }
+
+    fn stars_entries(storage: &dyn Storage) {
+        storage.insert_entry(date(2021, 1, 2), "Newer").unwrap();
+        storage.insert_entry(date(2020, 1, 2), "Older").unwrap();
+        storage
+            .insert_entry(date(2020, 6, 1), "Not starred")
+            .unwrap();
+
+        assert!(storage.set_favorite(date(2021, 1, 2), true).unwrap());
+        assert!(storage.set_favorite(date(2020, 1, 2), true).unwrap());
+        assert!(!storage.set_favorite(date(2019, 1, 1), true).unwrap());
+        assert_eq!(
+            bodies(storage.fetch_favorites().unwrap()),
+            vec!["Older", "Newer"]
+        );
+        assert!(
+            storage
+                .fetch_by_date(date(2020, 1, 2))
+                .unwrap()
+                .unwrap()
+                .is_favorite
+        );
+
+        storage.set_favorite(date(2021, 1, 2), false).unwrap();
+        assert_eq!(bodies(storage.fetch_favorites().unwrap()), vec!["Older"]);
+    }
+
+    fn tags_entries(storage: &dyn Storage) {
+        let id = storage.insert_entry(date(2020, 5, 17), "Tagged").unwrap();
+        for tag in &["work", "family", "work"] {
+            storage.add_tag(id, tag).unwrap();
+        }
+        storage.update_body(id, "Still tagged").unwrap();
+
+        assert_eq!(storage.fetch_tags(id).unwrap(), vec!["family", "work"]);
+        let entry = storage.fetch_by_date(date(2020, 5, 17)).unwrap().unwrap();
+        assert_eq!(entry.body, "Still tagged");
+    }
+
+    fn lists_entries_oldest_first(storage: &dyn Storage) {
+        assert!(storage.fetch_all().unwrap().is_empty());
+        assert_eq!(storage.fetch_first_date().unwrap(), None);
+
+        storage.insert_entry(date(2020, 10, 2), "Third").unwrap();
+        storage.insert_entry(date(2019, 12, 31), "First").unwrap();
+        storage.insert_entry(date(2020, 2, 10), "Second").unwrap();
+
+        assert_eq!(
+            bodies(storage.fetch_all().unwrap()),
+            vec!["First", "Second", "Third"]
+        );
+        assert_eq!(
+            storage.fetch_first_date().unwrap(),
+            Some(date(2019, 12, 31))
+        );
+    }
+
+    fn fetches_between_dates(storage: &dyn Storage) {
+        storage.insert_entry(date(2020, 1, 31), "Before").unwrap();
+        storage.insert_entry(date(2020, 3, 1), "End").unwrap();
+        storage.insert_entry(date(2020, 2, 1), "Start").unwrap();
+        storage.insert_entry(date(2020, 3, 2), "After").unwrap();
+
+        assert_eq!(
+            bodies(
+                storage
+                    .fetch_between(date(2020, 2, 1), date(2020, 3, 1))
+                    .unwrap()
+            ),
+            vec!["Start", "End"]
+        );
+    }
+
+    fn fetches_on_this_day(storage: &dyn Storage) {
+        storage.insert_entry(date(2019, 3, 4), "2019").unwrap();
+        storage.insert_entry(date(2018, 3, 4), "2018").unwrap();
+        storage
+            .insert_entry(date(2019, 3, 5), "Another day")
+            .unwrap();
+        storage.insert_entry(date(2020, 3, 4), "Today").unwrap();
+
+        assert_eq!(
+            bodies(storage.fetch_on_this_day(date(2020, 3, 4)).unwrap()),
+            vec!["2018", "2019"]
+        );
+    }
+
+    fn fetches_near_this_day_across_the_new_year(storage: &dyn Storage) {
+        assert!(storage
+            .fetch_near_this_day(date(2020, 1, 1), 3)
+            .unwrap()
+            .is_empty());
+
+        storage
+            .insert_entry(date(2018, 12, 30), "Just before")
+            .unwrap();
+        storage
+            .insert_entry(date(2019, 1, 3), "Just after")
+            .unwrap();
+        storage.insert_entry(date(2019, 1, 10), "Too far").unwrap();
+
+        assert_eq!(
+            bodies(storage.fetch_near_this_day(date(2020, 1, 1), 3).unwrap()),
+            vec!["Just before", "Just after"]
+        );
+    }
+
+    fn searches_ignoring_case(storage: &dyn Storage) {
+        storage
+            .insert_entry(date(2020, 1, 1), "Went to the Beach")
+            .unwrap();
+        storage
+            .insert_entry(date(2020, 1, 2), "Got 100% on the test")
+            .unwrap();
+        storage
+            .insert_entry(date(2020, 1, 3), "Got 100 points")
+            .unwrap();
+
+        assert_eq!(
+            bodies(storage.search("beach").unwrap()),
+            vec!["Went to the Beach"]
+        );
+        assert_eq!(
+            bodies(storage.search("100%").unwrap()),
+            vec!["Got 100% on the test"]
+        );
+        assert!(storage.search("mountain").unwrap().is_empty());
+    }
+
+    /// Runs each of the tests against an in-memory database and against a directory
+    /// of entry files.
+    macro_rules! storage_tests {
+        ($($test:ident),* $(,)?) => {
+            mod sqlite {
+                use crate::journal;
+                use rusqlite::Connection;
+                $(
+                    #[test]
+                    fn $test() {
+                        let sql_conn = Connection::open_in_memory().unwrap();
+                        journal::create_table(&sql_conn).unwrap();
+                        super::$test(&sql_conn);
+                    }
+                )*
+            }
+
+            mod files {
+                use crate::test_util::TempDir;
+                $(
+                    #[test]
+                    fn $test() {
+                        let dir = TempDir::new(concat!("storage-", stringify!($test)));
+                        super::$test(&crate::storage::FileStorage::new(&dir.file("entries")));
+                    }
+                )*
+            }
+        };
+    }
+
+    storage_tests!(
+        fetches_an_entry_by_its_date,
+        keeps_a_body_that_looks_like_a_header,
+        updates_the_body,
+        deletes_an_entry,
+        stars_entries,
+        tags_entries,
+        lists_entries_oldest_first,
+        fetches_between_dates,
+        fetches_on_this_day,
+        fetches_near_this_day_across_the_new_year,
+        searches_ignoring_case,
+    );
+}
@@ -0,0 +1,1658 @@
+//! The database, and storing incoming journal entries.
+
+use crate::body::BodyProcessor;
+use crate::command::entry_ref;
+use crate::config::{Config, DbRollover, DuplicatePolicy, StorageBackend};
+use crate::error::{self, Error};
+use crate::hook::run_post_store_hook;
+use crate::journal;
+use crate::mail::{plus_tag, send_reply, Attachment, Email};
+use crate::storage::{FileStorage, Storage};
+use chrono::prelude::*;
+use chrono::Duration;
+use rusqlite::{Connection, Result, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+/// How alike two entries have to be (as a share of the words they use) to be taken
+/// for the same email delivered twice.
+const REDELIVERY_SIMILARITY: f64 = 0.95;
+
+/// How the entry saved in place of an email over `max_email_bytes` starts.
+const TOO_LARGE_NOTE: &str = "[The email for this entry was too large to save";
+
+pub fn initialize_db(config: &Config, today: NaiveDate) -> Result<()> {
+    let sql_conn = open_current_db(config, today)?;
+
+    // Everything still works, but nothing stops another entry for those days
+    if let Some(warning) = duplicate_days_warning(&sql_conn, &db_path(config, today.year()))? {
+        log_error!("{}", warning);
+    }
+
+    Ok(())
+}
+
+/// What to warn about a database with days that have more than one entry, if it
+/// has any.
+fn duplicate_days_warning(sql_conn: &Connection, path: &str) -> Result<Option<String>> {
+    let duplicate_days = journal::count_duplicate_days(sql_conn)?;
+    if duplicate_days == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "Warning: {} has {} day(s) with more than one entry, so one entry a day can't be enforced. Run `mail-journal dedup-days` to merge them.",
+        path, duplicate_days
+    )))
+}
+
+/// Gets the path of the database file that holds the entries for the given year.
+/// With `db_rollover: Yearly`, `{year}` in `db_filename` is replaced with the year,
+/// so each year gets a file of its own.
+pub fn db_path(config: &Config, year: i32) -> String {
+    match config.db_rollover {
+        DbRollover::Single => config.db_filename.clone(),
+        DbRollover::Yearly => config.db_filename.replace("{year}", &year.to_string()),
+    }
+}
+
+/// Opens the database for the current year, the year of `today`, which is also
+/// where state is kept (see `carry_over_state`).
+pub fn open_current_db(config: &Config, today: NaiveDate) -> Result<Connection> {
+    open_db(config, today.year())
+}
+
+/// Opens the database that holds the entries for the given year, creating its
+/// tables if it is new.
+pub fn open_db(config: &Config, year: i32) -> Result<Connection> {
+    let path = db_path(config, year);
+    let is_new = !Path::new(&path).exists();
+    let sql_conn = Connection::open(&path)?;
+
+    // Before anything is written to a new file
+    if let Some(mode) = config.db_file_mode {
+        restrict_db_files(&path, mode);
+    }
+
+    create_tables(&sql_conn)?;
+
+    if is_new && config.db_rollover == DbRollover::Yearly {
+        carry_over_state(config, &sql_conn, year)?;
+    }
+
+    Ok(sql_conn)
+}
+
+/// Copies the state into a new yearly database from the latest year before it, so
+/// that things like a pause, the replies queued during quiet hours and the reminder
+/// status aren't lost when the year rolls over.
+fn carry_over_state(config: &Config, sql_conn: &Connection, year: i32) -> Result<()> {
+    let previous = (year - 200..year)
+        .rev()
+        .find(|&previous| Path::new(&db_path(config, previous)).exists());
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+
+    let uri = format!("file:{}?mode=ro", db_path(config, previous));
+    sql_conn.execute("ATTACH DATABASE ?1 AS previous", [uri])?;
+
+    // Files from before state was kept have nothing to carry over
+    if journal::has_column(sql_conn, "previous", "state", "key")? {
+        let carried = sql_conn.execute(
+            "INSERT OR IGNORE INTO state (key, value) SELECT key, value FROM previous.state",
+            NO_PARAMS,
+        )?;
+        log!(
+            "Carried {} state values over from {} into {}",
+            carried,
+            previous,
+            year
+        );
+    }
+
+    sql_conn.execute("DETACH DATABASE previous", NO_PARAMS)?;
+
+    Ok(())
+}
+
+/// Gives the database file, and any of SQLite's files alongside it, the permissions
+/// of `db_file_mode`. SQLite creates new journal files with the permissions of the
+/// database, so only ones left from before the mode was set need changing. The
+/// database still works either way, so a file that can't be changed is only logged.
+fn restrict_db_files(path: &str, mode: u32) {
+    for suffix in &["", "-journal", "-wal", "-shm"] {
+        let file = format!("{}{}", path, suffix);
+        let permissions = match fs::metadata(&file) {
+            Ok(metadata) => metadata.permissions(),
+            Err(_) => continue,
+        };
+
+        if permissions.mode() & 0o777 != mode {
+            if let Err(e) = fs::set_permissions(&file, fs::Permissions::from_mode(mode)) {
+                log_error!("Failed to set the permissions of {}: {}", file, e);
+            }
+        }
+    }
+}
+
+/// The most databases SQLite will attach to a single connection.
+const MAX_ATTACHED: usize = 10;
+
+/// Opens the current year's database for reading the whole journal. With yearly
+/// files, the files of previous years are attached read-only, and a temporary
+/// `entries` view (which takes precedence over the table of the same name) joins
+/// them all together, so the usual queries see every year. The connection must
+/// not be used for writing entries.
+pub fn open_db_with_history(config: &Config, today: NaiveDate) -> Result<Connection> {
+    let sql_conn = open_current_db(config, today)?;
+    if config.db_rollover == DbRollover::Single {
+        return Ok(sql_conn);
+    }
+
+    let current_year = today.year();
+    let mut years: Vec<i32> = (current_year - 200..current_year)
+        .filter(|&year| Path::new(&db_path(config, year)).exists())
+        .collect();
+
+    if years.len() > MAX_ATTACHED {
+        log!(
+            "Warning: only the {} most recent yearly databases can be read at once, so entries from before {} are left out.",
+            MAX_ATTACHED,
+            years[years.len() - MAX_ATTACHED]
+        );
+        years.drain(..years.len() - MAX_ATTACHED);
+    }
+
+    let mut sources =
+        vec!["SELECT id, day, month, year, body, is_favorite FROM main.entries".to_string()];
+    for year in years {
+        let schema = format!("archive_{}", year);
+        let uri = format!("file:{}?mode=ro", db_path(config, year));
+        sql_conn.execute(&format!("ATTACH DATABASE ?1 AS {}", schema), [uri])?;
+
+        // Files last written to before favorites existed won't have the column, and
+        // being read-only, they can't be migrated
+        let is_favorite = if journal::has_column(&sql_conn, &schema, "entries", "is_favorite")? {
+            "is_favorite"
+        } else {
+            "0"
+        };
+        sources.push(format!(
+            "SELECT id, day, month, year, body, {} FROM {}.entries",
+            is_favorite, schema
+        ));
+    }
+
+    sql_conn.execute(
+        &format!(
+            "CREATE TEMP VIEW entries AS {}",
+            sources.join(" UNION ALL ")
+        ),
+        NO_PARAMS,
+    )?;
+
+    Ok(sql_conn)
+}
+
+/// Opens where the entries for the given year are kept, which is the database for
+/// that year unless `storage_backend` says otherwise.
+pub fn open_storage(config: &Config, year: i32) -> error::Result<Box<dyn Storage>> {
+    match &config.storage_backend {
+        StorageBackend::Sqlite => Ok(Box::new(open_db(config, year)?)),
+        StorageBackend::Files { dir } => Ok(Box::new(FileStorage::new(dir))),
+    }
+}
+
+/// Opens the whole journal for reading, like `open_db_with_history`.
+pub fn open_storage_with_history(
+    config: &Config,
+    today: NaiveDate,
+) -> error::Result<Box<dyn Storage>> {
+    match &config.storage_backend {
+        StorageBackend::Sqlite => Ok(Box::new(open_db_with_history(config, today)?)),
+        StorageBackend::Files { dir } => Ok(Box::new(FileStorage::new(dir))),
+    }
+}
+
+fn create_tables(sql_conn: &Connection) -> Result<()> {
+    journal::create_table(sql_conn)?;
+
+    // Small key/value store for anything we need to remember across restarts
+    sql_conn.execute(
+        "CREATE TABLE IF NOT EXISTS state (
+              key   TEXT PRIMARY KEY,
+              value TEXT NOT NULL
+              )",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
+
+pub fn get_state(sql_conn: &Connection, key: &str) -> Result<Option<String>> {
+    let mut stmt = sql_conn.prepare("SELECT value FROM state WHERE key = ?1")?;
+    let value = stmt.query_map([key], |row| row.get(0))?.next().transpose();
+
+    value
+}
+
+pub fn set_state(sql_conn: &Connection, key: &str, value: &str) -> Result<()> {
+    sql_conn.execute(
+        "INSERT OR REPLACE INTO state (key, value) VALUES (?1, ?2)",
+        [key, value],
+    )?;
+
+    Ok(())
+}
+
+pub fn clear_state(sql_conn: &Connection, key: &str) -> Result<()> {
+    sql_conn.execute("DELETE FROM state WHERE key = ?1", [key])?;
+
+    Ok(())
+}
+
+/// Saves an email as a journal entry, or replies saying why it wasn't. Returns
+/// whether the entry is saved.
+pub fn store_journal_email(
+    config: &Config,
+    email: &Email,
+    now: DateTime<Utc>,
+) -> error::Result<bool> {
+    // A sender with a badly set clock can give us a Date in the future, which
+    // would file the entry under a day that hasn't happened yet
+    let timestamp = if email.timestamp > now + Duration::minutes(MAX_CLOCK_SKEW_MINUTES) {
+        log!(
+            "Warning: email from {} is dated {}, which is in the future. Storing it under today instead.",
+            email.from, email.timestamp
+        );
+        now
+    } else {
+        email.timestamp
+    };
+
+    let date = timestamp.naive_utc().date();
+
+    if let Some(size) = email.too_large {
+        return store_too_large_placeholder(config, email, date, size, now);
+    }
+
+    // Attachments are only kept with attachments_dir set, so otherwise they don't count
+    let has_attachments = config.attachments_dir.is_some() && !email.attachments.is_empty();
+
+    // Already checked by validate, so the names are all known
+    let processor = BodyProcessor::new(&config.body_processors).map_err(Error::Config)?;
+
+    // An email with nothing in the body, like one sent just to mark the day, or
+    // with only a photo
+    let mut entry = processor.process(&email.body);
+    if entry.trim().is_empty() {
+        if !config.allow_empty_body {
+            log!("Journal entry from {} is empty, ignoring it.", email.from);
+            reply_to_entry(
+                config,
+                &email.reply_to,
+                "Error",
+                "Your journal entry wasn't saved, because it had no text. Write your entry in the body of the email.",
+                now,
+            )?;
+
+            return Ok(false);
+        }
+
+        if config.empty_body_uses_subject && !email.subject.trim().is_empty() {
+            entry = email.subject.trim().to_string();
+        }
+    }
+
+    // Worked out after the subject may have taken the body's place, so that entry is kept
+    let is_empty = entry.trim().is_empty();
+
+    // Measured in characters rather than bytes, so the limit means the same thing
+    // whatever language the entry is written in, and without the blank lines the
+    // mail client leaves around it
+    if let Some(max_chars) = config.max_entry_chars {
+        let chars = entry.trim().chars().count();
+        if chars > max_chars {
+            log!(
+                "Journal entry from {} is {} characters long, which is over the limit of {}, ignoring it.",
+                email.from, chars, max_chars
+            );
+            reply_to_entry(
+                config,
+                &email.reply_to,
+                "Error",
+                &format!(
+                    "Your journal entry wasn't saved, because it is {} characters long and entries can be at most {} characters.",
+                    chars, max_chars
+                ),
+                now,
+            )?;
+
+            return Ok(false);
+        }
+    }
+
+    let quick_word = quick_entry_word(config, &entry);
+
+    // Quick entries are for the days there's no time to write, and a photo says
+    // enough by itself, so neither has to be long enough
+    let is_photo_entry = is_empty && has_attachments;
+    if let (Some(min_words), None, false) = (config.min_words, quick_word, is_photo_entry) {
+        let words = entry.split_whitespace().count();
+        if words < min_words {
+            log!(
+                "Journal entry from {} is {} words long, which is under the minimum of {}, ignoring it.",
+                email.from, words, min_words
+            );
+            reply_to_entry(
+                config,
+                &email.reply_to,
+                "Keep going!",
+                &format!(
+                    "You're off to a good start with {} words, but entries need at least {}. Keep writing, and send the whole entry again when you get there. Your entry so far:\n\n\"{}\"",
+                    words,
+                    min_words,
+                    entry.trim()
+                ),
+                now,
+            )?;
+
+            return Ok(false);
+        }
+    }
+
+    // Quick and photo entries are let through here too, for the same reasons
+    if let (true, None, false) = (config.require_template_sections, quick_word, is_photo_entry) {
+        let missing = missing_template_sections(config, &entry);
+        if !missing.is_empty() {
+            log!(
+                "Journal entry from {} is missing {} of the template sections, ignoring it.",
+                email.from,
+                missing.len()
+            );
+            reply_to_entry(
+                config,
+                &email.reply_to,
+                "Missing sections",
+                &format!(
+                    "Your journal entry wasn't saved, because it's missing these sections of the template: {}. Send the whole entry again with a line starting with each of them:\n\n{}\n\nYour entry so far:\n\n\"{}\"",
+                    missing.join(", "),
+                    config.entry_template.as_deref().unwrap_or_default().trim(),
+                    entry.trim()
+                ),
+                now,
+            )?;
+
+            return Ok(false);
+        }
+    }
+
+    // What's attached is compared too, so two photos sent one after the other are two entries
+    let mut compared = entry.clone();
+    if has_attachments {
+        for attachment in &email.attachments {
+            compared.push_str(&format!(" {}", attachment.filename));
+        }
+    }
+
+    if is_redelivery(config, date, &compared, now)? {
+        log!(
+            "Journal entry from {} is the same as the one saved just before it, ignoring it.",
+            email.from
+        );
+
+        // Its entry is saved already, so it's as good as saved
+        return Ok(true);
+    }
+
+    let storage = open_storage(config, date.year())?;
+
+    // We need to check if there is already an entry for this day
+    let existing = storage.fetch_by_date(date)?;
+
+    // The entry that was too large, sent again, takes the place of its placeholder
+    let replaces_placeholder = existing
+        .as_ref()
+        .is_some_and(|existing| is_too_large_placeholder(&existing.body));
+
+    if existing.is_some()
+        && !replaces_placeholder
+        && config.duplicate_policy == DuplicatePolicy::Reject
+    {
+        log!("Journal entry for today was already submitted, ignoring new entry.");
+
+        // A client that keeps resending the entry shouldn't get an error back every time
+        let state_conn = open_current_db(config, now.naive_utc().date())?;
+        let date_str = date.to_string();
+        if config.throttle_duplicate_errors
+            && get_state(&state_conn, "duplicate_error_date")?.as_deref() == Some(date_str.as_str())
+        {
+            log!(
+                "Already sent an error for a duplicate entry on {}, not sending another.",
+                date
+            );
+            return Ok(false);
+        }
+        set_state(&state_conn, "duplicate_error_date", &date_str)?;
+
+        reply_to_entry(
+            config,
+            &email.reply_to,
+            "Error",
+            "You already submitted a journal entry for today!",
+            now,
+        )?;
+
+        return Ok(false);
+    }
+
+    // Save any attachments, and note what happened to each of them in the entry
+    let mut body = if is_empty {
+        String::new()
+    } else {
+        entry.clone()
+    };
+    if let Some(dir) = &config.attachments_dir {
+        let dir = Path::new(dir).join(timestamp.format("%Y-%m-%d").to_string());
+        for attachment in &email.attachments {
+            if !body.is_empty() {
+                body.push_str("\n\n");
+            }
+            body.push_str(&format!("[{}]", save_attachment(config, &dir, attachment)));
+        }
+    }
+
+    // Head each section with the time it was written, so an appended entry reads like a log
+    if config.append_timestamp {
+        body = format!("[{}]\n{}", timestamp.format("%H:%M UTC"), body.trim());
+    }
+
+    let is_new = existing.is_none() || replaces_placeholder;
+    let (id, body) = match existing {
+        Some(existing) if replaces_placeholder => {
+            log!(
+                "Replacing the placeholder for the journal entry for {}.",
+                date
+            );
+
+            storage.update_body(existing.id, &body)?;
+            (existing.id, body)
+        }
+        Some(existing) => {
+            log!("Appending to the journal entry for today.");
+
+            let body = format!("{}{}{}", existing.body, config.append_delimiter, body);
+            storage.update_body(existing.id, &body)?;
+            (existing.id, body)
+        }
+        None => {
+            // Store the entry
+            (storage.insert_entry(date, &body)?, body)
+        }
+    };
+
+    remember_entry(config, date, &compared, now)?;
+
+    // Entries sent to a plus address, like journal+work@example.com, are tagged with it
+    for tag in email
+        .recipients
+        .iter()
+        .filter_map(|recipient| plus_tag(&config.journal_email, recipient))
+    {
+        log!("Tagging the journal entry for {} with \"{}\"", date, tag);
+        storage.add_tag(id, &tag)?;
+    }
+
+    if let Some(word) = quick_word {
+        log!("Journal entry for {} is a quick \"{}\" entry.", date, word);
+        if let Some(tag) = &config.quick_entry_tag {
+            storage.add_tag(id, tag)?;
+        }
+
+        reply_to_entry(
+            config,
+            &email.reply_to,
+            "Quick entry saved",
+            &format!(
+                "Got it, \"{}\" is saved as your journal entry {}. Write more whenever you have the time.",
+                word,
+                entry_ref(date)
+            ),
+            now,
+        )?;
+    } else if config.confirm_entries && is_new {
+        reply_to_entry(
+            config,
+            &email.reply_to,
+            "Entry saved",
+            &format!(
+                "Saved your journal entry {} ({} words).",
+                entry_ref(date),
+                entry.split_whitespace().count()
+            ),
+            now,
+        )?;
+    }
+
+    run_post_store_hook(config, date, &body, &storage.fetch_tags(id)?, !is_new);
+
+    Ok(true)
+}
+
+/// Saves a note as the entry for an email that was over `max_email_bytes`, for the
+/// entry sent again to replace, and asks for it to be sent again smaller. A day
+/// that already has an entry is left as it is.
+fn store_too_large_placeholder(
+    config: &Config,
+    email: &Email,
+    date: NaiveDate,
+    size: u32,
+    now: DateTime<Utc>,
+) -> error::Result<bool> {
+    let max_bytes = config.max_email_bytes.unwrap_or_default();
+    log!(
+        "Journal entry from {} is {} bytes, which is over the limit of {}, not saving it.",
+        email.from,
+        size,
+        max_bytes
+    );
+
+    let storage = open_storage(config, date.year())?;
+    let mut reply = format!(
+        "Your journal entry wasn't saved, because the email was {} bytes and emails can be at most {} bytes. Send it again with fewer or smaller attachments.",
+        size, max_bytes
+    );
+    if storage.fetch_by_date(date)?.is_none() {
+        let placeholder = format!(
+            "{} ({} bytes, over the limit of {})]",
+            TOO_LARGE_NOTE, size, max_bytes
+        );
+        storage.insert_entry(date, &placeholder)?;
+        reply.push_str(&format!(
+            " Until then, a note that it arrived is saved as your journal entry {}.",
+            entry_ref(date)
+        ));
+    }
+
+    reply_to_entry(config, &email.reply_to, "Error", &reply, now)?;
+
+    Ok(false)
+}
+
+/// Checks whether the whole entry is the note `store_too_large_placeholder` saves.
+fn is_too_large_placeholder(body: &str) -> bool {
+    body.starts_with(TOO_LARGE_NOTE) && !body.contains('\n')
+}
+
+/// The template sections that no line of the entry starts with. Case doesn't matter,
+/// so "grateful for: coffee" fills in "Grateful for:".
+fn missing_template_sections<'a>(config: &'a Config, entry: &str) -> Vec<&'a str> {
+    let lines: Vec<String> = entry
+        .lines()
+        .map(|line| line.trim_start().to_lowercase())
+        .collect();
+
+    config
+        .template_sections()
+        .into_iter()
+        .filter(|section| {
+            let section = section.to_lowercase();
+            !lines.iter().any(|line| line.starts_with(&section))
+        })
+        .collect()
+}
+
+/// A reply held back until `quiet_hours` are over.
+#[derive(Serialize, Deserialize)]
+struct QueuedReply {
+    send_at: DateTime<Utc>,
+    to: String,
+    subject: String,
+    body: String,
+}
+
+/// Replies about an entry, like to confirm it or say why it wasn't saved. During
+/// `quiet_hours` the reply is queued instead, and `send_queued_replies` sends it
+/// once they're over.
+fn reply_to_entry(
+    config: &Config,
+    to: &str,
+    subject: &str,
+    body: &str,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let send_at = match config
+        .quiet_hours
+        .and_then(|hours| quiet_hours_end(hours, now))
+    {
+        Some(send_at) => send_at,
+        None => {
+            send_reply(config, to, subject, body);
+            return Ok(());
+        }
+    };
+
+    let sql_conn = open_current_db(config, now.naive_utc().date())?;
+    let mut queue = load_reply_queue(&sql_conn)?;
+    queue.push(QueuedReply {
+        send_at,
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body: body.to_string(),
+    });
+    save_reply_queue(&sql_conn, &queue)?;
+
+    log!(
+        "Holding back the \"{}\" reply until {}, after quiet hours",
+        subject,
+        send_at
+    );
+    Ok(())
+}
+
+/// Works out when the quiet hours `now` falls in are over, or returns `None` if it
+/// isn't during quiet hours. The hours are UTC, and can span midnight, like (22, 7).
+pub fn quiet_hours_end((start, end): (u8, u8), now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let hour = now.hour() as u8;
+    let is_quiet = if start < end {
+        start <= hour && hour < end
+    } else {
+        hour >= start || hour < end
+    };
+    if !is_quiet {
+        return None;
+    }
+
+    let end_today = Utc.from_utc_datetime(&now.naive_utc().date().and_hms(u32::from(end), 0, 0));
+    Some(if end_today > now {
+        end_today
+    } else {
+        end_today + Duration::days(1)
+    })
+}
+
+/// Sends the replies held back during quiet hours that are due by now.
+pub fn send_queued_replies(config: &Config, now: DateTime<Utc>) -> Result<()> {
+    let sql_conn = open_current_db(config, now.naive_utc().date())?;
+    let (due, waiting): (Vec<QueuedReply>, Vec<QueuedReply>) = load_reply_queue(&sql_conn)?
+        .into_iter()
+        .partition(|reply| reply.send_at <= now);
+    if due.is_empty() {
+        return Ok(());
+    }
+
+    // Taken off the queue before they're sent, so that none is ever sent twice
+    save_reply_queue(&sql_conn, &waiting)?;
+
+    log!(
+        "Quiet hours are over, sending {} held back reply(s)",
+        due.len()
+    );
+    for reply in due {
+        send_reply(config, &reply.to, &reply.subject, &reply.body);
+    }
+
+    Ok(())
+}
+
+fn load_reply_queue(sql_conn: &Connection) -> Result<Vec<QueuedReply>> {
+    let queue = match get_state(sql_conn, "reply_queue")? {
+        Some(queue) => queue,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(serde_json::from_str(&queue).unwrap_or_else(|e| {
+        log_error!(
+            "Dropping the queue of held back replies, which can't be read: {}",
+            e
+        );
+        Vec::new()
+    }))
+}
+
+fn save_reply_queue(sql_conn: &Connection, queue: &[QueuedReply]) -> Result<()> {
+    if queue.is_empty() {
+        return clear_state(sql_conn, "reply_queue");
+    }
+
+    // Made of nothing but strings, so it always serializes
+    set_state(
+        sql_conn,
+        "reply_queue",
+        &serde_json::to_string(queue).unwrap(),
+    )
+}
+
+/// Checks whether an entry is a second delivery of the last one saved, going by
+/// whether it arrived soon after it, for the same day, with nearly the same words.
+fn is_redelivery(config: &Config, date: NaiveDate, body: &str, now: DateTime<Utc>) -> Result<bool> {
+    if config.dedup_similarity_window_minutes == 0 {
+        return Ok(false);
+    }
+
+    let recent = match get_state(
+        &open_current_db(config, now.naive_utc().date())?,
+        "recent_entry",
+    )? {
+        Some(recent) => recent,
+        None => return Ok(false),
+    };
+
+    // Saved by `remember_entry` as the time, the date and then the body, a line each
+    let mut parts = recent.splitn(3, '\n');
+    let saved_at = parts
+        .next()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+    let saved_date = parts.next().and_then(|date| date.parse::<NaiveDate>().ok());
+    let saved_body = parts.next().unwrap_or_default();
+
+    let window = Duration::minutes(config.dedup_similarity_window_minutes);
+    Ok(saved_date == Some(date)
+        && saved_at.is_some_and(|saved_at| now - saved_at.with_timezone(&Utc) <= window)
+        && similarity(body, saved_body) >= REDELIVERY_SIMILARITY)
+}
+
+/// Remembers the entry just saved, for `is_redelivery` to compare the next one to.
+fn remember_entry(config: &Config, date: NaiveDate, body: &str, now: DateTime<Utc>) -> Result<()> {
+    set_state(
+        &open_current_db(config, now.naive_utc().date())?,
+        "recent_entry",
+        &format!("{}\n{}\n{}", now.to_rfc3339(), date, body),
+    )
+}
+
+/// Measures how alike two texts are, as the share of their (lowercased) words that
+/// they have in common, from 0 to 1.
+fn similarity(a: &str, b: &str) -> f64 {
+    let words = |text: &str| -> HashSet<String> {
+        text.split_whitespace().map(str::to_lowercase).collect()
+    };
+    let (a, b) = (words(a), words(b));
+
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+
+    a.intersection(&b).count() as f64 / a.union(&b).count() as f64
+}
+
+/// Checks whether an entry is nothing but one of the `quick_entry_words`, which mark
+/// a day with nothing to say, returning the word if so.
+fn quick_entry_word<'a>(config: &'a Config, body: &str) -> Option<&'a str> {
+    let word = body.trim().trim_matches(|c: char| !c.is_alphanumeric());
+
+    config
+        .quick_entry_words
+        .iter()
+        .find(|quick_word| quick_word.trim().to_lowercase() == word.to_lowercase())
+        .map(String::as_str)
+}
+
+/// Deletes the attachments saved on days more than `attachment_retention_days` ago.
+/// The entries themselves, along with their notes about the attachments, are kept.
+/// Only runs once a day, however often it's called.
+pub fn prune_attachments(config: &Config, today: NaiveDate) -> Result<()> {
+    let (dir, retention_days) = match (&config.attachments_dir, config.attachment_retention_days) {
+        (Some(dir), Some(retention_days)) => (dir, retention_days),
+        _ => return Ok(()),
+    };
+
+    let sql_conn = open_current_db(config, today)?;
+    let today_str = today.to_string();
+    if get_state(&sql_conn, "attachments_pruned_on")?.as_deref() == Some(today_str.as_str()) {
+        return Ok(());
+    }
+
+    // Attachments are saved in a directory per day, named after the date
+    let cutoff = today - Duration::days(retention_days);
+    match std::fs::read_dir(dir) {
+        Ok(day_dirs) => {
+            for day_dir in day_dirs.flatten() {
+                let path = day_dir.path();
+                let date = day_dir
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| NaiveDate::parse_from_str(name, "%Y-%m-%d").ok());
+
+                if path.is_dir() && date.is_some_and(|date| date < cutoff) {
+                    match std::fs::remove_dir_all(&path) {
+                        Ok(_) => log!("Deleted old attachments in {}", path.display()),
+                        Err(e) => log_error!("Failed to delete {}: {}", path.display(), e),
+                    }
+                }
+            }
+        }
+        // Nothing has been saved yet
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log_error!("Failed to read the attachments directory {}: {}", dir, e),
+    }
+
+    set_state(&sql_conn, "attachments_pruned_on", &today_str)
+}
+
+/// Saves an attachment into the given directory, unless it is too big or not an
+/// allowed type. Returns a note describing the outcome, for the journal entry.
+fn save_attachment(config: &Config, dir: &Path, attachment: &Attachment) -> String {
+    if attachment.data.len() > config.max_attachment_bytes {
+        log!("Skipping attachment '{}': too large", attachment.filename);
+        return format!(
+            "attachment '{}' skipped: larger than {} bytes",
+            attachment.filename, config.max_attachment_bytes
+        );
+    }
+
+    if !config
+        .allowed_attachment_mimes
+        .iter()
+        .any(|pattern| mime_matches(pattern, &attachment.mimetype))
+    {
+        log!(
+            "Skipping attachment '{}': {} is not an allowed type",
+            attachment.filename,
+            attachment.mimetype
+        );
+        return format!(
+            "attachment '{}' skipped: disallowed type",
+            attachment.filename
+        );
+    }
+
+    // Never trust the sender's filename to stay inside the attachments directory
+    let safe_name: String = attachment
+        .filename
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let safe_name = match safe_name.trim_start_matches('.') {
+        "" => "attachment",
+        name => name,
+    };
+
+    let mut path = dir.join(safe_name);
+    let mut copy = 1;
+    while path.exists() {
+        path = dir.join(format!("{}-{}", copy, safe_name));
+        copy += 1;
+    }
+
+    let result = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, &attachment.data));
+    match result {
+        Ok(_) => format!(
+            "attachment '{}' saved as {}",
+            attachment.filename,
+            path.display()
+        ),
+        Err(e) => {
+            log_error!("Failed to save attachment '{}': {}", attachment.filename, e);
+            format!("attachment '{}' could not be saved", attachment.filename)
+        }
+    }
+}
+
+/// Checks a mimetype against an allowlist pattern, which is either an exact type
+/// like `application/pdf` or a wildcard like `image/*`.
+fn mime_matches(pattern: &str, mimetype: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let mimetype = mimetype.to_lowercase();
+
+    if pattern == "*" || pattern == "*/*" {
+        return true;
+    }
+
+    match pattern.strip_suffix("/*") {
+        Some(top_level) => mimetype.split('/').next() == Some(top_level),
+        None => pattern == mimetype,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sent_emails, test_config, TempDir};
+
+    #[test]
+    fn a_database_with_duplicate_days_is_warned_about_until_they_are_merged() {
+        let dir = TempDir::new("store_duplicate_days");
+        let config = test_config(&dir);
+        let sql_conn = open_db(&config, 2024).unwrap();
+        sql_conn
+            .execute("DROP INDEX entries_date", NO_PARAMS)
+            .unwrap();
+        let date = NaiveDate::from_ymd(2024, 6, 1);
+        journal::insert_entry(&sql_conn, date, "First").unwrap();
+        journal::insert_entry(&sql_conn, date, "Second").unwrap();
+
+        assert_eq!(
+            duplicate_days_warning(&sql_conn, "journal.db").unwrap().as_deref(),
+            Some("Warning: journal.db has 1 day(s) with more than one entry, so one entry a day can't be enforced. Run `mail-journal dedup-days` to merge them.")
+        );
+
+        journal::merge_duplicate_days(&sql_conn, journal::DayMerge::Concatenate, "\n").unwrap();
+        assert_eq!(
+            duplicate_days_warning(&sql_conn, "journal.db").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn an_entry_sent_at_new_year_goes_in_the_new_years_database() {
+        let dir = TempDir::new("store_new_year");
+        let config = Config {
+            db_rollover: DbRollover::Yearly,
+            db_filename: dir.file("journal-{year}.db"),
+            ..test_config(&dir)
+        };
+
+        let new_year = Utc.ymd(2025, 1, 1).and_hms(0, 5, 0);
+        assert!(
+            store_journal_email(&config, &email(new_year, "", "Happy new year"), new_year).unwrap()
+        );
+
+        assert!(!Path::new(&dir.file("journal-2024.db")).exists());
+        let storage = open_storage_with_history(&config, new_year.naive_utc().date()).unwrap();
+        let bodies: Vec<String> = storage
+            .fetch_all()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.body.trim().to_string())
+            .collect();
+        assert_eq!(bodies, vec!["Happy new year"]);
+        assert!(Path::new(&dir.file("journal-2025.db")).exists());
+    }
+
+    fn email(date: DateTime<Utc>, subject: &str, body: &str) -> Email {
+        let raw = format!(
+            "From: me@example.com\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            subject,
+            date.to_rfc2822(),
+            body
+        );
+        Email::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    /// An email with a text part if it's given, then an attachment for each of the
+    /// filenames, mimetypes and contents.
+    fn email_with_attachments(text: Option<&str>, attachments: &[(&str, &str, &str)]) -> Email {
+        let mut raw = format!(
+            "From: me@example.com\r\nSubject: Re: Journal\r\nDate: {}\r\nContent-Type: multipart/mixed; boundary=\"boundary\"\r\n\r\n",
+            now().to_rfc2822()
+        );
+        if let Some(text) = text {
+            raw.push_str(&format!(
+                "--boundary\r\nContent-Type: text/plain\r\n\r\n{}\r\n",
+                text
+            ));
+        }
+        for (filename, mimetype, data) in attachments {
+            raw.push_str(&format!(
+                "--boundary\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\r\n{}\r\n",
+                mimetype, filename, data
+            ));
+        }
+        raw.push_str("--boundary--\r\n");
+
+        Email::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    fn saved_entry(config: &Config, date: NaiveDate) -> Option<String> {
+        open_storage(config, date.year())
+            .unwrap()
+            .fetch_by_date(date)
+            .unwrap()
+            .map(|entry| entry.body.trim().to_string())
+    }
+
+    /// The subjects and bodies of the replies sent since the test last looked.
+    fn replies() -> Vec<(String, String)> {
+        sent_emails()
+            .iter()
+            .map(|email| (email.subject(), email.body()))
+            .collect()
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2024, 3, 10).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn the_test_config_is_one_mail_journal_would_run_with() {
+        let dir = TempDir::new("test-config");
+        assert_eq!(test_config(&dir).validate(), Ok(()));
+    }
+
+    #[test]
+    fn an_entry_dated_in_the_future_is_saved_under_today() {
+        let dir = TempDir::new("future-entry");
+        let config = test_config(&dir);
+        let next_week = now() + Duration::days(7);
+
+        store_journal_email(
+            &config,
+            &email(next_week, "Re: Journal", "From the future"),
+            now(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("From the future")
+        );
+        assert_eq!(saved_entry(&config, next_week.naive_utc().date()), None);
+    }
+
+    #[test]
+    fn an_entry_dated_just_ahead_of_us_keeps_its_date() {
+        let dir = TempDir::new("slightly-early-entry");
+        let config = test_config(&dir);
+        let just_ahead = now() + Duration::minutes(MAX_CLOCK_SKEW_MINUTES);
+
+        store_journal_email(&config, &email(just_ahead, "Re: Journal", "On time"), now()).unwrap();
+
+        assert_eq!(
+            saved_entry(&config, just_ahead.naive_utc().date()).as_deref(),
+            Some("On time")
+        );
+    }
+
+    #[test]
+    fn attachments_over_the_size_cap_or_of_other_types_are_skipped() {
+        let dir = TempDir::new("attachment-limits");
+        let config = Config {
+            attachments_dir: Some(dir.file("attachments")),
+            max_attachment_bytes: 20,
+            ..test_config(&dir)
+        };
+        let email = email_with_attachments(
+            Some("Photos from the hike"),
+            &[
+                ("small.png", "image/png", "a tiny picture"),
+                ("big.png", "image/png", "a picture that is far too big"),
+                ("notes.zip", "application/zip", "zipped"),
+            ],
+        );
+
+        store_journal_email(&config, &email, now()).unwrap();
+
+        let attachments = dir.path().join("attachments").join("2024-03-10");
+        let saved = attachments.join("small.png");
+        let entry = saved_entry(&config, now().naive_utc().date()).unwrap();
+        let lines: Vec<&str> = entry
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Photos from the hike".to_string(),
+                format!("[attachment 'small.png' saved as {}]", saved.display()),
+                "[attachment 'big.png' skipped: larger than 20 bytes]".to_string(),
+                "[attachment 'notes.zip' skipped: disallowed type]".to_string(),
+            ]
+        );
+        assert!(fs::read(&saved).unwrap().starts_with(b"a tiny picture"));
+        assert!(!attachments.join("big.png").exists());
+        assert!(!attachments.join("notes.zip").exists());
+    }
+
+    #[test]
+    fn an_email_with_only_a_photo_is_saved_as_an_entry() {
+        let dir = TempDir::new("photo-only");
+        let config = Config {
+            attachments_dir: Some(dir.file("attachments")),
+            allow_empty_body: true,
+            min_words: Some(5),
+            ..test_config(&dir)
+        };
+        let email = email_with_attachments(None, &[("photo.png", "image/png", "a picture")]);
+
+        store_journal_email(&config, &email, now()).unwrap();
+
+        let saved = dir
+            .path()
+            .join("attachments")
+            .join("2024-03-10")
+            .join("photo.png");
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()),
+            Some(format!(
+                "[attachment 'photo.png' saved as {}]",
+                saved.display()
+            ))
+        );
+        assert!(fs::read(&saved).unwrap().starts_with(b"a picture"));
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn mime_matches_takes_exact_types_and_wildcards() {
+        assert!(mime_matches("image/*", "image/png"));
+        assert!(mime_matches("IMAGE/*", "image/JPEG"));
+        assert!(mime_matches("application/pdf", "application/pdf"));
+        assert!(mime_matches("*/*", "application/zip"));
+        assert!(!mime_matches("image/*", "application/pdf"));
+        assert!(!mime_matches("application/pdf", "application/pdfx"));
+    }
+
+    #[test]
+    fn an_entry_over_max_entry_chars_is_rejected() {
+        let dir = TempDir::new("entry-too-long");
+        let config = Config {
+            max_entry_chars: Some(5),
+            ..test_config(&dir)
+        };
+
+        store_journal_email(&config, &email(now(), "Re: Journal", "éééééé"), now()).unwrap();
+
+        assert_eq!(saved_entry(&config, now().naive_utc().date()), None);
+        let sent = sent_emails();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, vec!["me@example.com".to_string()]);
+        assert_eq!(sent[0].subject(), "Error");
+        assert!(sent[0].body().contains("6 characters long"));
+    }
+
+    #[test]
+    fn an_entry_at_max_entry_chars_is_saved() {
+        let dir = TempDir::new("entry-at-limit");
+        let config = Config {
+            max_entry_chars: Some(5),
+            ..test_config(&dir)
+        };
+
+        // Five characters, though ten bytes
+        store_journal_email(&config, &email(now(), "Re: Journal", "ééééé"), now()).unwrap();
+
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("ééééé")
+        );
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn an_empty_entry_is_rejected_unless_allowed() {
+        let dir = TempDir::new("empty-rejected");
+        let config = Config {
+            allow_empty_body: false,
+            ..test_config(&dir)
+        };
+
+        store_journal_email(&config, &email(now(), "Still alive", " \r\n"), now()).unwrap();
+
+        assert_eq!(saved_entry(&config, now().naive_utc().date()), None);
+        let replies = replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, "Error");
+        assert!(replies[0].1.contains("it had no text"));
+    }
+
+    #[test]
+    fn an_empty_entry_is_saved_when_allowed() {
+        let dir = TempDir::new("empty-saved");
+        let config = Config {
+            allow_empty_body: true,
+            empty_body_uses_subject: false,
+            ..test_config(&dir)
+        };
+
+        store_journal_email(&config, &email(now(), "Still alive", ""), now()).unwrap();
+
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("")
+        );
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn an_empty_entry_can_take_the_subject_as_its_body() {
+        let dir = TempDir::new("empty-uses-subject");
+        let config = Config {
+            allow_empty_body: true,
+            empty_body_uses_subject: true,
+            ..test_config(&dir)
+        };
+
+        store_journal_email(&config, &email(now(), "  Still alive ", ""), now()).unwrap();
+
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("Still alive")
+        );
+    }
+
+    #[test]
+    fn an_empty_entry_still_has_to_be_min_words_long() {
+        let dir = TempDir::new("empty-min-words");
+        let config = Config {
+            allow_empty_body: true,
+            empty_body_uses_subject: true,
+            min_words: Some(3),
+            ..test_config(&dir)
+        };
+
+        store_journal_email(&config, &email(now(), "Still alive", ""), now()).unwrap();
+
+        assert_eq!(saved_entry(&config, now().naive_utc().date()), None);
+        assert_eq!(replies()[0].0, "Keep going!");
+    }
+
+    fn file_mode(path: &str) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn a_new_database_gets_db_file_mode() {
+        let dir = TempDir::new("store-file-mode");
+        let mut config = test_config(&dir);
+        config.db_file_mode = Some(0o600);
+
+        open_storage(&config, 2024)
+            .unwrap()
+            .insert_entry(now().naive_utc().date(), "Private thoughts")
+            .unwrap();
+        assert_eq!(file_mode(&config.db_filename), 0o600);
+    }
+
+    #[test]
+    fn an_existing_database_is_given_db_file_mode() {
+        let dir = TempDir::new("store-file-mode-existing");
+        let mut config = test_config(&dir);
+        open_db(&config, 2024).unwrap();
+        fs::set_permissions(&config.db_filename, fs::Permissions::from_mode(0o644)).unwrap();
+
+        config.db_file_mode = Some(0o640);
+        open_db(&config, 2024).unwrap();
+        assert_eq!(file_mode(&config.db_filename), 0o640);
+    }
+
+    fn yearly_config(dir: &TempDir) -> Config {
+        Config {
+            db_filename: dir.file("journal-{year}.db"),
+            db_rollover: DbRollover::Yearly,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn a_new_year_starts_with_the_state_of_the_last() {
+        let dir = TempDir::new("state-carried-over");
+        let config = yearly_config(&dir);
+        set_state(
+            &open_db(&config, 2024).unwrap(),
+            "pause_until",
+            "2025-01-03",
+        )
+        .unwrap();
+
+        let sql_conn = open_db(&config, 2025).unwrap();
+        assert_eq!(
+            get_state(&sql_conn, "pause_until").unwrap().as_deref(),
+            Some("2025-01-03")
+        );
+    }
+
+    #[test]
+    fn state_is_carried_over_a_year_without_a_database() {
+        let dir = TempDir::new("state-skipped-year");
+        let config = yearly_config(&dir);
+        set_state(&open_db(&config, 2023).unwrap(), "welcome_sent", "true").unwrap();
+
+        let sql_conn = open_db(&config, 2025).unwrap();
+        assert_eq!(
+            get_state(&sql_conn, "welcome_sent").unwrap().as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn state_is_only_carried_into_a_new_database() {
+        let dir = TempDir::new("state-existing-year");
+        let config = yearly_config(&dir);
+        open_db(&config, 2025).unwrap();
+        set_state(
+            &open_db(&config, 2024).unwrap(),
+            "pause_until",
+            "2025-01-03",
+        )
+        .unwrap();
+
+        let sql_conn = open_db(&config, 2025).unwrap();
+        assert_eq!(get_state(&sql_conn, "pause_until").unwrap(), None);
+    }
+
+    #[test]
+    fn an_identical_redelivery_is_saved_once() {
+        let dir = TempDir::new("store-redelivery");
+        let mut config = test_config(&dir);
+        config.duplicate_policy = DuplicatePolicy::Append;
+
+        let entry = email(now(), "Re: Journal", "A good day at the lake");
+        assert!(store_journal_email(&config, &entry, now()).unwrap());
+        assert!(store_journal_email(&config, &entry, now() + Duration::seconds(20)).unwrap());
+
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("A good day at the lake")
+        );
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn the_same_entry_after_the_window_is_appended() {
+        let dir = TempDir::new("store-redelivery-late");
+        let mut config = test_config(&dir);
+        config.duplicate_policy = DuplicatePolicy::Append;
+        config.append_delimiter = "\n".to_string();
+
+        let entry = email(now(), "Re: Journal", "Still raining");
+        let later = now() + Duration::minutes(config.dedup_similarity_window_minutes + 1);
+        store_journal_email(&config, &entry, now()).unwrap();
+        store_journal_email(&config, &entry, later).unwrap();
+
+        let saved = saved_entry(&config, now().naive_utc().date()).unwrap();
+        assert_eq!(saved.matches("Still raining").count(), 2);
+    }
+
+    #[test]
+    fn three_duplicates_on_a_day_get_one_error() {
+        let dir = TempDir::new("store-duplicate-throttle");
+        let config = test_config(&dir);
+
+        for (minutes, body) in &[
+            (0, "Morning"),
+            (1, "Afternoon"),
+            (2, "Evening"),
+            (3, "Night"),
+        ] {
+            let at = now() + Duration::minutes(*minutes);
+            store_journal_email(&config, &email(at, "Re: Journal", body), at).unwrap();
+        }
+
+        let replies = replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, "Error");
+        assert!(replies[0].1.contains("You already submitted"));
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("Morning")
+        );
+    }
+
+    #[test]
+    fn every_duplicate_gets_an_error_without_the_throttle() {
+        let dir = TempDir::new("store-duplicate-unthrottled");
+        let mut config = test_config(&dir);
+        config.throttle_duplicate_errors = false;
+
+        for (minutes, body) in &[(0, "Morning"), (1, "Afternoon"), (2, "Evening")] {
+            let at = now() + Duration::minutes(*minutes);
+            store_journal_email(&config, &email(at, "Re: Journal", body), at).unwrap();
+        }
+
+        assert_eq!(replies().len(), 2);
+    }
+
+    #[test]
+    fn a_new_entry_is_confirmed_once() {
+        let dir = TempDir::new("store-confirm");
+        let mut config = test_config(&dir);
+        config.confirm_entries = true;
+        config.duplicate_policy = DuplicatePolicy::Append;
+
+        let first = email(now(), "Re: Journal", "Went to the market");
+        store_journal_email(&config, &first, now()).unwrap();
+
+        let replies_after_first = replies();
+        assert_eq!(replies_after_first.len(), 1);
+        assert_eq!(replies_after_first[0].0, "Entry saved");
+        assert_eq!(
+            replies_after_first[0].1.trim(),
+            "Saved your journal entry #2024-03-10 (4 words)."
+        );
+
+        // Adding to the day's entry isn't a new entry, so it isn't confirmed again
+        let later = now() + Duration::hours(1);
+        let second = email(later, "Re: Journal", "Bought apples and bread");
+        store_journal_email(&config, &second, later).unwrap();
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn entries_are_not_confirmed_unless_asked() {
+        let dir = TempDir::new("store-no-confirm");
+        let config = test_config(&dir);
+
+        store_journal_email(&config, &email(now(), "Re: Journal", "A good day"), now()).unwrap();
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn timestamped_appends_read_like_a_log() {
+        let dir = TempDir::new("store-append-timestamp");
+        let mut config = test_config(&dir);
+        config.duplicate_policy = DuplicatePolicy::Append;
+        config.append_timestamp = true;
+
+        let morning = Utc.ymd(2024, 3, 10).and_hms(8, 5, 0);
+        let evening = Utc.ymd(2024, 3, 10).and_hms(21, 30, 0);
+        store_journal_email(&config, &email(morning, "Re: Journal", "Coffee"), morning).unwrap();
+        store_journal_email(&config, &email(evening, "Re: Journal", "Dinner"), evening).unwrap();
+
+        assert_eq!(
+            saved_entry(&config, morning.naive_utc().date()).as_deref(),
+            Some("[08:05 UTC]\nCoffee\n\n---\n\n[21:30 UTC]\nDinner")
+        );
+    }
+
+    #[test]
+    fn a_quick_entry_is_tagged_short_and_keeps_the_streak() {
+        let dir = TempDir::new("store-quick-entry");
+        let config = test_config(&dir);
+
+        for day in 8..=9 {
+            let at = Utc.ymd(2024, 3, day).and_hms(12, 0, 0);
+            store_journal_email(&config, &email(at, "Re: Journal", "A long day at work"), at)
+                .unwrap();
+        }
+
+        assert!(
+            store_journal_email(&config, &email(now(), "Re: Journal", "Busy."), now()).unwrap()
+        );
+
+        let storage = open_storage(&config, 2024).unwrap();
+        let entry = storage
+            .fetch_by_date(now().naive_utc().date())
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.body.trim(), "Busy.");
+        assert_eq!(
+            storage.fetch_tags(entry.id).unwrap(),
+            vec!["short".to_string()]
+        );
+        assert_eq!(replies()[0].0, "Quick entry saved");
+
+        crate::inbox::handle_email(&config, &email(now(), "COUNT", ""), now()).unwrap();
+        assert!(replies()[0].1.contains("your current streak is 3 day(s)"));
+    }
+
+    #[test]
+    fn an_entry_under_min_words_is_rejected_with_encouragement() {
+        let dir = TempDir::new("store-min-words-under");
+        let mut config = test_config(&dir);
+        config.min_words = Some(5);
+
+        let entry = email(now(), "Re: Journal", "Only four words here");
+        assert!(!store_journal_email(&config, &entry, now()).unwrap());
+        assert_eq!(saved_entry(&config, now().naive_utc().date()), None);
+
+        let replies = replies();
+        assert_eq!(replies[0].0, "Keep going!");
+        assert!(replies[0]
+            .1
+            .contains("4 words, but entries need at least 5"));
+    }
+
+    #[test]
+    fn an_entry_at_or_over_min_words_is_saved() {
+        for body in &[
+            "Exactly five words right here",
+            "Six words are here for sure",
+        ] {
+            let dir = TempDir::new("store-min-words-enough");
+            let mut config = test_config(&dir);
+            config.min_words = Some(5);
+
+            let entry = email(now(), "Re: Journal", body);
+            assert!(store_journal_email(&config, &entry, now()).unwrap());
+            assert_eq!(
+                saved_entry(&config, now().naive_utc().date()).as_deref(),
+                Some(*body)
+            );
+            assert!(replies().is_empty());
+        }
+    }
+
+    #[test]
+    fn a_quick_entry_needs_no_min_words() {
+        let dir = TempDir::new("store-min-words-quick");
+        let mut config = test_config(&dir);
+        config.min_words = Some(5);
+
+        let entry = email(now(), "Re: Journal", "skip");
+        assert!(store_journal_email(&config, &entry, now()).unwrap());
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("skip")
+        );
+    }
+
+    fn template_config(dir: &TempDir) -> Config {
+        let mut config = test_config(dir);
+        config.entry_template = Some("Grateful for:\nChallenge:\nWin:".to_string());
+        config.require_template_sections = true;
+        config
+    }
+
+    #[test]
+    fn an_entry_missing_a_template_section_is_rejected() {
+        let dir = TempDir::new("store-template-missing");
+        let config = template_config(&dir);
+
+        let entry = email(
+            now(),
+            "Re: Journal",
+            "Grateful for: coffee\r\nWin: finished the report",
+        );
+        assert!(!store_journal_email(&config, &entry, now()).unwrap());
+        assert_eq!(saved_entry(&config, now().naive_utc().date()), None);
+
+        let replies = replies();
+        assert_eq!(replies[0].0, "Missing sections");
+        assert!(replies[0]
+            .1
+            .contains("missing these sections of the template: Challenge:."));
+    }
+
+    #[test]
+    fn an_entry_with_every_template_section_is_saved() {
+        let dir = TempDir::new("store-template-complete");
+        let config = template_config(&dir);
+
+        let entry = email(
+            now(),
+            "Re: Journal",
+            "grateful for: coffee\r\nChallenge: the bus\r\nWin: finished the report",
+        );
+        assert!(store_journal_email(&config, &entry, now()).unwrap());
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn an_email_over_max_email_bytes_gets_a_placeholder_and_an_error() {
+        let dir = TempDir::new("store-too-large");
+        let mut config = test_config(&dir);
+        config.max_email_bytes = Some(1000);
+
+        // Only its headers were fetched
+        let mut too_large = email(now(), "Re: Journal", "");
+        too_large.too_large = Some(5000);
+        assert!(!store_journal_email(&config, &too_large, now()).unwrap());
+
+        let placeholder = saved_entry(&config, now().naive_utc().date()).unwrap();
+        assert!(is_too_large_placeholder(&placeholder));
+        assert!(placeholder.contains("5000 bytes, over the limit of 1000"));
+
+        let replies = replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, "Error");
+        assert!(replies[0].1.contains("the email was 5000 bytes"));
+        assert!(replies[0]
+            .1
+            .contains("saved as your journal entry #2024-03-10"));
+
+        // Sent again smaller, it takes the placeholder's place
+        let later = now() + Duration::hours(1);
+        let resent = email(later, "Re: Journal", "The trip, without the photos");
+        assert!(store_journal_email(&config, &resent, later).unwrap());
+        assert_eq!(
+            saved_entry(&config, now().naive_utc().date()).as_deref(),
+            Some("The trip, without the photos")
+        );
+    }
+
+    #[test]
+    fn a_confirmation_during_quiet_hours_is_sent_once_they_are_over() {
+        let dir = TempDir::new("store-quiet-hours");
+        let mut config = test_config(&dir);
+        config.confirm_entries = true;
+        config.quiet_hours = Some((11, 13));
+
+        store_journal_email(&config, &email(now(), "Re: Journal", "A good day"), now()).unwrap();
+        assert!(replies().is_empty());
+
+        send_queued_replies(&config, now() + Duration::minutes(30)).unwrap();
+        assert!(replies().is_empty());
+
+        let after = Utc.ymd(2024, 3, 10).and_hms(13, 0, 0);
+        send_queued_replies(&config, after).unwrap();
+        let replies_after = replies();
+        assert_eq!(replies_after.len(), 1);
+        assert_eq!(replies_after[0].0, "Entry saved");
+
+        // Taken off the queue, so it's never sent twice
+        send_queued_replies(&config, after + Duration::hours(1)).unwrap();
+        assert!(replies().is_empty());
+    }
+
+    #[test]
+    fn quiet_hours_can_span_midnight() {
+        let night = Utc.ymd(2024, 3, 10).and_hms(23, 15, 0);
+        assert_eq!(
+            quiet_hours_end((22, 7), night),
+            Some(Utc.ymd(2024, 3, 11).and_hms(7, 0, 0))
+        );
+
+        let early = Utc.ymd(2024, 3, 11).and_hms(3, 0, 0);
+        assert_eq!(
+            quiet_hours_end((22, 7), early),
+            Some(Utc.ymd(2024, 3, 11).and_hms(7, 0, 0))
+        );
+
+        assert_eq!(quiet_hours_end((22, 7), now()), None);
+    }
+}
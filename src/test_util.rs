@@ -0,0 +1,188 @@
+//! Helpers shared by the tests of the modules.
+
+use crate::config::Config;
+use crate::mail::Email;
+use lettre::SendableEmail;
+use mailparse::{MailHeaderMap, ParsedMail};
+use std::cell::RefCell;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A directory of a test's own, removed again once the test is done with it. Tests
+/// run side by side, so each one needs a name of its own.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path =
+            std::env::temp_dir().join(format!("mail-journal-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The path of a file in the directory, as the config wants it.
+    pub fn file(&self, name: &str) -> String {
+        self.0.join(name).display().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A journal of its own in `dir`, written to by `me@example.com`. Its replies go to
+/// the test's outbox.
+pub fn test_config(dir: &TempDir) -> Config {
+    Config {
+        db_filename: dir.file("journal.db"),
+        target_email: "me@example.com".to_string(),
+        ..Config::default()
+    }
+}
+
+/// Stands in for the connection to a server, which answers with what it was given
+/// up front, whatever is sent to it. What's sent is kept in `sent`, for the test to
+/// look at.
+pub struct MockStream {
+    answers: Cursor<Vec<u8>>,
+    sent: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.answers.read(buf)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An IMAP connection that hasn't logged in yet, with the server answering with
+/// `answers`. Commands are tagged `a1`, `a2` and so on.
+pub fn imap_client(answers: &[u8]) -> imap::Client<MockStream> {
+    imap::Client::new(MockStream {
+        answers: Cursor::new(answers.to_vec()),
+        sent: Rc::default(),
+    })
+}
+
+/// An IMAP session that's logged in (as command `a1`), with the server going on to
+/// answer with `answers`. Commands are tagged `a2`, `a3` and so on from there.
+pub fn imap_session(answers: &[u8]) -> imap::Session<MockStream> {
+    recording_imap_session(answers).0
+}
+
+/// Like `imap_session`, along with the commands sent to the server once logged in.
+pub fn recording_imap_session(answers: &[u8]) -> (imap::Session<MockStream>, Rc<RefCell<Vec<u8>>>) {
+    let mut all_answers = b"a1 OK logged in\r\n".to_vec();
+    all_answers.extend_from_slice(answers);
+
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let stream = MockStream {
+        answers: Cursor::new(all_answers),
+        sent: Rc::clone(&sent),
+    };
+    let session = imap::Client::new(stream)
+        .login("me@example.com", "password")
+        .map_err(|(e, _)| e)
+        .unwrap();
+    sent.borrow_mut().clear();
+
+    (session, sent)
+}
+
+/// An email the code under test sent, which went to the outbox rather than to an
+/// SMTP server.
+pub struct SentEmail {
+    /// The addresses it was sent to, Cc included
+    pub to: Vec<String>,
+    /// The whole email, headers and all
+    pub message: String,
+}
+
+impl SentEmail {
+    pub fn header(&self, name: &str) -> Option<String> {
+        mailparse::parse_mail(self.message.as_bytes())
+            .unwrap()
+            .headers
+            .get_first_value(name)
+            .unwrap()
+            .map(|value| value.trim().to_string())
+    }
+
+    pub fn subject(&self) -> String {
+        self.header("Subject").unwrap_or_default()
+    }
+
+    /// The plain text of the email, with its line endings made `\n`.
+    pub fn body(&self) -> String {
+        fn find_text(part: &ParsedMail) -> Option<String> {
+            if part.subparts.is_empty() {
+                if part.ctype.mimetype == "text/plain" {
+                    return Some(part.get_body().unwrap());
+                }
+                return None;
+            }
+            part.subparts.iter().find_map(find_text)
+        }
+
+        let parsed = mailparse::parse_mail(self.message.as_bytes()).unwrap();
+        find_text(&parsed).unwrap_or_default().replace("\r\n", "\n")
+    }
+}
+
+thread_local! {
+    /// Every test runs on a thread of its own, so each has its own outbox.
+    static OUTBOX: RefCell<Vec<SentEmail>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Puts an email in the outbox, in place of sending it.
+pub fn record_sent_email(email: &lettre_email::Email) {
+    let sent = SentEmail {
+        to: email
+            .envelope()
+            .to()
+            .iter()
+            .map(|to| to.to_string())
+            .collect(),
+        message: String::from_utf8_lossy(&email.message()).to_string(),
+    };
+    OUTBOX.with(|outbox| outbox.borrow_mut().push(sent));
+}
+
+/// Takes the emails this test has sent since it last looked, oldest first.
+pub fn sent_emails() -> Vec<SentEmail> {
+    OUTBOX.with(|outbox| outbox.borrow_mut().drain(..).collect())
+}
+
+thread_local! {
+    /// The emails waiting for the code under test to check the inbox, one test's each.
+    static INBOX: RefCell<Vec<Email>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Puts an email in the inbox, for the next check to find. It must have a Date.
+pub fn receive_email(raw: &str) {
+    let email = Email::from_bytes(raw.as_bytes()).unwrap();
+    INBOX.with(|inbox| inbox.borrow_mut().push(email));
+}
+
+/// Takes the emails waiting in the inbox, oldest first, like checking it does.
+pub fn take_inbox() -> Vec<Email> {
+    INBOX.with(|inbox| inbox.borrow_mut().drain(..).collect())
+}